@@ -20,6 +20,7 @@ use std::mem;
 //   Header<Ethernet>.set_ethertype(u16) - set ethertype
 //   Header<Ethernet>.swap() - swap source and destination addresses
 //   TYPE_IPV4 - const u16 identifier for ethertype IPv4
+//   TYPE_IPV6 - const u16 identifier for ethertype IPv6
 
 pub type MacAddress = [u8; 6];
 
@@ -87,6 +88,7 @@ impl header::Header<Ethernet> {
 }
 
 pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_IPV6: u16 = 0x86dd;
 
 #[cfg(test)]
 mod selftest {
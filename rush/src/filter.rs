@@ -0,0 +1,494 @@
+use super::flow;
+use super::flow::{Dir, Flow, Prefix};
+use super::ipv4;
+use super::ipv6;
+
+use std::ops::RangeInclusive;
+
+// FILTER
+//
+// This module compiles a subset of pcap-filter(7) expressions (the filter
+// syntax of tcpdump) into flow::Flow rules for the Split app.
+//
+//   compile(&str, &Flow) -> Result<Vec<Flow>, String> - compile expression
+//     into flows that together match the packets matched by both the
+//     expression and the given flow (or describe why it is invalid)
+//
+// Primitives can be combined with “and” (“&&”), “or” (“||”), “not” (“!”), and
+// parentheses:
+//
+//   [src|dst] host ADDRESS - IPv4 or IPv6 address
+//   [src|dst] net PREFIX - e.g., 10.1.0.0/16 or 2001:db8::/32
+//   [tcp|udp] [src|dst] port PORT
+//   [tcp|udp] [src|dst] portrange MIN-MAX
+//   ip, ip6 - IPv4 or IPv6 packets
+//   tcp, udp, icmp, icmp6
+//   [ip|ip6] proto NUMBER
+//
+// Without src or dst, host, net, port, and portrange match either the source
+// or the destination of packets.
+//
+// Expressions are rewritten into disjunctive normal form (an “or” of “and”s
+// of possibly negated primitives). Each conjunction becomes a copy of the
+// given flow (with the same label) narrowed down by its primitives, and with
+// its negated primitives as exceptions.
+
+pub fn compile(expression: &str, flow: &Flow) -> Result<Vec<Flow>, String> {
+    let mut parser = Parser { tokens: tokenize(expression), position: 0 };
+    let expr = parser.expression()?;
+    if let Some(token) = parser.peek() {
+        return Err(unexpected(token))
+    }
+    Ok(dnf(&expr, false)?.iter()
+       .filter_map(|conjunction| narrow(flow, conjunction))
+       .collect())
+}
+
+#[derive(Clone,Debug)]
+enum Expr {
+    Primitive(Primitive),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>)
+}
+
+#[derive(Clone,Debug)]
+enum Primitive {
+    Address(Dir, Prefix),
+    Ports(Dir, RangeInclusive<u16>),
+    Protocol(u8)
+}
+
+// Primitives (and whether they are negated) that must all match
+type Conjunction = Vec<(bool, Primitive)>;
+
+// Maximum number of conjunctions (i.e., flows) per expression
+const MAX_CONJUNCTIONS: usize = 256;
+
+
+// Parser
+
+// Split expression into words, parentheses, and “!”, “&&”, “||” operators
+fn tokenize(expression: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let length = if rest.starts_with("&&") || rest.starts_with("||") {
+            2
+        } else {
+            rest.find(|c: char| c.is_whitespace() || "()!&|".contains(c))
+                .unwrap_or(rest.len())
+                .max(1)
+        };
+        tokens.push(&rest[..length]);
+        rest = rest[length..].trim_start();
+    }
+    tokens
+}
+
+fn unexpected(token: &str) -> String {
+    format!("unexpected “{}”", token)
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("unexpected end of expression")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn accept(&mut self, tokens: &[&str]) -> bool {
+        let accept = self.peek().is_some_and(|token| tokens.contains(&token));
+        if accept { self.position += 1 }
+        accept
+    }
+
+    // expression := conjunction { ("or" | "||") conjunction }
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut expr = self.conjunction()?;
+        while self.accept(&["or", "||"]) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.conjunction()?));
+        }
+        Ok(expr)
+    }
+
+    // conjunction := negation { ("and" | "&&") negation }
+    fn conjunction(&mut self) -> Result<Expr, String> {
+        let mut expr = self.negation()?;
+        while self.accept(&["and", "&&"]) {
+            expr = Expr::And(Box::new(expr), Box::new(self.negation()?));
+        }
+        Ok(expr)
+    }
+
+    // negation := ("not" | "!") negation | "(" expression ")" | primitive
+    fn negation(&mut self) -> Result<Expr, String> {
+        if self.accept(&["not", "!"]) {
+            Ok(Expr::Not(Box::new(self.negation()?)))
+        } else if self.accept(&["("]) {
+            let expr = self.expression()?;
+            match self.next()? {
+                ")" => Ok(expr),
+                token => Err(unexpected(token))
+            }
+        } else {
+            self.primitive()
+        }
+    }
+
+    // primitive := protocol [qualified] | qualified
+    fn primitive(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        let protocol = match token {
+            "ip" => Primitive::Address(Dir::Src, Prefix::V4(0, 0)),
+            "ip6" => Primitive::Address(Dir::Src, Prefix::V6([0; 16], 0)),
+            "tcp" => Primitive::Protocol(ipv4::PROTOCOL_TCP),
+            "udp" => Primitive::Protocol(ipv4::PROTOCOL_UDP),
+            "icmp" => Primitive::Protocol(ipv4::PROTOCOL_ICMP),
+            "icmp6" => Primitive::Protocol(ipv6::PROTOCOL_ICMPV6),
+            _ => {
+                self.position -= 1;
+                return self.qualified()
+            }
+        };
+        let protocol = Expr::Primitive(protocol);
+        let qualified = match (token, self.peek()) {
+            ("ip" | "ip6", Some("proto")) => self.qualified()?,
+            ("tcp" | "udp", Some("src" | "dst" | "port" | "portrange")) =>
+                self.qualified()?,
+            _ => return Ok(protocol)
+        };
+        Ok(Expr::And(Box::new(protocol), Box::new(qualified)))
+    }
+
+    // qualified := [src|dst] (host|net|port|portrange) VALUE | proto NUMBER
+    fn qualified(&mut self) -> Result<Expr, String> {
+        let dir = match self.peek() {
+            Some("src") => Some(Dir::Src),
+            Some("dst") => Some(Dir::Dst),
+            _ => None
+        };
+        if dir.is_some() { self.position += 1 }
+        let kind = self.next()?;
+        let value = match kind {
+            "host" | "net" | "port" | "portrange" => self.next()?,
+            "proto" if dir.is_none() => {
+                let value = self.next()?;
+                return match value.parse() {
+                    Ok(protocol) =>
+                        Ok(Expr::Primitive(Primitive::Protocol(protocol))),
+                    Err(_) => Err(format!("invalid protocol “{}”", value))
+                }
+            }
+            _ => return Err(unexpected(kind))
+        };
+        let primitive = |dir| -> Result<Expr, String> {
+            let invalid = || format!("invalid {} “{}”", kind, value);
+            let primitive = match kind {
+                "host" if !value.contains('/') => Primitive::Address(
+                    dir, flow::prefix(value).ok_or_else(invalid)?),
+                "net" => Primitive::Address(
+                    dir, flow::prefix(value).ok_or_else(invalid)?),
+                "port" if !value.contains('-') => Primitive::Ports(
+                    dir, flow::port_range(value).ok_or_else(invalid)?),
+                "portrange" => Primitive::Ports(
+                    dir, flow::port_range(value).ok_or_else(invalid)?),
+                _ => return Err(invalid())
+            };
+            Ok(Expr::Primitive(primitive))
+        };
+        match dir {
+            Some(dir) => primitive(dir),
+            None => Ok(Expr::Or(Box::new(primitive(Dir::Src)?),
+                                Box::new(primitive(Dir::Dst)?)))
+        }
+    }
+
+}
+
+
+// Compiler
+
+// Rewrite expression (or its negation) into disjunctive normal form, i.e.
+// into a list of conjunctions
+fn dnf(expr: &Expr, negate: bool) -> Result<Vec<Conjunction>, String> {
+    let limit = |count: usize| match count {
+        0..=MAX_CONJUNCTIONS => Ok(()),
+        _ => Err(format!("expression is too complex (more than {} flows)",
+                         MAX_CONJUNCTIONS))
+    };
+    match (expr, negate) {
+        (Expr::Primitive(primitive), _) =>
+            Ok(vec![vec![(negate, primitive.clone())]]),
+        (Expr::Not(expr), _) =>
+            dnf(expr, !negate),
+        (Expr::Or(a, b), false) | (Expr::And(a, b), true) => {
+            let (mut a, b) = (dnf(a, negate)?, dnf(b, negate)?);
+            limit(a.len() + b.len())?;
+            a.extend(b);
+            Ok(a)
+        }
+        (Expr::And(a, b), false) | (Expr::Or(a, b), true) => {
+            let (a, b) = (dnf(a, negate)?, dnf(b, negate)?);
+            limit(a.len() * b.len())?;
+            Ok(a.iter()
+               .flat_map(|a| b.iter().map(move |b| [&a[..], b].concat()))
+               .collect())
+        }
+    }
+}
+
+// Return flow narrowed down to the packets matching conjunction, or None if
+// no packet can match both
+fn narrow(flow: &Flow, conjunction: &Conjunction) -> Option<Flow> {
+    let mut flow = flow.clone();
+    for (negated, primitive) in conjunction {
+        if *negated {
+            let mut exception = Flow::default();
+            restrict(&mut exception, primitive)?;
+            flow.except.push(exception);
+        } else {
+            restrict(&mut flow, primitive)?;
+        }
+    }
+    Some(flow)
+}
+
+fn restrict(flow: &mut Flow, primitive: &Primitive) -> Option<()> {
+    let ports = |a: &RangeInclusive<u16>, b: &RangeInclusive<u16>| {
+        let (min, max) = (*a.start().max(b.start()), *a.end().min(b.end()));
+        (min <= max).then_some(min..=max)
+    };
+    match primitive {
+        Primitive::Address(Dir::Src, prefix) =>
+            flow.src = intersect(&flow.src, prefix)?,
+        Primitive::Address(Dir::Dst, prefix) =>
+            flow.dst = intersect(&flow.dst, prefix)?,
+        Primitive::Ports(Dir::Src, range) =>
+            flow.src_ports = ports(&flow.src_ports, range)?,
+        Primitive::Ports(Dir::Dst, range) =>
+            flow.dst_ports = ports(&flow.dst_ports, range)?,
+        Primitive::Protocol(protocol) => match flow.protocol {
+            0 => flow.protocol = *protocol,
+            current if current == *protocol => (),
+            _ => return None
+        }
+    }
+    Some(())
+}
+
+// Return the intersection of two prefixes (the longer one, unless they are
+// disjoint)
+fn intersect(a: &Prefix, b: &Prefix) -> Option<Prefix> {
+    let length = |prefix: &Prefix| match prefix {
+        Prefix::Any => None,
+        Prefix::V4(_, length) | Prefix::V6(_, length) => Some(*length)
+    };
+    let (longer, shorter) = match length(a) >= length(b) {
+        true => (a, b),
+        false => (b, a)
+    };
+    let address = match longer {
+        Prefix::Any => return Some(Prefix::Any),
+        Prefix::V4(address, _) => flow::Address::V4(*address),
+        Prefix::V6(address, _) => flow::Address::V6(*address)
+    };
+    shorter.contains(&address).then_some(*longer)
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+
+    use std::fmt;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn parse() {
+        let flow = Flow { label: "sfu".to_string(), ..Default::default() };
+        let flows = compile("udp and dst portrange 40000-50000 \
+                             and not host 10.0.0.1", &flow).unwrap();
+        println!("{:?}", flows);
+        assert!(flows.len() == 1);
+        assert!(flows[0].label == "sfu");
+        assert!(flows[0].protocol == ipv4::PROTOCOL_UDP);
+        assert!(flows[0].dst_ports == (40000..=50000));
+        assert!(flows[0].except.len() == 2);
+        let host = Prefix::V4(ipv4::pton("10.0.0.1"), 32);
+        assert!(flows[0].except[0].src == host);
+        assert!(flows[0].except[1].dst == host);
+        // Disjunctions yield a flow each, contradictions none
+        let flows = compile("(tcp||udp)&&!(src net 10.0.0.0/8)", &flow);
+        assert!(flows.unwrap().len() == 2);
+        let flows = compile("tcp port 80 or udp and tcp", &flow).unwrap();
+        assert!(flows.len() == 2);
+        assert!(flows[0].src_ports == (80..=80));
+        assert!(flows[1].dst_ports == (80..=80));
+        // Expressions are narrowed down by the given flow
+        let flow = Flow { protocol: ipv4::PROTOCOL_TCP, ..Default::default() };
+        assert!(compile("udp", &flow).unwrap().is_empty());
+        let flows = compile("ip6 proto 6 and dst net 2001:db8::/32", &flow);
+        assert!(flows.unwrap()[0].src == Prefix::V6([0; 16], 0));
+        // Errors
+        for (expression, error) in [
+            ("", "unexpected end of expression"),
+            ("tcp and", "unexpected end of expression"),
+            ("(tcp or udp", "unexpected end of expression"),
+            ("tcp)", "unexpected “)”"),
+            ("tcp udp", "unexpected “udp”"),
+            ("ether host 00:00:5e:00:53:01", "unexpected “ether”"),
+            ("src proto 6", "unexpected “proto”"),
+            ("proto tcp", "invalid protocol “tcp”"),
+            ("host 10.0.0.0/8", "invalid host “10.0.0.0/8”"),
+            ("dst net 10.0.0.0/33", "invalid net “10.0.0.0/33”"),
+            ("port 70000", "invalid port “70000”"),
+            ("portrange 50000-40000", "invalid portrange “50000-40000”"),
+            ("tcp & udp", "unexpected “&”"),
+            ("(port 1 or port 2) and (port 3 or port 4) and \
+              (port 5 or port 6) and (port 7 or port 8) and (port 9)",
+             "expression is too complex (more than 256 flows)")
+        ] {
+            let result = compile(expression, &flow);
+            println!("{:?}: {:?}", expression, result.as_ref().err());
+            assert!(result.err().as_deref() == Some(error));
+        }
+    }
+
+    #[test]
+    fn semantics() {
+        // Compare compiled flows against expressions evaluated on random
+        // tuples
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..1000 {
+            let expr = random_expr(&mut rng, 3);
+            let expression = format!("{}", expr);
+            let flows = compile(&expression, &Flow::default()).unwrap();
+            for _ in 0..100 {
+                let tuple = random_tuple(&mut rng);
+                assert!(flows.iter().any(|flow| flow.matches(&tuple))
+                        == eval(&expr, &tuple),
+                        "{} {:?} {:?}", expression, tuple, flows);
+            }
+        }
+    }
+
+    fn eval(expr: &Expr, tuple: &flow::Tuple) -> bool {
+        match expr {
+            Expr::Primitive(Primitive::Address(dir, prefix)) => match dir {
+                Dir::Src => prefix.contains(&tuple.src),
+                Dir::Dst => prefix.contains(&tuple.dst)
+            },
+            Expr::Primitive(Primitive::Ports(dir, range)) =>
+                tuple.ports.is_some_and(|(src, dst)| match dir {
+                    Dir::Src => range.contains(&src),
+                    Dir::Dst => range.contains(&dst)
+                }),
+            Expr::Primitive(Primitive::Protocol(protocol)) =>
+                tuple.protocol == Some(*protocol),
+            Expr::Not(expr) => !eval(expr, tuple),
+            Expr::And(a, b) => eval(a, tuple) && eval(b, tuple),
+            Expr::Or(a, b) => eval(a, tuple) || eval(b, tuple)
+        }
+    }
+
+    impl fmt::Display for Expr {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let dir = |dir: &Dir| match dir {
+                Dir::Src => "src",
+                Dir::Dst => "dst"
+            };
+            match self {
+                Expr::Primitive(Primitive::Address(d, prefix)) => match prefix {
+                    Prefix::V4(address, len) => write!(
+                        f, "{} net {}/{}", dir(d), ipv4::ntop(*address), len),
+                    Prefix::V6(address, len) => write!(
+                        f, "{} net {}/{}", dir(d), ipv6::ntop(address), len),
+                    Prefix::Any => write!(f, "(ip or ip6)")
+                },
+                Expr::Primitive(Primitive::Ports(d, ports)) => write!(
+                    f, "{} portrange {}-{}", dir(d), ports.start(),
+                    ports.end()),
+                Expr::Primitive(Primitive::Protocol(protocol)) =>
+                    write!(f, "proto {}", protocol),
+                Expr::Not(expr) => write!(f, "not ({})", expr),
+                Expr::And(a, b) => write!(f, "({}) and ({})", a, b),
+                Expr::Or(a, b) => write!(f, "({}) || ({})", a, b)
+            }
+        }
+    }
+
+    fn random_expr(rng: &mut StdRng, depth: usize) -> Expr {
+        let dir = |rng: &mut StdRng| match rng.gen_bool(0.5) {
+            true => Dir::Src,
+            false => Dir::Dst
+        };
+        match rng.gen_range(0..[3, 7][(depth > 0) as usize]) {
+            0 => {
+                let x = rng.gen_range(0..4);
+                let prefix = match rng.gen_bool(0.5) {
+                    true => Prefix::V4(
+                        ipv4::pton(&format!("10.{}.0.0", x)),
+                        [0, 8, 16][rng.gen_range(0..3)]),
+                    false => Prefix::V6(
+                        ipv6::pton(&format!("2001:db8:{}::", x)),
+                        [0, 32, 48][rng.gen_range(0..3)])
+                };
+                Expr::Primitive(Primitive::Address(dir(rng), prefix))
+            }
+            1 => {
+                let ports = [80..=80, 5000..=5010, 40000..=50000, 1000..=45000];
+                Expr::Primitive(Primitive::Ports(
+                    dir(rng), ports[rng.gen_range(0..ports.len())].clone()))
+            }
+            2 => Expr::Primitive(Primitive::Protocol(
+                [ipv4::PROTOCOL_TCP, ipv4::PROTOCOL_UDP, ipv4::PROTOCOL_ICMP]
+                    [rng.gen_range(0..3)])),
+            3 | 4 => Expr::Not(Box::new(random_expr(rng, depth - 1))),
+            5 => Expr::And(Box::new(random_expr(rng, depth - 1)),
+                           Box::new(random_expr(rng, depth - 1))),
+            _ => Expr::Or(Box::new(random_expr(rng, depth - 1)),
+                          Box::new(random_expr(rng, depth - 1)))
+        }
+    }
+
+    fn random_tuple(rng: &mut StdRng) -> flow::Tuple {
+        let x = rng.gen_range(0..5);
+        let (src, dst) = match rng.gen_bool(0.5) {
+            true => (
+                flow::Address::V4(ipv4::pton(&format!("10.{}.1.1", x))),
+                flow::Address::V4(ipv4::pton("10.0.0.1"))),
+            false => (
+                flow::Address::V6(ipv6::pton(&format!("2001:db8:{}::1", x))),
+                flow::Address::V6(ipv6::pton("2001:db8::2")))
+        };
+        let protocol = [ipv4::PROTOCOL_TCP, ipv4::PROTOCOL_UDP,
+                        ipv4::PROTOCOL_ICMP][rng.gen_range(0..3)];
+        let mut port = || [80, 5005, 40000, 45000, 3][rng.gen_range(0..5)];
+        let ports = match protocol {
+            ipv4::PROTOCOL_ICMP => None,
+            _ => Some((port(), port()))
+        };
+        flow::Tuple {
+            src,
+            dst,
+            protocol: Some(protocol),
+            ports,
+            dscp: 0,
+            ecn: 0,
+            icmp: None,
+            tcp_flags: None
+        }
+    }
+
+}
@@ -1,48 +1,204 @@
 use super::packet;
 use super::link;
 use super::engine;
+use super::lib;
 use super::header as hdr;
 use super::ethernet;
 use super::ethernet::Ethernet;
 use super::ipv4;
 use super::ipv4::IPv4;
+use super::ipv6;
+use super::ipv6::IPv6;
 use super::tcp::TCP;
 use super::udp::UDP;
 
 use std::ffi;
 use std::mem;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::collections::HashMap;
 
 
 // Split app: match incoming packets against flows and forward them to
 // associated outputs; packets not mathcing any flow are forwarded on the
 // "default" output
 //
-// NYI: IPv6, prefixes, protocols that use ports other than TCP/UDP
+// Flows match the source and destination addresses of packets against
+// prefixes, and their protocol and source and destination ports against
+// ranges. Flows can optionally match DSCP and ECN codepoints, ICMP (and
+// ICMPv6) types and codes, and TCP flags. The first matching flow is selected
+// (see Classifier). IPv6 packets are matched against the upper-layer protocol
+// (and ports) following any extension headers. Packets matching any of the
+// exceptions of a flow do not match the flow (see filter::compile).
+//
+// NYI: protocols that use ports other than TCP/UDP
 
 #[derive(Clone,Debug)]
 pub struct Flow {
     pub label: String,     // name of the output link
-    pub dir: Dir,          // look at source or destination address/port tuple?
-    pub ip: ipv4::Address, // zero is interpreted as “any address”
+    pub src: Prefix,       // source address prefix
+    pub dst: Prefix,       // destination address prefix
     pub protocol: u8,      // zero is interpreted as “any protocol”
-    pub port_min: u16,     // port range (NB: not all protocols use ports)
-    pub port_max: u16
+    pub src_ports: RangeInclusive<u16>, // port ranges (NB: packets without
+    pub dst_ports: RangeInclusive<u16>, // ports only match 0..=65535)
+    pub dscp: Option<u8>,  // None is interpreted as “any” from here on
+    pub ecn: Option<u8>,
+    pub icmp_type: Option<u8>, // (only ICMP packets match Some)
+    pub icmp_code: Option<u8>,
+    pub tcp_flags: Option<(u8, u8)>, // mask, value (only TCP packets match
+                                     // Some, if flags & mask == value)
+    pub except: Vec<Flow>  // exceptions
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Flow {
+            label: String::new(),
+            src: Prefix::Any,
+            dst: Prefix::Any,
+            protocol: 0,
+            src_ports: 0..=u16::MAX,
+            dst_ports: 0..=u16::MAX,
+            dscp: None,
+            ecn: None,
+            icmp_type: None,
+            icmp_code: None,
+            tcp_flags: None,
+            except: Vec::new()
+        }
+    }
 }
 
 #[derive(Clone,Debug,Copy)]
 pub enum Dir { Src, Dst }
 
+#[derive(Clone,Debug,Copy,PartialEq)]
+pub enum Prefix {
+    Any,                   // any address (of either family)
+    V4(ipv4::Address, u8), // address and prefix length
+    V6(ipv6::Address, u8)
+}
+
+#[derive(Clone,Debug,Copy,PartialEq,Eq,Hash)]
+pub enum Address {
+    V4(ipv4::Address),
+    V6(ipv6::Address)
+}
+
+// Flow tuple (and other fields matched by flows) of a packet
+#[derive(Clone,Debug)]
+pub struct Tuple {
+    pub src: Address,
+    pub dst: Address,
+    pub protocol: Option<u8>,      // None for non-first IPv6 fragments
+    pub ports: Option<(u16, u16)>, // source and destination port (TCP/UDP)
+    pub dscp: u8,
+    pub ecn: u8,
+    pub icmp: Option<(u8, u8)>,    // type and code (ICMP/ICMPv6)
+    pub tcp_flags: Option<u8>      // (TCP)
+}
+
+impl Prefix {
+    pub fn contains(&self, address: &Address) -> bool {
+        match (self, address) {
+            (Prefix::Any, _) => true,
+            (Prefix::V4(prefix, length), Address::V4(address)) =>
+                lib::ntohl(prefix ^ address)
+                    .checked_shr(32 - *length as u32).unwrap_or(0) == 0,
+            (Prefix::V6(prefix, length), Address::V6(address)) =>
+                (u128::from_be_bytes(*prefix) ^ u128::from_be_bytes(*address))
+                    .checked_shr(128 - *length as u32).unwrap_or(0) == 0,
+            _ => false
+        }
+    }
+}
+
+// Parse an address or CIDR prefix (e.g., "10.1.0.0/16" or "2001:db8::/32").
+// Addresses without prefix length are host prefixes (/32 or /128).
+pub fn prefix(string: &str) -> Option<Prefix> {
+    let (address, length) = match string.split_once('/') {
+        Some((address, length)) => (address, Some(length.parse().ok()?)),
+        None => (string, None)
+    };
+    match IpAddr::from_str(address).ok()? {
+        // NB: ipv4::Address is in network byte order
+        IpAddr::V4(ip) => match length.unwrap_or(32) {
+            length @ 0..=32 =>
+                Some(Prefix::V4(u32::from_ne_bytes(ip.octets()), length)),
+            _ => None
+        },
+        IpAddr::V6(ip) => match length.unwrap_or(128) {
+            length @ 0..=128 => Some(Prefix::V6(ip.octets(), length)),
+            _ => None
+        }
+    }
+}
+
+// Parse a port range (e.g., "40000-50000", or "80")
+pub fn port_range(string: &str) -> Option<RangeInclusive<u16>> {
+    let (min, max) = string.split_once('-').unwrap_or((string, string));
+    let (min, max): (u16, u16) = (min.parse().ok()?, max.parse().ok()?);
+    (min <= max).then_some(min..=max)
+}
+
+impl Flow {
+    pub fn matches(&self, tuple: &Tuple) -> bool {
+        let any_port = |ports: &RangeInclusive<u16>|
+            *ports == (0..=u16::MAX);
+        self.src.contains(&tuple.src)
+            && self.dst.contains(&tuple.dst)
+            && (self.protocol == 0 || tuple.protocol == Some(self.protocol))
+            && match tuple.ports {
+                Some((src, dst)) =>
+                    self.src_ports.contains(&src)
+                    && self.dst_ports.contains(&dst),
+                None =>
+                    any_port(&self.src_ports) && any_port(&self.dst_ports)
+            }
+            && self.dscp.is_none_or(|dscp| tuple.dscp == dscp)
+            && self.ecn.is_none_or(|ecn| tuple.ecn == ecn)
+            && self.icmp_type.is_none_or(|icmp_type| {
+                tuple.icmp.is_some_and(|icmp| icmp.0 == icmp_type)
+            })
+            && self.icmp_code.is_none_or(|icmp_code| {
+                tuple.icmp.is_some_and(|icmp| icmp.1 == icmp_code)
+            })
+            && self.tcp_flags.is_none_or(|(mask, value)| {
+                tuple.tcp_flags.is_some_and(|flags| flags & mask == value)
+            })
+            && !self.except.iter().any(|flow| flow.matches(tuple))
+    }
+
+    // Return flow with source and destination swapped (i.e., the flow that
+    // matches replies to the packets matched by this flow)
+    pub fn mirror(&self) -> Flow {
+        Flow {
+            src: self.dst,
+            dst: self.src,
+            src_ports: self.dst_ports.clone(),
+            dst_ports: self.src_ports.clone(),
+            except: self.except.iter().map(Flow::mirror).collect(),
+            ..self.clone()
+        }
+    }
+}
+
 #[derive(Clone,Debug)]
 pub struct Split {
     pub flows: Vec<Flow>
 }
 impl engine::AppConfig for Split {
     fn new(&self) -> Box<dyn engine::App> {
-        Box::new(SplitApp {flows: self.flows.to_vec()})
+        Box::new(SplitApp {
+            classifier: Classifier::new(&self.flows),
+            labels: self.flows.iter().map(|flow| flow.label.clone()).collect()
+        })
     }
 }
 pub struct SplitApp {
-    flows: Vec<Flow>
+    classifier: Classifier,
+    labels: Vec<String>
 }
 impl engine::App for SplitApp {
     fn has_push(&self) -> bool { true }
@@ -51,53 +207,489 @@ impl engine::App for SplitApp {
         let default = app.output.get("default").unwrap();
         while !link::empty(&input) {
             let mut p = link::receive(&mut input);
-            let mut output = default.borrow_mut();
-            for flow in &self.flows {
-                if flow_match(&mut p, flow) {
-                    output = app.output.get(&flow.label).unwrap().borrow_mut();
-                    break
-                }
-            }
-            link::transmit(&mut output, p);
+            let flow = tuple(&mut p)
+                .and_then(|tuple| self.classifier.classify(&tuple));
+            let output = match flow {
+                Some(flow) => app.output.get(&self.labels[flow]).unwrap(),
+                None => default
+            };
+            link::transmit(&mut output.borrow_mut(), p);
         }
     }
 }
 
-fn flow_match(p: &mut packet::Packet, flow: &Flow) -> bool {
+// Return the flow tuple of a packet, or None if it is neither IPv4 nor IPv6
+//
+// NYI: IP Options
+pub fn tuple(p: &mut packet::Packet) -> Option<Tuple> {
     let eth = hdr::from_mem::<Ethernet>(&mut p.data);
-    if eth.ethertype() != ethernet::TYPE_IPV4 { return false } // NYI: IPv6
-
     let ip_ofs = hdr::size_of::<Ethernet>();
-    let ip = hdr::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
-    if ip.ihl() > 5 { return false } // NYI: IP Options
 
-    let addr = match flow.dir {
-        Dir::Src => ip.src(),
-        Dir::Dst => ip.dst()
+    let (src, dst, dscp, ecn, protocol, proto_ofs) = match eth.ethertype() {
+        ethernet::TYPE_IPV4 => {
+            let ip = hdr::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            if ip.ihl() > 5 { return None } // NYI: IP Options
+            (Address::V4(ip.src()), Address::V4(ip.dst()), ip.dscp(), ip.ecn(),
+             Some(ip.protocol()), ip_ofs + hdr::size_of::<IPv4>())
+        }
+        ethernet::TYPE_IPV6 => {
+            let length = p.length as usize;
+            if length < ip_ofs + hdr::size_of::<IPv6>() { return None }
+            let ip = hdr::from_mem::<IPv6>(&mut p.data[ip_ofs..]);
+            let (src, dst) = (Address::V6(ip.src()), Address::V6(ip.dst()));
+            let (dscp, ecn) = (ip.dscp(), ip.ecn());
+            // Protocol is unknown for non-first fragments
+            match ipv6::upper_layer(&p.data[ip_ofs..length]) {
+                Some((protocol, ofs)) =>
+                    (src, dst, dscp, ecn, Some(protocol), ip_ofs + ofs),
+                None => (src, dst, dscp, ecn, None, 0)
+            }
+        }
+        _ => return None
     };
-    if flow.ip > 0 && addr != flow.ip { return false }
-    if flow.protocol > 0 && ip.protocol() != flow.protocol { return false }
 
-    let proto_ofs = hdr::size_of::<Ethernet>() + hdr::size_of::<IPv4>();
+    let (mut ports, mut icmp, mut tcp_flags) = (None, None, None);
+    match protocol {
+        Some(ipv4::PROTOCOL_TCP) => {
+            let tcp = hdr::from_mem::<TCP>(&mut p.data[proto_ofs..]);
+            ports = Some((tcp.src_port(), tcp.dst_port()));
+            tcp_flags = Some(tcp.flags());
+        }
+        Some(ipv4::PROTOCOL_UDP) => {
+            let udp = hdr::from_mem::<UDP>(&mut p.data[proto_ofs..]);
+            ports = Some((udp.src_port(), udp.dst_port()));
+        }
+        Some(ipv4::PROTOCOL_ICMP) | Some(ipv6::PROTOCOL_ICMPV6) =>
+            icmp = Some((p.data[proto_ofs], p.data[proto_ofs + 1])),
+        _ => ()
+    };
 
-    if flow.protocol == ipv4::PROTOCOL_TCP {
-        let tcp = hdr::from_mem::<TCP>(&mut p.data[proto_ofs..]);
-        let port = match flow.dir {
-            Dir::Src => tcp.src_port(),
-            Dir::Dst => tcp.dst_port()
-        };
-        if port < flow.port_min || port > flow.port_max { return false }
+    Some(Tuple {src, dst, protocol, ports, dscp, ecn, icmp, tcp_flags})
+}
+
+
+// Classifier: flows of a Split app compiled into lookup structures, so that
+// classifying a packet does not take time proportional to the number of flows
+//
+// Each field of a packet’s flow tuple is looked up separately, and yields the
+// set of flows that match it as a bitmap (one bit per flow, in order). The
+// first flow matching all fields is the lowest bit set in the intersection of
+// these bitmaps.
+//
+//   - addresses are looked up in a hash table of host addresses (i.e., flows
+//     with /32 or /128 prefixes), and otherwise in a binary trie of prefixes
+//     (one per address family); each trie node carries the flows of its own
+//     prefix and of all shorter prefixes containing it
+//   - protocols, DSCP and ECN codepoints, ICMP types and codes, and TCP flags
+//     are looked up in tables indexed by value
+//   - ports are looked up by binary search in a table of elementary intervals
+//     (the port ranges of all flows split at each other’s boundaries)
+//
+// Hence, a lookup costs at most one step per address bit, plus O(log(flows))
+// for ports, plus O(flows/64) for intersecting bitmaps.
+//
+// Exceptions are not compiled: they are checked in order for each candidate
+// flow (lowest bit first), and candidates with a matching exception are
+// skipped.
+
+pub struct Classifier {
+    bitmaps: Bitmaps,
+    src: PrefixTable,
+    dst: PrefixTable,
+    src_ports: PortTable,
+    dst_ports: PortTable,
+    // Bitmap per value (the last entry is used for packets without value)
+    protocols: Vec<usize>,
+    dscp: Vec<usize>,
+    ecn: Vec<usize>,
+    icmp_types: Vec<usize>,
+    icmp_codes: Vec<usize>,
+    tcp_flags: Vec<usize>,
+    except: Vec<Vec<Flow>> // exceptions per flow
+}
+
+impl Classifier {
+
+    pub fn new(flows: &[Flow]) -> Classifier {
+        let mut bitmaps = Bitmaps::new(flows.len());
+        let src: Vec<_> = flows.iter().map(|flow| flow.src).collect();
+        let dst: Vec<_> = flows.iter().map(|flow| flow.dst).collect();
+        let src_ports: Vec<_> = flows.iter()
+            .map(|flow| flow.src_ports.clone()).collect();
+        let dst_ports: Vec<_> = flows.iter()
+            .map(|flow| flow.dst_ports.clone()).collect();
+        let protocols = value_table(flows, 256, &mut bitmaps, |flow, value| {
+            flow.protocol == 0
+                || value.is_some_and(|value| value == flow.protocol as usize)
+        });
+        let dscp = value_table(flows, 64, &mut bitmaps, |flow, value| {
+            match_value(flow.dscp, value)
+        });
+        let ecn = value_table(flows, 4, &mut bitmaps, |flow, value| {
+            match_value(flow.ecn, value)
+        });
+        let icmp_types = value_table(flows, 256, &mut bitmaps, |flow, value| {
+            match_value(flow.icmp_type, value)
+        });
+        let icmp_codes = value_table(flows, 256, &mut bitmaps, |flow, value| {
+            match_value(flow.icmp_code, value)
+        });
+        let tcp_flags = value_table(flows, 256, &mut bitmaps, |flow, value| {
+            flow.tcp_flags.is_none_or(|(mask, flags)| {
+                value.is_some_and(|value| value as u8 & mask == flags)
+            })
+        });
+        Classifier {
+            src: PrefixTable::new(&src, &mut bitmaps),
+            dst: PrefixTable::new(&dst, &mut bitmaps),
+            src_ports: PortTable::new(&src_ports, &mut bitmaps),
+            dst_ports: PortTable::new(&dst_ports, &mut bitmaps),
+            protocols,
+            dscp,
+            ecn,
+            icmp_types,
+            icmp_codes,
+            tcp_flags,
+            except: flows.iter().map(|flow| flow.except.clone()).collect(),
+            bitmaps
+        }
+    }
 
-    } else if flow.protocol == ipv4::PROTOCOL_UDP {
-        let udp = hdr::from_mem::<UDP>(&mut p.data[proto_ofs..]);
-        let port = match flow.dir {
-            Dir::Src => udp.src_port(),
-            Dir::Dst => udp.dst_port()
+    // Return the index of the first flow matching tuple
+    pub fn classify(&self, tuple: &Tuple) -> Option<usize> {
+        let (src_ports, dst_ports) = match tuple.ports {
+            Some((src, dst)) =>
+                (self.src_ports.lookup(src), self.dst_ports.lookup(dst)),
+            None => (self.src_ports.any, self.dst_ports.any)
         };
-        if port < flow.port_min || port > flow.port_max { return false }
+        let lookup = |table: &Vec<usize>, value: Option<u8>| match value {
+            Some(value) => table[value as usize],
+            None => table[table.len() - 1]
+        };
+        let maps = [
+            self.src.lookup(&tuple.src),
+            self.dst.lookup(&tuple.dst),
+            src_ports,
+            dst_ports,
+            lookup(&self.protocols, tuple.protocol),
+            self.dscp[tuple.dscp as usize],
+            self.ecn[tuple.ecn as usize],
+            lookup(&self.icmp_types, tuple.icmp.map(|icmp| icmp.0)),
+            lookup(&self.icmp_codes, tuple.icmp.map(|icmp| icmp.1)),
+            lookup(&self.tcp_flags, tuple.tcp_flags)
+        ];
+        let words = self.bitmaps.words;
+        for word in 0..words {
+            let mut bits = maps.iter().fold(!0u64, |bits, &map| {
+                bits & self.bitmaps.data[map * words + word]
+            });
+            while bits != 0 {
+                let flow = word * 64 + bits.trailing_zeros() as usize;
+                if !self.except[flow].iter().any(|flow| flow.matches(tuple)) {
+                    return Some(flow)
+                }
+                bits &= bits - 1;
+            }
+        }
+        None
+    }
+
+}
+
+// Arena of fixed size bitmaps (referenced by index)
+struct Bitmaps {
+    words: usize, // size of each bitmap in 64-bit words
+    count: usize,
+    data: Vec<u64>
+}
+
+impl Bitmaps {
+
+    fn new(bits: usize) -> Bitmaps {
+        Bitmaps { words: bits.div_ceil(64), count: 0, data: Vec::new() }
+    }
+
+    fn alloc(&mut self) -> usize {
+        self.data.resize(self.data.len() + self.words, 0);
+        self.count += 1;
+        self.count - 1
+    }
+
+    fn set(&mut self, map: usize, bit: usize) {
+        self.data[map * self.words + bit / 64] |= 1 << (bit % 64);
+    }
+
+    fn or(&mut self, dst: usize, src: usize) {
+        for word in 0..self.words {
+            self.data[dst * self.words + word] |=
+                self.data[src * self.words + word];
+        }
+    }
+
+}
+
+struct PrefixTable {
+    hosts: HashMap<Address, usize>, // host address -> bitmap
+    v4: Trie,
+    v6: Trie
+}
+
+impl PrefixTable {
+
+    fn new(prefixes: &[Prefix], bitmaps: &mut Bitmaps) -> PrefixTable {
+        let (mut v4, mut v6) = (Trie::new(bitmaps), Trie::new(bitmaps));
+        let mut hosts = Vec::new();
+        for (flow, prefix) in prefixes.iter().enumerate() {
+            match *prefix {
+                Prefix::Any => {
+                    v4.mark(0, flow, bitmaps);
+                    v6.mark(0, flow, bitmaps);
+                }
+                Prefix::V4(address, length) => {
+                    let key = lib::ntohl(address) as u128;
+                    let node = v4.insert(key, 32, length as u32);
+                    v4.mark(node, flow, bitmaps);
+                    if length == 32 {
+                        hosts.push((Address::V4(address), node));
+                    }
+                }
+                Prefix::V6(address, length) => {
+                    let key = u128::from_be_bytes(address);
+                    let node = v6.insert(key, 128, length as u32);
+                    v6.mark(node, flow, bitmaps);
+                    if length == 128 {
+                        hosts.push((Address::V6(address), node));
+                    }
+                }
+            }
+        }
+        v4.accumulate(bitmaps);
+        v6.accumulate(bitmaps);
+        let hosts = hosts.into_iter()
+            .map(|(address, node)| match address {
+                Address::V4(_) => (address, v4.nodes[node].bitmap),
+                Address::V6(_) => (address, v6.nodes[node].bitmap)
+            }).collect();
+        PrefixTable { hosts, v4, v6 }
+    }
+
+    fn lookup(&self, address: &Address) -> usize {
+        if let Some(&map) = self.hosts.get(address) { return map }
+        match address {
+            Address::V4(address) =>
+                self.v4.lookup(lib::ntohl(*address) as u128, 32),
+            Address::V6(address) =>
+                self.v6.lookup(u128::from_be_bytes(*address), 128)
+        }
+    }
+
+}
+
+// Binary trie of address prefixes (keys are the lower bits of an u128, and
+// the root node is at index zero)
+struct Trie {
+    nodes: Vec<TrieNode>
+}
+#[derive(Default)]
+struct TrieNode {
+    children: [usize; 2], // zero if there is no child
+    bitmap: usize,        // flows matching this node
+    own: bool             // does this node have a bitmap of its own?
+}
+
+impl Trie {
+
+    fn new(bitmaps: &mut Bitmaps) -> Trie {
+        Trie { nodes: vec![TrieNode {
+            children: [0, 0],
+            bitmap: bitmaps.alloc(),
+            own: true
+        }] }
+    }
+
+    // Insert first length of bits of key, return its node
+    fn insert(&mut self, key: u128, bits: u32, length: u32) -> usize {
+        let mut node = 0;
+        for i in 0..length {
+            let bit = ((key >> (bits - 1 - i)) & 1) as usize;
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(TrieNode::default());
+                self.nodes[node].children[bit] = self.nodes.len() - 1;
+            }
+            node = self.nodes[node].children[bit];
+        }
+        node
+    }
+
+    fn mark(&mut self, node: usize, flow: usize, bitmaps: &mut Bitmaps) {
+        let node = &mut self.nodes[node];
+        if !node.own {
+            node.bitmap = bitmaps.alloc();
+            node.own = true;
+        }
+        bitmaps.set(node.bitmap, flow);
+    }
+
+    // Propagate flows from nodes to their children (NB: children are always
+    // inserted after their parents)
+    fn accumulate(&mut self, bitmaps: &mut Bitmaps) {
+        for node in 0..self.nodes.len() {
+            let bitmap = self.nodes[node].bitmap;
+            for child in self.nodes[node].children {
+                if child == 0 { continue }
+                let child = &mut self.nodes[child];
+                if child.own { bitmaps.or(child.bitmap, bitmap) }
+                else { child.bitmap = bitmap }
+            }
+        }
+    }
+
+    // Return bitmap of the longest prefix in trie that contains key
+    fn lookup(&self, key: u128, bits: u32) -> usize {
+        let mut node = &self.nodes[0];
+        for i in 0..bits {
+            let bit = ((key >> (bits - 1 - i)) & 1) as usize;
+            match node.children[bit] {
+                0 => break,
+                child => node = &self.nodes[child]
+            }
+        }
+        node.bitmap
+    }
+
+}
+
+// Return table of bitmaps of flows matching values 0..size (and None, at
+// index size). Values that match the same flows as None share its bitmap.
+fn value_table<F>(flows: &[Flow], size: usize, bitmaps: &mut Bitmaps,
+                  matches: F) -> Vec<usize>
+where F: Fn(&Flow, Option<usize>) -> bool
+{
+    let wildcard = bitmaps.alloc();
+    for (index, flow) in flows.iter().enumerate() {
+        if matches(flow, None) { bitmaps.set(wildcard, index) }
+    }
+    let mut table = vec![wildcard; size + 1];
+    for (value, map) in table.iter_mut().take(size).enumerate() {
+        for (index, flow) in flows.iter().enumerate() {
+            if matches(flow, None) || !matches(flow, Some(value)) { continue }
+            if *map == wildcard {
+                *map = bitmaps.alloc();
+                bitmaps.or(*map, wildcard);
+            }
+            bitmaps.set(*map, index);
+        }
+    }
+    table
+}
+
+fn match_value(matcher: Option<u8>, value: Option<usize>) -> bool {
+    match matcher {
+        Some(matcher) => value == Some(matcher as usize),
+        None => true
+    }
+}
+
+struct PortTable {
+    starts: Vec<u16>,    // first port of each elementary interval
+    bitmaps: Vec<usize>, // flows matching each elementary interval
+    any: usize           // flows matching packets without ports
+}
+
+impl PortTable {
+
+    fn new(ranges: &[RangeInclusive<u16>], bitmaps: &mut Bitmaps) -> PortTable {
+        let mut starts = vec![0];
+        for range in ranges {
+            starts.push(*range.start());
+            if *range.end() < u16::MAX { starts.push(range.end() + 1) }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+        let mut maps = Vec::new();
+        for start in &starts {
+            let map = bitmaps.alloc();
+            for (flow, range) in ranges.iter().enumerate() {
+                if range.contains(start) { bitmaps.set(map, flow) }
+            }
+            maps.push(map);
+        }
+        let any = bitmaps.alloc();
+        for (flow, range) in ranges.iter().enumerate() {
+            if *range == (0..=u16::MAX) { bitmaps.set(any, flow) }
+        }
+        PortTable { starts, bitmaps: maps, any }
+    }
+
+    fn lookup(&self, port: u16) -> usize {
+        self.bitmaps[self.starts.partition_point(|&start| start <= port) - 1]
     }
 
-    true
+}
+
+
+// DscpSplit app: forward incoming packets to outputs according to their DSCP
+// (Differentiated Services Codepoint); packets not matching any class
+// (including non-IP packets) are forwarded on the "default" output
+
+#[derive(Clone,Debug)]
+pub struct DscpClass {
+    pub label: String, // name of the output link
+    pub dscp: Vec<u8>  // codepoints of class (e.g., 46 for EF)
+}
+
+#[derive(Clone,Debug)]
+pub struct DscpSplit {
+    pub classes: Vec<DscpClass>
+}
+impl engine::AppConfig for DscpSplit {
+    fn new(&self) -> Box<dyn engine::App> {
+        // Map each codepoint to the first class that contains it
+        let mut classes = vec![None; 64];
+        for class in self.classes.iter().rev() {
+            for &dscp in &class.dscp {
+                assert!(dscp < 64, "DSCP must be below 64");
+                classes[dscp as usize] = Some(class.label.clone());
+            }
+        }
+        Box::new(DscpSplitApp {classes})
+    }
+}
+pub struct DscpSplitApp {
+    classes: Vec<Option<String>> // DSCP -> label
+}
+impl engine::App for DscpSplitApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let default = app.output.get("default").unwrap();
+        while !link::empty(&input) {
+            let mut p = link::receive(&mut input);
+            let label = dscp(&mut p)
+                .and_then(|dscp| self.classes[dscp as usize].as_ref());
+            let output = match label {
+                Some(label) => app.output.get(label).unwrap(),
+                None => default
+            };
+            link::transmit(&mut output.borrow_mut(), p);
+        }
+    }
+}
+
+fn dscp(p: &mut packet::Packet) -> Option<u8> {
+    let eth = hdr::from_mem::<Ethernet>(&mut p.data);
+    let ip_ofs = hdr::size_of::<Ethernet>();
+
+    match eth.ethertype() {
+        ethernet::TYPE_IPV4 =>
+            Some(hdr::from_mem::<IPv4>(&mut p.data[ip_ofs..]).dscp()),
+        ethernet::TYPE_IPV6 => {
+            let length = p.length as usize;
+            if length < ip_ofs + hdr::size_of::<IPv6>() { return None }
+            Some(hdr::from_mem::<IPv6>(&mut p.data[ip_ofs..]).dscp())
+        }
+        _ => None
+    }
 }
 
 
@@ -116,28 +708,37 @@ fn flow_match(p: &mut packet::Packet, flow: &Flow) -> bool {
 //    Bits   | 63..48  39..32    31..0
 //    Fields | port    protocol  ipv4addr
 //
-// For each packet received on the input port, its flow tuple is extracted and
+// For each packet received on any input port, its flow tuple is extracted and
 // hashed to select a slot in the array. The slot’s packet counter is incremented
 // by one, the bits counter is incremented by the bit length of the packet on the
-// wire (i.e., including Ethernet overhead), the and flow ID is set according to
+// wire (i.e., including the overhead of the link-layer `framing' of the input
+// port, see packet::Framing), the and flow ID is set according to
 // the packet’s flow tuple. I.e., the slot’s flow ID is set to reflect the
-// flow tuple of the last packet counted.
+// flow tuple of the last packet counted. Packets received on input ports
+// without framing are counted with Ethernet framing. All packets are forwarded
+// on the output port.
 //
 // NYI: IPv6, protocols that use ports other than TCP/UDP
 
 #[derive(Clone,Debug)]
 pub struct Top {
     pub path: String,
-    pub dir: Dir
+    pub dir: Dir,
+    pub framing: HashMap<String, packet::Framing> // framing by input port
 }
 impl engine::AppConfig for Top {
     fn new(&self) -> Box<dyn engine::App> {
-        Box::new(TopApp {map: open_flowtop_map(&self.path), dir: self.dir})
+        Box::new(TopApp {
+            map: open_flowtop_map(&self.path),
+            dir: self.dir,
+            framing: self.framing.clone()
+        })
     }
 }
 pub struct TopApp {
     map: *mut FlowTop,
-    dir: Dir
+    dir: Dir,
+    framing: HashMap<String, packet::Framing>
 }
 impl engine::App for TopApp {
     fn has_stop(&self) -> bool { true }
@@ -145,17 +746,21 @@ impl engine::App for TopApp {
 
     fn has_push(&self) -> bool { true }
     fn push(&self, app: &engine::AppState) {
-        let mut input = app.input.get("input").unwrap().borrow_mut();
         let mut output = app.output.get("output").unwrap().borrow_mut();
-        while !link::empty(&input) {
-            let mut p = link::receive(&mut input);
-            flow_count(&mut p, self.dir, self.map);
-            link::transmit(&mut output, p);
+        for (name, input) in app.input.iter() {
+            let framing = self.framing.get(name).unwrap_or(&packet::ETHERNET);
+            let mut input = input.borrow_mut();
+            while !link::empty(&input) {
+                let mut p = link::receive(&mut input);
+                flow_count(&mut p, self.dir, framing, self.map);
+                link::transmit(&mut output, p);
+            }
         }
     }
 }
 
-fn flow_count(p: &mut Box<packet::Packet>, dir: Dir, map: *mut FlowTop) {
+fn flow_count(p: &mut Box<packet::Packet>, dir: Dir,
+              framing: &packet::Framing, map: *mut FlowTop) {
     let mut addr: u32 = 0;
     let mut protocol: u8 = 0;
     let mut port: u16 = 0;
@@ -192,7 +797,8 @@ fn flow_count(p: &mut Box<packet::Packet>, dir: Dir, map: *mut FlowTop) {
         }
     }
 
-    flowtop_inc(map, addr, protocol, port, packet::bitlength(p));
+    flowtop_inc(map, addr, protocol, port,
+                packet::framed_bitlength(p, framing));
 }
 
 fn open_flowtop_map(path: &str) -> *mut FlowTop {
@@ -243,6 +849,64 @@ fn flowtop_inc(map: *mut FlowTop, ip: u32, protocol: u8, port: u16, bits: u64) {
     slot.bits += bits;
 }
 
+// Return hash of a packet’s 5-tuple (source and destination address and port,
+// and protocol), mixed the same way flow_slot() hashes flow IDs. IPv6
+// addresses are folded to 32 bits, and the ports of IPv6 packets are located
+// past any extension headers (non-first fragments hash without ports).
+// Packets that are neither IPv4 nor IPv6 hash to zero.
+//
+// NYI: IP Options
+pub fn flow_hash(p: &mut packet::Packet) -> u64 {
+    let eth = hdr::from_mem::<Ethernet>(&mut p.data);
+    let ip_ofs = hdr::size_of::<Ethernet>();
+
+    let (src, dst, protocol, proto_ofs) = match eth.ethertype() {
+        ethernet::TYPE_IPV4 => {
+            let ip = hdr::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            let proto_ofs = match ip.ihl() {
+                5 => Some(ip_ofs + hdr::size_of::<IPv4>()),
+                _ => None
+            };
+            (ip.src(), ip.dst(), ip.protocol(), proto_ofs)
+        }
+        ethernet::TYPE_IPV6 => {
+            let length = p.length as usize;
+            if length < ip_ofs + hdr::size_of::<IPv6>() { return 0 }
+            let ip = hdr::from_mem::<IPv6>(&mut p.data[ip_ofs..]);
+            let (src, dst) = (fold_address(&ip.src()), fold_address(&ip.dst()));
+            match ipv6::upper_layer(&p.data[ip_ofs..length]) {
+                Some((protocol, ofs)) =>
+                    (src, dst, protocol, Some(ip_ofs + ofs)),
+                None => (src, dst, ipv6::PROTOCOL_FRAGMENT, None)
+            }
+        }
+        _ => return 0
+    };
+
+    let mut ports = (0, 0);
+    if let Some(proto_ofs) = proto_ofs {
+        if protocol == ipv4::PROTOCOL_TCP {
+            let tcp = hdr::from_mem::<TCP>(&mut p.data[proto_ofs..]);
+            ports = (tcp.src_port(), tcp.dst_port());
+        } else if protocol == ipv4::PROTOCOL_UDP {
+            let udp = hdr::from_mem::<UDP>(&mut p.data[proto_ofs..]);
+            ports = (udp.src_port(), udp.dst_port());
+        }
+    }
+
+    murmurhash64_mix64(
+        flow_id(src, protocol, ports.0) ^
+            murmurhash64_mix64(flow_id(dst, protocol, ports.1))
+    )
+}
+
+// Fold IPv6 address into 32 bits (XOR of its words)
+fn fold_address(address: &ipv6::Address) -> u32 {
+    address.chunks(4)
+        .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+        .fold(0, |folded, word| folded ^ word)
+}
+
 fn flow_id(ip: u32, protocol: u8, port: u16) -> u64 {
     ((port as u64) << 48) | ((protocol as u64) << 32) | ((ip as u64) << 0)
 }
@@ -269,8 +933,13 @@ mod selftest {
     use crate::lib;
     use crate::config;
     use crate::basic_apps;
+    use crate::tcp;
     use std::cell::RefCell;
     use std::fs;
+    use std::time::Instant;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    extern crate test;
 
     #[test]
     fn split() {
@@ -314,19 +983,21 @@ mod selftest {
         config::app(&mut c, "split", &Split {flows: vec![
             Flow {
                 label: "src_addr".to_string(),
-                dir: Dir::Src,
-                ip: ipv4::pton("192.168.0.123"),
+                src: Prefix::V4(ipv4::pton("192.168.0.123"), 32),
+                dst: Prefix::Any,
                 protocol: 0,
-                port_min: 0,
-                port_max: 0
+                src_ports: 0..=65535,
+                dst_ports: 0..=65535,
+                ..Default::default()
             },
             Flow {
                 label: "dst_tcp80".to_string(),
-                dir: Dir::Dst,
-                ip: 0,
+                src: Prefix::Any,
+                dst: Prefix::Any,
                 protocol: ipv4::PROTOCOL_TCP,
-                port_min: 80,
-                port_max: 80
+                src_ports: 0..=65535,
+                dst_ports: 80..=80,
+                ..Default::default()
             }
         ]});
         config::app(&mut c, "sink", &basic_apps::Sink {});
@@ -352,6 +1023,446 @@ mod selftest {
         assert!(default_out.borrow().txpackets == 1);
     }
 
+    #[test]
+    fn split_ipv6() {
+        let udp = |src: u8, next_header: u8, ext: &[u8]| {
+            let mut packet = vec![
+                /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+                /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+                /*Ethertype*/ 0x86, 0xdd,
+                /*IPv6 version, TC, flow label*/ 0x60, 0x00, 0x00, 0x00,
+                /*Payload length*/ 0x00, 8 + ext.len() as u8,
+                /*Next header*/ next_header, /*Hop limit*/ 0x40,
+                /*Src addr*/ 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                               0, 0, 0, 0, 0, 0, 0, src,
+                /*Dst addr*/ 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                               0, 0, 0, 0, 0, 0, 0, 0x2a];
+            packet.extend_from_slice(ext);
+            packet.extend_from_slice(&[
+                /*Src port*/ 0x13, 0x88, /*Dst port*/ 0x13, 0x88,
+                /*Length*/ 0x00, 0x08, /*Checksum*/ 0x00, 0x00]);
+            packet
+        };
+        let dstopts = [
+            /*Next header*/ ipv4::PROTOCOL_UDP, /*Length*/ 0,
+            /*Options*/ 0x01, 0x04, 0x00, 0x00, 0x00, 0x00];
+        let fragment = [
+            /*Next header*/ ipv4::PROTOCOL_UDP, 0,
+            /*Offset, M*/ 0x00, 0xb8, /*ID*/ 0x00, 0x00, 0x00, 0x01];
+        let packets = vec![
+            // UDP [2001:db8::1]:5000 -> [2001:db8::2a]:5000
+            udp(0x01, ipv4::PROTOCOL_UDP, &[]),
+            // UDP [2001:db8::1]:5000 -> [2001:db8::2a]:5000 (Dest. Options)
+            udp(0x01, ipv6::PROTOCOL_DSTOPTS, &dstopts),
+            // UDP [2001:db8::2]:5000 -> [2001:db8::2a]:5000 (Dest. Options)
+            udp(0x02, ipv6::PROTOCOL_DSTOPTS, &dstopts),
+            // UDP [2001:db8::2]:? -> [2001:db8::2a]:? (non-first fragment)
+            udp(0x02, ipv6::PROTOCOL_FRAGMENT, &fragment),
+            // IPv4
+            vec![
+                /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+                /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+                /*Ethertype*/ 0x08, 0x00,
+                /*IPv4 version, IHL*/ 0x45, /*TOS*/ 0x00,
+                /*Total length*/ 0x00, 0x1c, /*ID*/ 0x00, 0x00,
+                /*Flags, frag. offset*/ 0x40, 0x00, /*TTL*/ 0x40,
+                /*Protocol*/ 0x11, /*Checksum*/ 0x00, 0x00,
+                /*Src addr*/ 10, 0, 0, 1,
+                /*Dst addr*/ 10, 0, 0, 2,
+                /*Src port*/ 0x13, 0x88, /*Dst port*/ 0x13, 0x88]
+        ];
+
+        engine::configure(&config::new());
+        let mut c = config::new();
+        config::app(&mut c, "source", &PacketGen {packets});
+        config::app(&mut c, "split", &Split {flows: vec![
+            Flow {
+                label: "src_addr".to_string(),
+                src: Prefix::V6(ipv6::pton("2001:db8::1"), 128),
+                dst: Prefix::Any,
+                protocol: ipv4::PROTOCOL_UDP,
+                src_ports: 5000..=5000,
+                dst_ports: 0..=65535,
+                ..Default::default()
+            },
+            Flow {
+                label: "udp5000".to_string(),
+                src: Prefix::Any,
+                dst: Prefix::Any,
+                protocol: ipv4::PROTOCOL_UDP,
+                src_ports: 5000..=5000,
+                dst_ports: 0..=65535,
+                ..Default::default()
+            }
+        ]});
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> split.input");
+        for output in ["src_addr", "udp5000", "default"] {
+            config::link(&mut c, &format!("split.{} -> sink.{}", output, output));
+        }
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            done: Some(Box::new(|| true)), // single breath
+            report_links: true,
+            ..Default::default()
+        }));
+
+        let txpackets = |output: &str| engine::state().link_table
+            .get(&format!("split.{} -> sink.{}", output, output)).unwrap()
+            .borrow().txpackets;
+        assert!(txpackets("src_addr") == 2);
+        assert!(txpackets("udp5000") == 2);
+        assert!(txpackets("default") == 1);
+    }
+
+    #[test]
+    fn five_tuple() {
+        let v4 = |s: &str| Address::V4(ipv4::pton(s));
+        let v6 = |s: &str| Address::V6(ipv6::pton(s));
+        let tuple = |src, dst, protocol, ports| Tuple {
+            src, dst, protocol: Some(protocol), ports,
+            dscp: 0, ecn: 0, icmp: None, tcp_flags: None
+        };
+        // UDP from the SFU subnet 10.1.0.0/16 to client ports 40000-50000
+        let sfu = Flow {
+            label: "sfu".to_string(),
+            src: Prefix::V4(ipv4::pton("10.1.0.0"), 16),
+            dst: Prefix::Any,
+            protocol: ipv4::PROTOCOL_UDP,
+            src_ports: 0..=65535,
+            dst_ports: 40000..=50000,
+            ..Default::default()
+        };
+        let udp = ipv4::PROTOCOL_UDP;
+        assert!(sfu.matches(&tuple(v4("10.1.0.1"), v4("192.168.0.2"),
+                                   udp, Some((3478, 40000)))));
+        assert!(sfu.matches(&tuple(v4("10.1.255.255"), v4("192.168.0.2"),
+                                   udp, Some((3478, 50000)))));
+        assert!(!sfu.matches(&tuple(v4("10.2.0.1"), v4("192.168.0.2"),
+                                    udp, Some((3478, 40000)))));
+        assert!(!sfu.matches(&tuple(v4("10.1.0.1"), v4("192.168.0.2"),
+                                    udp, Some((3478, 50001)))));
+        assert!(!sfu.matches(&tuple(v4("10.1.0.1"), v4("192.168.0.2"),
+                                    ipv4::PROTOCOL_TCP, Some((3478, 40000)))));
+        assert!(!sfu.matches(&tuple(v6("::ffff:10.1.0.1"), v6("2001:db8::1"),
+                                    udp, Some((3478, 40000)))));
+        // Prefix lengths
+        let prefix = Prefix::V4(ipv4::pton("0.0.0.0"), 0);
+        assert!(prefix.contains(&v4("255.255.255.255")));
+        assert!(!prefix.contains(&v6("::")));
+        let prefix = Prefix::V4(ipv4::pton("10.0.0.1"), 32);
+        assert!(prefix.contains(&v4("10.0.0.1")));
+        assert!(!prefix.contains(&v4("10.0.0.0")));
+        let prefix = Prefix::V6(ipv6::pton("2001:db8::"), 32);
+        assert!(prefix.contains(&v6("2001:db8:ffff::1")));
+        assert!(!prefix.contains(&v6("2001:db9::1")));
+        assert!(Prefix::V6(ipv6::pton("::"), 0).contains(&v6("fe80::1")));
+        assert!(Prefix::Any.contains(&v4("10.0.0.1")));
+        assert!(Prefix::Any.contains(&v6("fe80::1")));
+        // Packets without ports only match flows without port ranges
+        let any = Flow {dst_ports: 0..=65535, protocol: 0, ..sfu};
+        let icmp = tuple(v4("10.1.0.1"), v4("192.168.0.2"), 1, None);
+        assert!(any.matches(&icmp));
+        let ports = Flow {src_ports: 0..=1023, ..any};
+        assert!(!ports.matches(&icmp));
+    }
+
+    #[test]
+    fn matchers() {
+        let ipv4 = |tos: u8, protocol: u8, l4: &[u8]| {
+            let mut packet = vec![
+                /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+                /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+                /*Ethertype*/ 0x08, 0x00,
+                /*IPv4 version, IHL*/ 0x45, /*TOS*/ tos,
+                /*Total length*/ 0x00, 20 + l4.len() as u8, /*ID*/ 0x00, 0x00,
+                /*Flags, frag. offset*/ 0x40, 0x00, /*TTL*/ 0x40,
+                /*Protocol*/ protocol, /*Checksum*/ 0x00, 0x00,
+                /*Src addr*/ 10, 0, 0, 1,
+                /*Dst addr*/ 10, 0, 0, 2];
+            packet.extend_from_slice(l4);
+            packet
+        };
+        let tcp = |flags: u8| vec![
+            /*Src port*/ 0x13, 0x88, /*Dst port*/ 0x00, 0x50,
+            /*Seq*/ 0, 0, 0, 0, /*Ack*/ 0, 0, 0, 0,
+            /*Data offset*/ 0x50, /*Flags*/ flags, /*Window*/ 0xff, 0xff,
+            /*Checksum*/ 0, 0, /*Urgent pointer*/ 0, 0];
+        let icmp_echo = [/*Type*/ 8, /*Code*/ 0, /*Checksum*/ 0, 0,
+                         /*ID*/ 0, 1, /*Seq*/ 0, 1];
+        let icmpv6_echo = vec![
+            /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+            /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+            /*Ethertype*/ 0x86, 0xdd,
+            /*IPv6 version, TC, flow label*/ 0x6b, 0x80, 0x00, 0x00,
+            /*Payload length*/ 0x00, 8,
+            /*Next header*/ ipv6::PROTOCOL_ICMPV6, /*Hop limit*/ 0x40,
+            /*Src addr*/ 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                           0, 0, 0, 0, 0, 0, 0, 1,
+            /*Dst addr*/ 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                           0, 0, 0, 0, 0, 0, 0, 2,
+            /*Type*/ 128, /*Code*/ 0, /*Checksum*/ 0, 0,
+            /*ID*/ 0, 1, /*Seq*/ 0, 1];
+        let flows = vec![
+            Flow {
+                label: "syn".to_string(),
+                protocol: ipv4::PROTOCOL_TCP,
+                tcp_flags: Some((tcp::FLAG_SYN | tcp::FLAG_ACK, tcp::FLAG_SYN)),
+                ..Default::default()
+            },
+            Flow {
+                label: "audio".to_string(),
+                dscp: Some(46),
+                ..Default::default()
+            },
+            Flow {
+                label: "ping".to_string(),
+                protocol: ipv4::PROTOCOL_ICMP,
+                icmp_type: Some(8),
+                icmp_code: Some(0),
+                ..Default::default()
+            },
+            Flow {
+                label: "ping6".to_string(),
+                protocol: ipv6::PROTOCOL_ICMPV6,
+                icmp_type: Some(128),
+                ..Default::default()
+            },
+            Flow {
+                label: "ce".to_string(),
+                ecn: Some(ipv4::ECN_CE),
+                ..Default::default()
+            }
+        ];
+        let cases = [
+            (ipv4(0, ipv4::PROTOCOL_TCP, &tcp(tcp::FLAG_SYN)), Some(0)),
+            (ipv4(46 << 2, ipv4::PROTOCOL_TCP, &tcp(tcp::FLAG_SYN)), Some(0)),
+            (ipv4(46 << 2, ipv4::PROTOCOL_TCP,
+                  &tcp(tcp::FLAG_SYN | tcp::FLAG_ACK)), Some(1)),
+            (ipv4(0, ipv4::PROTOCOL_TCP, &tcp(tcp::FLAG_ACK)), None),
+            (ipv4(34 << 2 | 0b11, ipv4::PROTOCOL_UDP, &[0; 8]), Some(4)),
+            (ipv4(0, ipv4::PROTOCOL_ICMP, &icmp_echo), Some(2)),
+            (ipv4(0, ipv4::PROTOCOL_ICMP, &[0; 8]), None), // Echo reply
+            (icmpv6_echo, Some(1)) // Traffic class: EF
+        ];
+        let classifier = Classifier::new(&flows);
+        for (data, flow) in cases {
+            let mut p = packet::allocate();
+            lib::copy(&mut p.data, &data, data.len());
+            p.length = data.len() as u16;
+            let tuple = tuple(&mut p).unwrap();
+            assert!(flows.iter().position(|f| f.matches(&tuple)) == flow,
+                    "{:?} {:?}", tuple, flow);
+            assert!(classifier.classify(&tuple) == flow);
+            packet::free(p);
+        }
+    }
+
+    #[test]
+    fn classifier() {
+        // Compare against Flow::matches (linear search) for random flows
+        let mut rng = StdRng::seed_from_u64(42);
+        for nflows in [0, 1, 10, 100, 300] {
+            let flows: Vec<_> = (0..nflows)
+                .map(|_| random_flow(&mut rng)).collect();
+            let classifier = Classifier::new(&flows);
+            let mut matches = 0;
+            for _ in 0..10_000 {
+                let tuple = random_tuple(&mut rng);
+                let linear = flows.iter().position(|flow| flow.matches(&tuple));
+                assert!(classifier.classify(&tuple) == linear,
+                        "{:?} {:?}", tuple, linear);
+                if linear.is_some() { matches += 1 }
+            }
+            println!("{} flows: {} matches", nflows, matches);
+        }
+    }
+
+    #[test]
+    fn classifier_bench() {
+        let nflows = match std::env::var("RUSH_CLASSIFIER_NFLOWS") {
+            Ok(val) => val.parse::<usize>().unwrap(),
+            _ => 500
+        };
+        let nlookups = match std::env::var("RUSH_CLASSIFIER_NLOOKUPS") {
+            Ok(val) => val.parse::<f64>().unwrap() as usize,
+            _ => 100_000
+        };
+        // Per-participant flows (UDP from a host to a port range), and a
+        // catch-all flow for the SFU subnet
+        let host = |i: usize|
+            ipv4::pton(&format!("10.1.{}.{}", i / 250, i % 250));
+        let mut flows: Vec<_> = (0..nflows).map(|i| Flow {
+            label: format!("participant{}", i),
+            src: Prefix::V4(host(i), 32),
+            dst: Prefix::Any,
+            protocol: ipv4::PROTOCOL_UDP,
+            src_ports: 0..=65535,
+            dst_ports: 40000..=50000,
+            ..Default::default()
+        }).collect();
+        flows.push(Flow {
+            label: "sfu".to_string(),
+            src: Prefix::V4(ipv4::pton("10.1.0.0"), 16),
+            dst: Prefix::Any,
+            protocol: 0,
+            src_ports: 0..=65535,
+            dst_ports: 0..=65535,
+            ..Default::default()
+        });
+        // UDP packets from participants (and other hosts of the SFU subnet)
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut packets: Vec<_> = (0..1024).map(|_| {
+            let i = rng.gen_range(0..nflows + nflows / 10);
+            let port = rng.gen_range(30000..60000u16).to_be_bytes();
+            let data = [
+                /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+                /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+                /*Ethertype*/ 0x08, 0x00,
+                /*IPv4 version, IHL*/ 0x45, /*TOS*/ 0x00,
+                /*Total length*/ 0x00, 28, /*ID*/ 0x00, 0x00,
+                /*Flags, frag. offset*/ 0x40, 0x00, /*TTL*/ 0x40,
+                /*Protocol*/ 0x11, /*Checksum*/ 0x00, 0x00,
+                /*Src addr*/ 10, 1, (i / 250) as u8, (i % 250) as u8,
+                /*Dst addr*/ 192, 168, 0, 2,
+                /*Src port*/ 0x0d, 0x96, /*Dst port*/ port[0], port[1],
+                /*Length*/ 0x00, 8, /*Checksum*/ 0x00, 0x00];
+            let mut p = packet::allocate();
+            lib::copy(&mut p.data, &data, data.len());
+            p.length = data.len() as u16;
+            p
+        }).collect();
+        let npackets = packets.len();
+
+        let classifier = Classifier::new(&flows);
+        let classify = |p: &mut packet::Packet|
+            tuple(p).and_then(|tuple| classifier.classify(&tuple));
+        for p in packets.iter_mut() {
+            let flow = flows.iter().position(|flow| flow_match(p, flow));
+            assert!(classify(p) == flow);
+        }
+        let start = Instant::now();
+        for i in 0..nlookups {
+            let p = &mut packets[i % npackets];
+            test::black_box(flows.iter().position(|flow| flow_match(p, flow)));
+        }
+        let linear = start.elapsed().as_secs_f64();
+        let start = Instant::now();
+        for i in 0..nlookups {
+            test::black_box(classify(&mut packets[i % npackets]));
+        }
+        let compiled = start.elapsed().as_secs_f64();
+        println!("Classified {} packets against {} flows: \
+                  baseline {:.2} Mpps, classifier {:.2} Mpps",
+                 nlookups, flows.len(),
+                 nlookups as f64 / linear / 1e6,
+                 nlookups as f64 / compiled / 1e6);
+        for p in packets {
+            packet::free(p);
+        }
+    }
+
+    // Match packet against flow like SplitApp did before Classifier, i.e.,
+    // parse the packet’s headers for each flow (IPv4 only)
+    fn flow_match(p: &mut packet::Packet, flow: &Flow) -> bool {
+        let eth = hdr::from_mem::<Ethernet>(&mut p.data);
+        if eth.ethertype() != ethernet::TYPE_IPV4 { return false }
+
+        let ip_ofs = hdr::size_of::<Ethernet>();
+        let ip = hdr::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+        if ip.ihl() > 5 { return false } // NYI: IP Options
+
+        if !flow.src.contains(&Address::V4(ip.src())) { return false }
+        if !flow.dst.contains(&Address::V4(ip.dst())) { return false }
+        if flow.protocol > 0 && ip.protocol() != flow.protocol { return false }
+
+        let proto_ofs = ip_ofs + hdr::size_of::<IPv4>();
+        let (src, dst) = match flow.protocol {
+            ipv4::PROTOCOL_TCP => {
+                let tcp = hdr::from_mem::<TCP>(&mut p.data[proto_ofs..]);
+                (tcp.src_port(), tcp.dst_port())
+            }
+            ipv4::PROTOCOL_UDP => {
+                let udp = hdr::from_mem::<UDP>(&mut p.data[proto_ofs..]);
+                (udp.src_port(), udp.dst_port())
+            }
+            _ => return true
+        };
+        flow.src_ports.contains(&src) && flow.dst_ports.contains(&dst)
+    }
+
+    fn random_flow(rng: &mut StdRng) -> Flow {
+        let ports = [0..=65535, 80..=80, 5000..=5010, 40000..=50000,
+                     1000..=45000];
+        Flow {
+            label: String::new(),
+            src: random_prefix(rng),
+            dst: random_prefix(rng),
+            protocol: [0, 0, ipv4::PROTOCOL_TCP, ipv4::PROTOCOL_UDP, 1]
+                [rng.gen_range(0..5)],
+            src_ports: ports[rng.gen_range(0..ports.len())].clone(),
+            dst_ports: ports[rng.gen_range(0..ports.len())].clone(),
+            dscp: [None, None, Some(0), Some(46)][rng.gen_range(0..4)],
+            ecn: [None, None, Some(0), Some(3)][rng.gen_range(0..4)],
+            icmp_type: [None, None, Some(0), Some(8)][rng.gen_range(0..4)],
+            icmp_code: [None, None, Some(0), Some(1)][rng.gen_range(0..4)],
+            tcp_flags: [None, None, Some((0x12, 0x02)), Some((0x02, 0x02))]
+                [rng.gen_range(0..4)],
+            except: (0..[0, 0, 0, 1, 2][rng.gen_range(0..5)])
+                .map(|_| random_flow(rng)).collect()
+        }
+    }
+
+    fn random_prefix(rng: &mut StdRng) -> Prefix {
+        match random_address(rng) {
+            _ if rng.gen_ratio(1, 4) => Prefix::Any,
+            Address::V4(address) => Prefix::V4(
+                address, [0, 8, 16, 24, 30, 32][rng.gen_range(0..6)]),
+            Address::V6(address) => Prefix::V6(
+                address, [0, 32, 120, 126, 128][rng.gen_range(0..5)])
+        }
+    }
+
+    fn random_address(rng: &mut StdRng) -> Address {
+        let x = rng.gen_range(0..8);
+        match rng.gen_bool(0.5) {
+            true => Address::V4(ipv4::pton(&format!("10.{}.{}.{}", x, x, x))),
+            false => Address::V6(ipv6::pton(&format!("2001:db8::{}", x)))
+        }
+    }
+
+    fn random_tuple(rng: &mut StdRng) -> Tuple {
+        let port = |rng: &mut StdRng| {
+            [80, 5005, 40000, 45000, 3, 65535][rng.gen_range(0..6)]
+        };
+        let protocol = [None, Some(ipv4::PROTOCOL_TCP),
+                        Some(ipv4::PROTOCOL_UDP), Some(1)][rng.gen_range(0..4)];
+        let ports = match protocol {
+            Some(ipv4::PROTOCOL_TCP) | Some(ipv4::PROTOCOL_UDP) =>
+                Some((port(rng), port(rng))),
+            _ => None
+        };
+        let icmp = match protocol {
+            Some(1) => Some((rng.gen_range(0..10), rng.gen_range(0..2))),
+            _ => None
+        };
+        let tcp_flags = match protocol {
+            Some(ipv4::PROTOCOL_TCP) =>
+                Some([0x02, 0x12, 0x10, 0x11][rng.gen_range(0..4)]),
+            _ => None
+        };
+        Tuple {
+            src: random_address(rng),
+            dst: random_address(rng),
+            protocol,
+            ports,
+            dscp: [0, 46, 34][rng.gen_range(0..3)],
+            ecn: rng.gen_range(0..4),
+            icmp,
+            tcp_flags
+        }
+    }
+
     #[test]
     fn flowtop() {
         let map = open_flowtop_map("flowtop.map");
@@ -376,6 +1487,80 @@ mod selftest {
         let _ = fs::remove_file("flowtop.map");
     }
 
+    #[test]
+    fn dscp_split() {
+        let udp = |tos: u8| vec![
+            /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+            /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+            /*Ethertype*/ 0x08, 0x00,
+            /*IPv4 version, IHL*/ 0x45, /*TOS*/ tos,
+            /*Total length*/ 0x00, 0x1c, /*ID*/ 0x00, 0x00,
+            /*Flags, frag. offset*/ 0x40, 0x00, /*TTL*/ 0x40,
+            /*Protocol*/ 0x11, /*Checksum*/ 0x00, 0x00,
+            /*Src addr*/ 10, 0, 0, 1,
+            /*Dst addr*/ 10, 0, 0, 2,
+            /*Src port*/ 0x13, 0x88, /*Dst port*/ 0x13, 0x88];
+        let packets = vec![
+            udp(46 << 2), // EF
+            udp(46 << 2 | 0b11), // EF (CE)
+            udp(34 << 2), // AF41
+            udp(36 << 2), // AF42
+            udp(0),
+            udp6_packet(46 << 2, 1), // EF
+            // IPv6 (truncated)
+            vec![
+                /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+                /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+                /*Ethertype*/ 0x86, 0xdd]
+        ];
+
+        engine::configure(&config::new());
+        let mut c = config::new();
+        config::app(&mut c, "source", &PacketGen {packets});
+        config::app(&mut c, "split", &DscpSplit {classes: vec![
+            DscpClass {label: "audio".to_string(), dscp: vec![46]},
+            DscpClass {label: "video".to_string(), dscp: vec![34, 36]},
+            DscpClass {label: "shadowed".to_string(), dscp: vec![36]}
+        ]});
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> split.input");
+        for output in ["audio", "video", "shadowed", "default"] {
+            config::link(&mut c, &format!("split.{} -> sink.{}", output, output));
+        }
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            done: Some(Box::new(|| true)), // single breath
+            report_links: true,
+            ..Default::default()
+        }));
+
+        let txpackets = |output: &str| engine::state().link_table
+            .get(&format!("split.{} -> sink.{}", output, output)).unwrap()
+            .borrow().txpackets;
+        assert!(txpackets("audio") == 3);
+        assert!(txpackets("video") == 2);
+        assert!(txpackets("shadowed") == 0);
+        assert!(txpackets("default") == 2);
+    }
+
+    #[test]
+    fn flow_hash_ipv6() {
+        let hash = |data: Vec<u8>| {
+            let mut p = packet::allocate();
+            lib::copy(&mut p.data, &data, data.len());
+            p.length = data.len() as u16;
+            let hash = flow_hash(&mut p);
+            packet::free(p);
+            hash
+        };
+        // IPv6 packets hash by their 5-tuple (not by traffic class)
+        assert!(hash(udp6_packet(0, 1)) != 0);
+        assert!(hash(udp6_packet(0, 1)) == hash(udp6_packet(46 << 2, 1)));
+        assert!(hash(udp6_packet(0, 1)) != hash(udp6_packet(0, 2)));
+        // Truncated IPv6 packets hash to zero
+        assert!(hash(udp6_packet(0, 1)[..40].to_vec()) == 0);
+    }
+
     #[test]
     fn top() {
         let packets = vec![
@@ -417,7 +1602,8 @@ mod selftest {
         config::app(&mut c, "source", &PacketGen {packets: packets});
         config::app(&mut c, "top", &Top {
             path: "flowtop.map".to_string(),
-            dir: Dir::Src
+            dir: Dir::Src,
+            framing: HashMap::new()
         });
         config::app(&mut c, "sink", &basic_apps::Sink {});
         config::link(&mut c, "source.output -> top.input");
@@ -452,6 +1638,23 @@ mod selftest {
         let _ = fs::remove_file("flowtop.map");
     }
 
+    // UDP [fc00::1]:<src_port> -> [fc00::2]:5000
+    fn udp6_packet(traffic_class: u8, src_port: u8) -> Vec<u8> {
+        let mut data = vec![
+            /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+            /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+            /*Ethertype*/ 0x86, 0xdd,
+            /*Version, traffic class, flow label*/
+            0x60 | traffic_class >> 4, traffic_class << 4, 0x00, 0x00,
+            /*Payload length*/ 0x00, 0x08, /*Next header*/ 0x11,
+            /*Hop limit*/ 0x40];
+        data.extend(ipv6::pton("fc00::1"));
+        data.extend(ipv6::pton("fc00::2"));
+        data.extend([/*Src port*/ 0x00, src_port, /*Dst port*/ 0x13, 0x88,
+                     /*Length*/ 0x00, 0x08, /*Checksum*/ 0x00, 0x00]);
+        data
+    }
+
     #[derive(Clone,Debug)]
     pub struct PacketGen { packets: Vec<Vec<u8>> }
     impl engine::AppConfig for PacketGen {
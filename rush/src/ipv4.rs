@@ -21,6 +21,10 @@ use std::str::FromStr;
 //   Header<IPv4>.set_version(u16) - set 4-bit version (should always be 4)
 //   Header<IPv4>.ihl() -> u16 - get 4-bit IHL (5 unless there are options)
 //   Header<IPv4>.set_ihl(u16) - set 4-bit IHL (5 unless there are options)
+//   Header<IPv4>.dscp() -> u8 - get 6-bit Differentiated Services Codepoint
+//   Header<IPv4>.set_dscp(u8) - set 6-bit Differentiated Services Codepoint
+//   Header<IPv4>.ecn() -> u8 - get 2-bit ECN codepoint
+//   Header<IPv4>.set_ecn(u8) - set 2-bit ECN codepoint
 //   Header<IPv4>.total_size() -> u16 - get IPv4 frame size including header
 //   Header<IPv4>.set_total_size(u16) - set IPv4 frame size including header
 //   Header<IPv4>.id() -> u16 - get flow identifier
@@ -41,8 +45,10 @@ use std::str::FromStr;
 //   Header<IPv4>.dst() -> Address - get destination address
 //   Header<IPv4>.set_dst(Address) - set destination address
 //   Header<IPv4>.swap() - swap source and destination addresses
+//   PROTOCOL_ICMP - const u8 identifier for protocol ICMP
 //   PROTOCOL_TCP - const u8 identifier for protocol TCP
 //   PROTOCOL_UDP - const u8 identifier for protocol UDP
+//   ECN_NOT_ECT, ECN_ECT1, ECN_ECT0, ECN_CE - const u8 ECN codepoints
 
 pub type Address = u32;
 
@@ -108,6 +114,26 @@ impl header::Header<IPv4> {
         h.ihl_v_tos |= lib::htons((ihl & 0xf) << 8);
     }
 
+    pub fn dscp(&self) -> u8 {
+        ((lib::ntohs(self.header_ref().ihl_v_tos) >> 2) & 0x3f) as u8
+    }
+
+    pub fn set_dscp(&mut self, dscp: u8) {
+        let h = self.header_mut();
+        h.ihl_v_tos &= lib::htons(0xff03);
+        h.ihl_v_tos |= lib::htons(((dscp & 0x3f) as u16) << 2);
+    }
+
+    pub fn ecn(&self) -> u8 {
+        (lib::ntohs(self.header_ref().ihl_v_tos) & 0x3) as u8
+    }
+
+    pub fn set_ecn(&mut self, ecn: u8) {
+        let h = self.header_mut();
+        h.ihl_v_tos &= lib::htons(0xfffc);
+        h.ihl_v_tos |= lib::htons((ecn & 0x3) as u16);
+    }
+
     pub fn total_length(&self) -> u16 {
         lib::ntohs(self.header_ref().total_length)
     }
@@ -207,9 +233,15 @@ impl header::Header<IPv4> {
 
 }
 
+pub const PROTOCOL_ICMP: u8 = 1;
 pub const PROTOCOL_TCP: u8 = 6;
 pub const PROTOCOL_UDP: u8 = 17;
 
+pub const ECN_NOT_ECT: u8 = 0b00;
+pub const ECN_ECT1: u8 = 0b01;
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
+
 #[cfg(test)]
 mod selftest {
     use super::*;
@@ -239,6 +271,12 @@ mod selftest {
                  ip.version(),
                  ip.ihl());
         println!("total_length={}", ip.total_length());
+        ip.set_dscp(46); // Expedited Forwarding
+        ip.set_ecn(ECN_ECT0);
+        assert!(ip.dscp() == 46 && ip.ecn() == ECN_ECT0);
+        assert!(ip.version() == 4 && ip.ihl() == 5);
+        ip.set_ecn(ECN_CE);
+        assert!(ip.dscp() == 46 && ip.ecn() == ECN_CE);
         let mut ip = IPv4::new();
         ip.set_total_length(60);
         ip.set_id(23757);
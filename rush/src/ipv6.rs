@@ -0,0 +1,290 @@
+use super::lib;
+use super::header;
+
+use std::mem;
+use std::net;
+use std::str::FromStr;
+
+// IPv6
+//
+// This module contains an IPv6 header definition, a type for IPv6 addresses,
+// and some related utilities.
+//
+//   Address - sixteen bytes (in network byte order)
+//   ntop(&Address) -> String - return string representation of IPv6 address
+//   pton(&str) -> Address - parse IPv6 address from string representation
+//   IPv6 - struct for IPv6 headers
+//   IPv6::new() -> Header<IPv6> - new header with defaults (version, ...)
+//   Header<IPv6>.version() -> u32 - get 4-bit version (always 6)
+//   Header<IPv6>.set_version(u32) - set 4-bit version (should always be 6)
+//   Header<IPv6>.dscp() -> u8 - get 6-bit Differentiated Services Codepoint
+//   Header<IPv6>.set_dscp(u8) - set 6-bit Differentiated Services Codepoint
+//   Header<IPv6>.ecn() -> u8 - get 2-bit ECN codepoint
+//   Header<IPv6>.set_ecn(u8) - set 2-bit ECN codepoint
+//   Header<IPv6>.flow_label() -> u32 - get 20-bit flow label
+//   Header<IPv6>.set_flow_label(u32) - set 20-bit flow label
+//   Header<IPv6>.payload_length() -> u16 - get length of payload (including
+//                                          extension headers)
+//   Header<IPv6>.set_payload_length(u16) - set length of payload
+//   Header<IPv6>.next_header() -> u8 - get protocol of next header
+//   Header<IPv6>.set_next_header(u8) - set protocol of next header
+//   Header<IPv6>.hop_limit() -> u8 - get hop limit
+//   Header<IPv6>.set_hop_limit(u8) - set hop limit
+//   Header<IPv6>.src() -> Address - get source address
+//   Header<IPv6>.set_src(&Address) - set source address
+//   Header<IPv6>.dst() -> Address - get destination address
+//   Header<IPv6>.set_dst(&Address) - set destination address
+//   Header<IPv6>.swap() - swap source and destination addresses
+//   upper_layer(&[u8]) -> Option<(u8, usize)> - skip extension headers and
+//                                               locate upper-layer header
+//   PROTOCOL_HOPOPTS, PROTOCOL_ROUTING, PROTOCOL_FRAGMENT, PROTOCOL_AH,
+//   PROTOCOL_DSTOPTS - const u8 identifiers for extension headers
+//   PROTOCOL_ESP - const u8 identifier for Encapsulating Security Payload
+//   PROTOCOL_ICMPV6 - const u8 identifier for protocol ICMPv6
+//   PROTOCOL_NONE - const u8 identifier for “no next header”
+
+pub type Address = [u8; 16];
+
+pub fn ntop(address: &Address) -> String {
+    net::Ipv6Addr::from(*address).to_string()
+}
+
+pub fn pton(string: &str) -> Address {
+    net::Ipv6Addr::from_str(string).unwrap().octets()
+}
+
+#[repr(C, packed)]
+#[derive(Default)]
+pub struct IPv6 {
+    v_tc_fl: u32, // version:4, traffic class(dscp:6 + ecn:2), flow label:20
+    payload_length: u16,
+    next_header: u8,
+    hop_limit: u8,
+    src: Address,
+    dst: Address
+}
+
+impl IPv6 {
+    pub fn new() -> header::Header<IPv6> {
+        let mut h = header::new::<IPv6>();
+        h.set_version(6);
+        h
+    }
+}
+
+impl header::Header<IPv6> {
+
+    pub fn version(&self) -> u32 {
+        (lib::ntohl(self.header_ref().v_tc_fl) >> 28) & 0xf
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        let h = self.header_mut();
+        h.v_tc_fl &= lib::htonl(0x0fffffff);
+        h.v_tc_fl |= lib::htonl((version & 0xf) << 28);
+    }
+
+    pub fn dscp(&self) -> u8 {
+        ((lib::ntohl(self.header_ref().v_tc_fl) >> 22) & 0x3f) as u8
+    }
+
+    pub fn set_dscp(&mut self, dscp: u8) {
+        let h = self.header_mut();
+        h.v_tc_fl &= lib::htonl(0xf03fffff);
+        h.v_tc_fl |= lib::htonl(((dscp & 0x3f) as u32) << 22);
+    }
+
+    pub fn ecn(&self) -> u8 {
+        ((lib::ntohl(self.header_ref().v_tc_fl) >> 20) & 0x3) as u8
+    }
+
+    pub fn set_ecn(&mut self, ecn: u8) {
+        let h = self.header_mut();
+        h.v_tc_fl &= lib::htonl(0xffcfffff);
+        h.v_tc_fl |= lib::htonl(((ecn & 0x3) as u32) << 20);
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        lib::ntohl(self.header_ref().v_tc_fl) & 0xfffff
+    }
+
+    pub fn set_flow_label(&mut self, flow_label: u32) {
+        let h = self.header_mut();
+        h.v_tc_fl &= lib::htonl(0xfff00000);
+        h.v_tc_fl |= lib::htonl(flow_label & 0xfffff);
+    }
+
+    pub fn payload_length(&self) -> u16 {
+        lib::ntohs(self.header_ref().payload_length)
+    }
+
+    pub fn set_payload_length(&mut self, payload_length: u16) {
+        self.header_mut().payload_length = lib::htons(payload_length);
+    }
+
+    pub fn next_header(&self) -> u8 {
+        self.header_ref().next_header
+    }
+
+    pub fn set_next_header(&mut self, next_header: u8) {
+        self.header_mut().next_header = next_header;
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.header_ref().hop_limit
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.header_mut().hop_limit = hop_limit;
+    }
+
+    pub fn src(&self) -> Address {
+        self.header_ref().src
+    }
+
+    pub fn set_src(&mut self, address: &Address) {
+        self.header_mut().src = *address;
+    }
+
+    pub fn dst(&self) -> Address {
+        self.header_ref().dst
+    }
+
+    pub fn set_dst(&mut self, address: &Address) {
+        self.header_mut().dst = *address;
+    }
+
+    pub fn swap(&mut self) {
+        let h = self.header_mut();
+        mem::swap(&mut h.src, &mut h.dst);
+    }
+
+}
+
+// Follow the chain of extension headers of the IPv6 packet in mem (which
+// starts with the IPv6 header and ends with the packet) and return the
+// upper-layer protocol (e.g., PROTOCOL_TCP) and the offset of its header in
+// mem. Returns None if the chain is truncated, and for fragments other than
+// the first one (which carry no upper-layer header).
+//
+// NB: the payload of ESP is opaque, so PROTOCOL_ESP is returned as the
+// upper-layer protocol.
+pub fn upper_layer(mem: &[u8]) -> Option<(u8, usize)> {
+    let mut protocol = mem.get(6).copied()?; // Next Header
+    let mut offset = header::size_of::<IPv6>();
+    loop {
+        let length = match protocol {
+            PROTOCOL_HOPOPTS | PROTOCOL_ROUTING | PROTOCOL_DSTOPTS =>
+                (*mem.get(offset + 1)? as usize + 1) * 8,
+            PROTOCOL_FRAGMENT => {
+                let frag_off = u16::from_be_bytes(
+                    [*mem.get(offset + 2)?, *mem.get(offset + 3)?]) >> 3;
+                if frag_off > 0 { return None }
+                8
+            }
+            PROTOCOL_AH =>
+                (*mem.get(offset + 1)? as usize + 2) * 4,
+            _ => break
+        };
+        protocol = *mem.get(offset)?;
+        offset += length;
+    }
+    if offset > mem.len() { return None }
+    Some((protocol, offset))
+}
+
+pub const PROTOCOL_HOPOPTS: u8 = 0;
+pub const PROTOCOL_ROUTING: u8 = 43;
+pub const PROTOCOL_FRAGMENT: u8 = 44;
+pub const PROTOCOL_ESP: u8 = 50;
+pub const PROTOCOL_AH: u8 = 51;
+pub const PROTOCOL_ICMPV6: u8 = 58;
+pub const PROTOCOL_NONE: u8 = 59;
+pub const PROTOCOL_DSTOPTS: u8 = 60;
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::ipv4;
+
+    #[test]
+    fn ipv6() {
+        let mut ip = IPv6::new();
+        ip.set_src(&pton("2001:db8::1"));
+        ip.set_next_header(ipv4::PROTOCOL_UDP);
+        let mut mem: [u8; 40] = [1; 40];
+        let mut ip2 = header::from_mem::<IPv6>(&mut mem);
+        ip2.set_dst(&pton("fe80::1:2:3"));
+        ip2.set_next_header(ipv4::PROTOCOL_TCP);
+        ip.set_dst(&ip2.dst());
+        ip.swap();
+        println!("ip  dst={} src={} ({})",
+                 ntop(&ip.dst()),
+                 ntop(&ip.src()),
+                 ip.next_header());
+        println!("ip2 dst={} src={} ({})",
+                 ntop(&ip2.dst()),
+                 ntop(&ip2.src()),
+                 ip2.next_header());
+        assert!(ntop(&ip.src()) == "fe80::1:2:3");
+        assert!(ntop(&ip.dst()) == "2001:db8::1");
+        println!("size_of::<IPv6> {}", header::size_of::<IPv6>());
+        assert!(header::size_of::<IPv6>() == 40);
+        ip.set_flow_label(0xabcde);
+        ip.set_dscp(46); // Expedited Forwarding
+        ip.set_ecn(ipv4::ECN_ECT0);
+        assert!(ip.dscp() == 46 && ip.ecn() == ipv4::ECN_ECT0);
+        assert!(ip.version() == 6 && ip.flow_label() == 0xabcde);
+        ip.set_ecn(ipv4::ECN_CE);
+        assert!(ip.dscp() == 46 && ip.ecn() == ipv4::ECN_CE);
+        println!("v_tc_fl={:08x}", lib::ntohl(ip.header_ref().v_tc_fl));
+        ip.set_payload_length(8);
+        ip.set_hop_limit(64);
+        assert!(ip.payload_length() == 8 && ip.hop_limit() == 64);
+    }
+
+    #[test]
+    fn extension_headers() {
+        let packet = |chain: &[u8]| {
+            let mut ip = IPv6::new();
+            ip.set_next_header(chain[0]);
+            let mut mem = ip.header_slice().to_vec();
+            mem.extend_from_slice(&chain[1..]);
+            mem
+        };
+        // No extension headers
+        let mem = packet(&[ipv4::PROTOCOL_UDP, /*UDP*/ 0, 1, 0, 2]);
+        assert!(upper_layer(&mem) == Some((ipv4::PROTOCOL_UDP, 40)));
+        // Hop-by-Hop Options (16 bytes), Fragment (first), Routing (8 bytes)
+        let mem = packet(&[
+            PROTOCOL_HOPOPTS,
+            /*Next header*/ PROTOCOL_FRAGMENT, /*Length*/ 1,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            /*Next header*/ PROTOCOL_ROUTING, 0, /*Offset, M*/ 0x00, 0x01,
+            /*ID*/ 0, 0, 0, 42,
+            /*Next header*/ ipv4::PROTOCOL_TCP, /*Length*/ 0,
+            0, 0, 0, 0, 0, 0,
+            /*TCP*/ 0, 1, 0, 2]);
+        assert!(upper_layer(&mem) == Some((ipv4::PROTOCOL_TCP, 40+16+8+8)));
+        // Authentication Header (16 bytes), Destination Options (8 bytes)
+        let mem = packet(&[
+            PROTOCOL_AH,
+            /*Next header*/ PROTOCOL_DSTOPTS, /*Length*/ 2,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            /*Next header*/ PROTOCOL_NONE, /*Length*/ 0,
+            0, 0, 0, 0, 0, 0]);
+        assert!(upper_layer(&mem) == Some((PROTOCOL_NONE, 40+16+8)));
+        // Fragment (not first)
+        let mem = packet(&[
+            PROTOCOL_FRAGMENT,
+            /*Next header*/ ipv4::PROTOCOL_UDP, 0, /*Offset, M*/ 0x05, 0x00,
+            /*ID*/ 0, 0, 0, 42,
+            /*UDP payload*/ 0, 1, 0, 2]);
+        assert!(upper_layer(&mem).is_none());
+        // Truncated
+        let mem = packet(&[PROTOCOL_HOPOPTS, ipv4::PROTOCOL_UDP, 1, 0, 0]);
+        assert!(upper_layer(&mem).is_none());
+        assert!(upper_layer(&mem[..20]).is_none());
+    }
+
+}
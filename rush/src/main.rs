@@ -11,6 +11,7 @@ mod basic_apps;
 mod header;
 mod ethernet;
 mod ipv4;
+mod ipv6;
 mod tcp;
 mod udp;
 mod checksum;
@@ -18,6 +19,7 @@ mod rawsocket_app;
 mod qos;
 mod offload;
 mod flow;
+mod filter;
 
 mod synthetic_network;
 
@@ -4,19 +4,23 @@ use super::lib;
 
 use std::cmp;
 use std::mem;
+use std::time::Instant;
 
 // PACKET STRUCT AND FREELIST
 //
 // This module defines a struct to represent packets of network data, and
 // implements a global freelist from which packets can be allocated.
 //
-//   Packet - packet structure with length and data fields
+//   Packet - packet structure with length, timestamp, and data fields
 //   PAYLOAD_SIZE - size of packet’s data field
 //   preallocate(usize) - preallocate a minimum amount of packets
 //   allocate() -> Box<Packet> - take a packet off the freelist for use
 //   free(Box<Packet>) - return a packet to the freelist
 //   clone(Box<Packet>) -> Box<Packet> - return a copy of packet
 //   bitlength(Box<Packet>) -> usize - return bit length of packet on-the-wire
+//   Framing - link-layer framing overhead of packets on-the-wire
+//   ETHERNET, PPPOE, ADSL, WIFI, CELLULAR - framing of common link layers
+//   framed_bitlength(Packet, Framing) -> u64 - bit length with given framing
 
 // The maximum amount of payload in any given packet.
 // NB: for synthetic_network we cranked this way up to fit the maximum
@@ -27,8 +31,14 @@ pub const PAYLOAD_SIZE: usize = 65535;
 // Packet of network data, with associated metadata.
 // XXX - should be #[repr(C, packed)], however that would require unsafe{} to
 // access members. Is the memory layout in repr(rust) equivalent?
+//
+// The timestamp records when the packet became ready to be processed: when
+// it was received (i.e., the kernel receive timestamp), or when an app that
+// held the packet back released it. Apps that delay packets use it to
+// compute per-packet delays independent of when the engine breathes.
 pub struct Packet {
     pub length: u16, // data payload length
+    pub timestamp: Option<Instant>, // None if unknown
     pub data: [u8; PAYLOAD_SIZE]
 }
 
@@ -44,10 +54,11 @@ fn new_packet() -> Box<Packet> {
                                  mem::align_of::<Packet>());
     let mut p = unsafe { Box::from_raw(base as *mut Packet) };
     p.length = 0;
+    p.timestamp = None;
     p
 }
 fn new_packet_noroot() -> Box<Packet> {
-    Box::new(Packet { length: 0, data: [0; PAYLOAD_SIZE] })
+    Box::new(Packet { length: 0, timestamp: None, data: [0; PAYLOAD_SIZE] })
 }
 
 // Maximum number of packets on the freelist.
@@ -124,6 +135,7 @@ pub fn allocate() -> Box<Packet> {
 fn free_internal(mut p: Box<Packet>) {
     if unsafe { FL.nfree } == MAX_PACKETS { panic!("Packet freelist overflow"); }
     p.length = 0;
+    p.timestamp = None;
     unsafe { FL.list[FL.nfree] = &mut *p; } mem::forget(p);
     unsafe { FL.nfree += 1; }
 }
@@ -139,6 +151,7 @@ pub fn clone (p: &Box<Packet>) -> Box<Packet> {
     let mut copy = allocate();
     lib::copy(&mut copy.data, &p.data, p.length as usize);
     copy.length = p.length;
+    copy.timestamp = p.timestamp;
     copy
 }
 
@@ -147,7 +160,49 @@ pub fn bitlength(p: &Box<Packet>) -> u64 {
     // Account for minimum data size and overhead of Ethernet preamble, CRC,
     // and inter-packet gap
     // https://netoptimizer.blogspot.com/2014/05/the-calculations-10gbits-wirespeed.html
-    (12 + 8 + cmp::max(p.length as u64, 60) + 4) * 8
+    framed_bitlength(p, &ETHERNET)
+}
+
+// Framing overhead of a link layer (like tc-stab(8)): packets shorter than
+// `min_length' are padded to it, and `overhead' bytes are added to every
+// packet (or removed, if negative). If `atm' is true the resulting frame is
+// transmitted in ATM cells that carry 48 bytes each, with 5 bytes of cell
+// header (the last cell is padded).
+//
+// NB: packet lengths include the Ethernet header (but not its CRC), so
+// overheads are relative to the Ethernet frame.
+#[derive(Clone,Copy,Debug)]
+pub struct Framing {
+    pub overhead: i64, // bytes added per packet
+    pub min_length: u64, // minimum packet length (bytes, excluding overhead)
+    pub atm: bool // transmitted in ATM cells
+}
+// Ethernet: inter-packet gap (12), preamble (8), and CRC (4)
+pub const ETHERNET: Framing =
+    Framing { overhead: 12 + 8 + 4, min_length: 60, atm: false };
+// PPPoE over Ethernet (e.g., VDSL2 or fiber): Ethernet, PPPoE (6), and PPP (2)
+pub const PPPOE: Framing =
+    Framing { overhead: 12 + 8 + 4 + 6 + 2, min_length: 60, atm: false };
+// PPPoE over ATM (ADSL, RFC 2684 bridged LLC/SNAP): LLC/SNAP (10), PPPoE (6),
+// PPP (2), and AAL5 trailer (8)
+pub const ADSL: Framing =
+    Framing { overhead: 10 + 6 + 2 + 8, min_length: 0, atm: true };
+// 802.11 QoS data frames: MAC header (26), LLC/SNAP (8), and FCS (4) in place
+// of the Ethernet header (14)
+pub const WIFI: Framing =
+    Framing { overhead: 26 + 8 + 4 - 14, min_length: 0, atm: false };
+// LTE/5G user plane: PDCP (2), RLC (2), and MAC (2) headers in place of the
+// Ethernet header (14)
+pub const CELLULAR: Framing =
+    Framing { overhead: 2 + 2 + 2 - 14, min_length: 0, atm: false };
+
+pub fn framed_bitlength(p: &Packet, framing: &Framing) -> u64 {
+    let length = cmp::max(p.length as u64, framing.min_length) as i64;
+    let length = cmp::max(length + framing.overhead, 0) as u64;
+    match framing.atm {
+        true => length.div_ceil(48) * 53 * 8,
+        false => length * 8
+    }
 }
 
 // pub fn debug() {
@@ -177,4 +232,20 @@ mod selftest {
         //p.length = 2; // Would cause compile error
     }
 
+    #[test]
+    fn framing() {
+        let mut p = allocate();
+        p.length = 1514;
+        assert_eq!(bitlength(&p), (1514 + 24) * 8);
+        assert_eq!(framed_bitlength(&p, &PPPOE), (1514 + 32) * 8);
+        // 1514 + 26 bytes fill 33 ATM cells (of 53 bytes)
+        assert_eq!(framed_bitlength(&p, &ADSL), 33 * 53 * 8);
+        assert_eq!(framed_bitlength(&p, &CELLULAR), (1514 - 8) * 8);
+        // Small packets are padded
+        p.length = 42;
+        assert_eq!(bitlength(&p), (60 + 24) * 8);
+        assert_eq!(framed_bitlength(&p, &ADSL), 2 * 53 * 8);
+        free(p);
+    }
+
 }
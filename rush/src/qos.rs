@@ -1,45 +1,778 @@
 use super::packet;
 use super::link;
 use super::engine;
+use super::flow;
+use super::header;
+use super::ethernet;
+use super::ethernet::Ethernet;
+use super::ipv4;
+use super::ipv4::IPv4;
+use super::ipv6::IPv6;
+use super::tcp::TCP;
+use super::udp::UDP;
 
 // QoS: quality of service regulating apps
 
 use std::time::{Duration, Instant};
 use std::collections::VecDeque;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
-use rand::Rng;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+// Stochastic apps draw random numbers from their own generator. If a seed is
+// given the generator is seeded with it, and the app will make the same
+// decisions for the same sequence of input packets on every run. Otherwise,
+// the generator is seeded from system entropy.
+fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    }
+}
+
+
+
+// Schedules: time-varying app parameters
+//
+// A schedule is a list of keyframes (time in milliseconds since the app first
+// processed packets, value) in order of time. Between keyframes the value either stays
+// at the value of the previous keyframe (Step), or is interpolated linearly
+// (Linear). Before the first keyframe the value is that of the first
+// keyframe, and after the last keyframe it is that of the last keyframe.
+//
+// If `period' is set, the schedule repeats every `period' milliseconds. In
+// that case the value wraps around from the last keyframe to the first
+// keyframe of the next period.
+//
+// Apps that accept a schedule evaluate it continuously (i.e., every breath),
+// and the scheduled value overrides the app’s respective static parameter.
+
+#[derive(Clone,Debug)]
+pub struct Schedule {
+    pub keyframes: Vec<(u64, f64)>, // (ms, value)
+    pub interpolation: Interpolation,
+    pub period: Option<u64> // ms
+}
+#[derive(Clone,Copy,Debug)]
+pub enum Interpolation { Step, Linear }
+impl Schedule {
+    // Value of schedule `ms' milliseconds after start
+    pub fn value(&self, ms: f64) -> f64 {
+        let keyframes = &self.keyframes;
+        assert!(!keyframes.is_empty(), "Schedule has no keyframes");
+        let (first, last) = (keyframes[0], keyframes[keyframes.len()-1]);
+        let (t, prev, next) = match self.period {
+            Some(period) if period > 0 => {
+                let period = period as f64;
+                let t = ms % period;
+                // Keyframes surrounding t (wrapping around)
+                let i = keyframes.partition_point(|&(time, _)| time as f64 <= t);
+                let prev = if i > 0 {
+                    (keyframes[i-1].0 as f64, keyframes[i-1].1)
+                } else {
+                    (last.0 as f64 - period, last.1)
+                };
+                let next = if i < keyframes.len() {
+                    (keyframes[i].0 as f64, keyframes[i].1)
+                } else {
+                    (first.0 as f64 + period, first.1)
+                };
+                (t, prev, next)
+            },
+            _ => {
+                let i = keyframes.partition_point(|&(time, _)| time as f64 <= ms);
+                if i == 0 { return first.1 }
+                if i == keyframes.len() { return last.1 }
+                let (prev, next) = (keyframes[i-1], keyframes[i]);
+                (ms, (prev.0 as f64, prev.1), (next.0 as f64, next.1))
+            }
+        };
+        match self.interpolation {
+            Interpolation::Step => prev.1,
+            Interpolation::Linear if next.0 > prev.0 =>
+                prev.1 + (next.1 - prev.1) * (t - prev.0) / (next.0 - prev.0),
+            Interpolation::Linear => next.1
+        }
+    }
+
+    // Value of schedule at engine::now(), the schedule starts when it is first
+    // evaluated (i.e., `start' is set on first use)
+    pub fn value_since(&self, start: &Cell<Option<Instant>>) -> f64 {
+        let now = engine::now();
+        if start.get().is_none() { start.set(Some(now)) }
+        let elapsed = now.saturating_duration_since(start.get().unwrap());
+        self.value(elapsed.as_secs_f64() * 1000.0)
+    }
+}
+
 
 
 // Loss app: simulate probabilistic packet loss
+//
+// Besides uniform (independent) loss, LossApp implements the bursty
+// Gilbert-Elliott and 4-state Markov loss models known from netem(8), see
+//   “Definition of a general and intuitive loss model for packet networks and
+//    its implementation in the Netem module in the Linux kernel”
+//   (S. Salsano, F. Ludovici, A. Ordine)
 
 #[derive(Clone,Debug)]
 pub struct Loss {
+    pub model: LossModel,
+    pub seed: Option<u64>, // random seed (see rng())
+    pub schedule: Option<Schedule> // schedule of uniform loss ratio (model
+                                   // must be Uniform)
+}
+#[derive(Clone,Debug)]
+pub enum LossModel {
     // ratio 0..1 of dropped packets (0.0 → 0%, 0.5 → 50%, 1.0 → 100%)
-    pub ratio: f64
+    Uniform(f64),
+    // Two-state (good/bad) Markov chain:
+    //   p: probability of transitioning from good to bad state
+    //   r: probability of transitioning from bad to good state
+    //   loss_good: loss probability while in good state (1-k)
+    //   loss_bad: loss probability while in bad state (1-h)
+    GilbertElliott { p: f64, r: f64, loss_good: f64, loss_bad: f64 },
+    // Four-state Markov chain, states are:
+    //   1: packet received (gap period)
+    //   2: packet received (burst period)
+    //   3: packet lost (burst period)
+    //   4: isolated packet loss (gap period)
+    // pXY is the probability of transitioning from state X to state Y.
+    FourState { p13: f64, p31: f64, p32: f64, p23: f64, p14: f64 }
 }
 impl engine::AppConfig for Loss {
     fn new(&self) -> Box<dyn engine::App> {
-        assert!(self.ratio >= 0.0 && self.ratio <= 1.0,
-                "Ratio must be within 0.0 and 1.0");
-        Box::new(LossApp {ratio: self.ratio})
+        let probabilities = match self.model {
+            LossModel::Uniform(ratio) => vec![ratio],
+            LossModel::GilbertElliott { p, r, loss_good, loss_bad } =>
+                vec![p, r, loss_good, loss_bad],
+            LossModel::FourState { p13, p31, p32, p23, p14 } =>
+                vec![p13, p31, p32, p23, p14]
+        };
+        for ratio in probabilities {
+            assert!((0.0..=1.0).contains(&ratio),
+                    "Ratio must be within 0.0 and 1.0");
+        }
+        assert!(self.schedule.is_none()
+                || matches!(self.model, LossModel::Uniform(_)),
+                "Schedule requires uniform loss model");
+        Box::new(LossApp {
+            model: self.model.clone(),
+            state: Cell::new(1),
+            rng: RefCell::new(rng(self.seed)),
+            schedule: self.schedule.clone(),
+            start: Cell::new(None)
+        })
     }
 }
-pub struct LossApp { ratio: f64 }
+pub struct LossApp {
+    model: LossModel,
+    state: Cell<u8>, // Markov chain state (see loss_step)
+    rng: RefCell<StdRng>,
+    schedule: Option<Schedule>,
+    start: Cell<Option<Instant>>
+}
 impl engine::App for LossApp {
     fn has_push(&self) -> bool { true }
     fn push(&self, app: &engine::AppState) {
         let mut input = app.input.get("input").unwrap().borrow_mut();
         let mut output = app.output.get("output").unwrap().borrow_mut();
-        let mut rng = rand::thread_rng();
+        let mut rng = self.rng.borrow_mut();
+        let mut state = self.state.get();
+        let model = match &self.schedule {
+            Some(schedule) => LossModel::Uniform(
+                schedule.value_since(&self.start).clamp(0.0, 1.0)
+            ),
+            None => self.model.clone()
+        };
         while !link::empty(&input) {
             let p = link::receive(&mut input);
-            if rng.gen::<f64>() >= self.ratio {
+            if !loss_step(&model, &mut state, &mut *rng) {
                 link::transmit(&mut output, p);
             } else {
                 packet::free(p);
             }
         }
+        self.state.set(state);
+    }
+}
+
+// Advance loss model by one packet, return true if the packet is lost.
+//
+// For GilbertElliott, state 1 is the good state and state 3 is the bad
+// state. Loss is decided according to the current state before
+// transitioning (like netem does). For FourState, the state is advanced first,
+// and the packet is lost if the chain entered state 3 or 4.
+fn loss_step<R: Rng>(model: &LossModel, state: &mut u8, rng: &mut R) -> bool {
+    match *model {
+        LossModel::Uniform(ratio) => rng.gen::<f64>() < ratio,
+        LossModel::GilbertElliott { p, r, loss_good, loss_bad } => {
+            let (loss, transition, next) = match *state {
+                1 => (loss_good, p, 3),
+                _ => (loss_bad, r, 1)
+            };
+            let lost = rng.gen::<f64>() < loss;
+            if rng.gen::<f64>() < transition { *state = next; }
+            lost
+        },
+        LossModel::FourState { p13, p31, p32, p23, p14 } => {
+            let rnd = rng.gen::<f64>();
+            *state = match *state {
+                1 => if rnd < p13 { 3 } else if rnd < p13 + p14 { 4 } else { 1 },
+                2 => if rnd < p23 { 3 } else { 2 },
+                3 => if rnd < p31 { 1 } else if rnd < p31 + p32 { 2 } else { 3 },
+                _ => 1
+            };
+            *state == 3 || *state == 4
+        }
+    }
+}
+
+
+// Outage app: simulate complete loss of connectivity
+//
+// During an outage all packets are dropped, or, if `hold' is set, held in a
+// FIFO of `hold' bytes (tail-drop) and forwarded once connectivity is
+// restored. Outages follow a pattern (times in milliseconds since the app
+// first processed packets):
+//
+//   Once: a single outage of `duration' starting at `start'
+//   Periodic: outages of `duration' every `period', the first starting at
+//     `start'
+//   Random: alternating periods of connectivity and outage, their durations
+//     are exponentially distributed with means `mean_up' and `mean_down'
+//
+// The number of packets affected by each outage is reported.
+
+#[derive(Clone,Debug)]
+pub struct Outage {
+    pub pattern: OutagePattern,
+    pub hold: Option<usize>, // hold queue size in bytes (None → drop)
+    pub seed: Option<u64> // random seed (see rng())
+}
+#[derive(Clone,Debug)]
+pub enum OutagePattern {
+    Once { start: u64, duration: u64 },
+    Periodic { start: u64, period: u64, duration: u64 },
+    Random { mean_up: f64, mean_down: f64 }
+}
+impl engine::AppConfig for Outage {
+    fn new(&self) -> Box<dyn engine::App> {
+        match self.pattern {
+            OutagePattern::Periodic { period, duration, .. } =>
+                assert!(duration <= period,
+                        "Outage duration must not exceed period"),
+            OutagePattern::Random { mean_up, mean_down } =>
+                assert!(mean_up > 0.0 && mean_down > 0.0,
+                        "Mean up and down times must be positive"),
+            _ => ()
+        }
+        let mut rng = rng(self.seed);
+        let transition = match self.pattern {
+            OutagePattern::Random { mean_up, .. } =>
+                exponential(mean_up, rng.gen()),
+            _ => 0.0
+        };
+        Box::new(OutageApp {
+            pattern: self.pattern.clone(),
+            queue: self.hold.map(|size| RefCell::new(PacketQueue::new(size))),
+            start: Cell::new(None),
+            rng: RefCell::new(rng),
+            down: Cell::new(false),
+            transition: Cell::new(transition),
+            outages: RefCell::new(Vec::new())
+        })
+    }
+}
+pub struct OutageApp {
+    pattern: OutagePattern,
+    queue: Option<RefCell<PacketQueue>>,
+    start: Cell<Option<Instant>>,
+    rng: RefCell<StdRng>,
+    down: Cell<bool>, // currently in outage?
+    transition: Cell<f64>, // ms (next state change of Random pattern)
+    outages: RefCell<Vec<OutageRecord>>
+}
+struct OutageRecord {
+    start: f64, // ms
+    end: Option<f64>, // ms
+    held: u64,
+    dropped: u64
+}
+impl engine::App for OutageApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let now = engine::now();
+        if self.start.get().is_none() { self.start.set(Some(now)) }
+        let ms = now.saturating_duration_since(self.start.get().unwrap())
+            .as_secs_f64() * 1000.0;
+        let down = self.down_at(ms);
+        let mut outages = self.outages.borrow_mut();
+        if down && !self.down.get() {
+            outages.push(OutageRecord {
+                start: ms, end: None, held: 0, dropped: 0
+            });
+        } else if !down && self.down.get() {
+            outages.last_mut().unwrap().end = Some(ms);
+        }
+        self.down.set(down);
+        match &self.queue {
+            None => while !link::empty(&input) {
+                let p = link::receive(&mut input);
+                if down {
+                    outages.last_mut().unwrap().dropped += 1;
+                    packet::free(p);
+                } else {
+                    link::transmit(&mut output, p);
+                }
+            },
+            Some(queue) => {
+                let mut queue = queue.borrow_mut();
+                // Forward held packets first (once connectivity is restored)
+                while !down && !queue.empty() && !link::full(&output) {
+                    link::transmit(&mut output, queue.dequeue().1);
+                }
+                while !link::empty(&input) {
+                    let p = link::receive(&mut input);
+                    if !down && queue.empty() {
+                        link::transmit(&mut output, p);
+                    } else if queue.fits(&p) {
+                        if down { outages.last_mut().unwrap().held += 1; }
+                        queue.enqueue(now, p);
+                    } else {
+                        if let (true, Some(outage)) = (down, outages.last_mut()) {
+                            outage.dropped += 1;
+                        }
+                        packet::free(p);
+                    }
+                }
+            }
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        let outages = self.outages.borrow();
+        let held: u64 = outages.iter().map(|outage| outage.held).sum();
+        let dropped: u64 = outages.iter().map(|outage| outage.dropped).sum();
+        println!("  {} outages, {} packets held, {} packets dropped",
+                 outages.len(), held, dropped);
+        for outage in outages.iter() {
+            let end = match outage.end {
+                Some(end) => format!("{:.0} ms", end),
+                None => "(ongoing)".to_string()
+            };
+            println!("    {:.0} ms - {}: {} held, {} dropped",
+                     outage.start, end, outage.held, outage.dropped);
+        }
+    }
+}
+impl OutageApp {
+    // Return true if connectivity is lost `ms' milliseconds after start
+    fn down_at(&self, ms: f64) -> bool {
+        match self.pattern {
+            OutagePattern::Once { start, duration } => {
+                let (start, duration) = (start as f64, duration as f64);
+                ms >= start && ms < start + duration
+            },
+            OutagePattern::Periodic { start, period, duration } => {
+                let (start, period, duration) =
+                    (start as f64, period as f64, duration as f64);
+                ms >= start && (ms - start) % period < duration
+            },
+            OutagePattern::Random { mean_up, mean_down } => {
+                let mut rng = self.rng.borrow_mut();
+                let mut down = self.down.get();
+                while ms >= self.transition.get() {
+                    down = !down;
+                    let mean = if down { mean_down } else { mean_up };
+                    self.transition.set(
+                        self.transition.get() + exponential(mean, rng.gen())
+                    );
+                }
+                down
+            }
+        }
+    }
+}
+
+// Map uniform random value u (0..1) to an exponentially distributed value
+fn exponential(mean: f64, u: f64) -> f64 {
+    -mean * (1.0 - u).ln()
+}
+
+
+// Reorder app: reorder packets like netem(8)
+//
+// Packets selected for reordering are held back until `distance' packets
+// that arrived after them have been forwarded, all other packets are forwarded
+// immediately (i.e., only reordered packets are delayed). Like netem, the
+// `gap'-th packet since the last reordered packet is selected with probability
+// `probability' (correlated with the last random value like netem does it, see
+// correlated()). If `gap' is zero or one any packet is selected with that
+// probability.
+//
+// Held back packets are forwarded after at most `timeout' milliseconds (so
+// that packets are not held back indefinitely when traffic pauses).
+
+#[derive(Clone,Debug)]
+pub struct Reorder {
+    pub probability: f64, // probability of reordering a packet (0..1)
+    pub correlation: f64, // correlation between consecutive packets (0..1)
+    pub gap: u32, // packets since last reordered packet
+    pub distance: usize, // packets overtaking reordered packets
+    pub timeout: u64, // ms
+    pub seed: Option<u64> // random seed (see rng())
+}
+impl engine::AppConfig for Reorder {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!((0.0..=1.0).contains(&self.probability),
+                "Probability must be within 0.0 and 1.0");
+        assert!((0.0..=1.0).contains(&self.correlation),
+                "Correlation must be within 0.0 and 1.0");
+        Box::new(ReorderApp {
+            probability: self.probability,
+            correlation: self.correlation,
+            gap: self.gap,
+            distance: self.distance,
+            timeout: Duration::from_millis(self.timeout),
+            rng: RefCell::new(rng(self.seed)),
+            last: Cell::new(0.5),
+            counter: Cell::new(0),
+            held: RefCell::new(VecDeque::new()),
+            forwarded: Cell::new(0),
+            reordered: Cell::new(0)
+        })
+    }
+}
+pub struct ReorderApp {
+    probability: f64,
+    correlation: f64,
+    gap: u32,
+    distance: usize,
+    timeout: Duration,
+    rng: RefCell<StdRng>,
+    last: Cell<f64>, // last (correlated) uniform random value
+    counter: Cell<u32>, // packets since last reordered packet
+    // Reordered packets held back, and when they are released (i.e., once
+    // `forwarded' reaches the given count)
+    held: RefCell<VecDeque<(Instant, u64, Box<packet::Packet>)>>,
+    forwarded: Cell<u64>, // total packets forwarded immediately
+    reordered: Cell<u64> // total packets reordered
+}
+impl engine::App for ReorderApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut rng = self.rng.borrow_mut();
+        let mut held = self.held.borrow_mut();
+        let now = engine::now();
+        while !link::empty(&input) {
+            let p = link::receive(&mut input);
+            let reorder = self.counter.get() + 1 >= self.gap
+                && correlated(rng.gen::<f64>(), &self.last, self.correlation)
+                    < self.probability;
+            if reorder {
+                // Reorder: hold packet back until `distance' packets
+                // overtook it
+                self.counter.set(0);
+                self.reordered.set(self.reordered.get() + 1);
+                let release = self.forwarded.get() + self.distance as u64;
+                held.push_back((now, release, p));
+            } else {
+                self.counter.set(self.counter.get() + 1);
+                self.forwarded.set(self.forwarded.get() + 1);
+                link::transmit(&mut output, p);
+            }
+            while let Some(&(_, release, _)) = held.front() {
+                if self.forwarded.get() < release { break }
+                link::transmit(&mut output, release_held(&mut held));
+            }
+        }
+        // Forward packets held back for longer than timeout
+        while let Some(&(time, _, _)) = held.front() {
+            if now.saturating_duration_since(time) < self.timeout { break }
+            link::transmit(&mut output, release_held(&mut held));
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        println!("  {} packets reordered", self.reordered.get());
+    }
+}
+impl Drop for ReorderApp {
+    fn drop(&mut self) {
+        for (_, _, p) in self.held.borrow_mut().drain(..) { packet::free(p); }
+    }
+}
+
+// First packet held back is released now (see packet::Packet)
+fn release_held(held: &mut VecDeque<(Instant, u64, Box<packet::Packet>)>)
+                -> Box<packet::Packet> {
+    let (_, _, mut p) = held.pop_front().unwrap();
+    p.timestamp = Some(engine::now());
+    p
+}
+
+
+// Duplicate app: duplicate packets like netem(8)
+//
+// Packets are duplicated with probability `probability' (correlated with the
+// last random value like netem does it, see correlated()). Duplicates are
+// forwarded `delay' milliseconds after the original packet arrived.
+
+#[derive(Clone,Debug)]
+pub struct Duplicate {
+    pub probability: f64, // probability of duplicating a packet (0..1)
+    pub correlation: f64, // correlation between consecutive packets (0..1)
+    pub delay: u64, // ms between original and duplicate
+    pub capacity: usize, // delay queue capacity
+    pub seed: Option<u64> // random seed (see rng())
+}
+impl engine::AppConfig for Duplicate {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!((0.0..=1.0).contains(&self.probability),
+                "Probability must be within 0.0 and 1.0");
+        assert!((0.0..=1.0).contains(&self.correlation),
+                "Correlation must be within 0.0 and 1.0");
+        Box::new(DuplicateApp {
+            probability: self.probability,
+            correlation: self.correlation,
+            delay: Duration::from_millis(self.delay),
+            queue: RefCell::new(DelayQueue::new(self.capacity)),
+            rng: RefCell::new(rng(self.seed)),
+            last: Cell::new(0.5),
+            duplicates: Cell::new(0)
+        })
+    }
+}
+pub struct DuplicateApp {
+    probability: f64,
+    correlation: f64,
+    delay: Duration,
+    queue: RefCell<DelayQueue>,
+    rng: RefCell<StdRng>,
+    last: Cell<f64>, // last (correlated) uniform random value
+    duplicates: Cell<u64> // total packets duplicated
+}
+impl engine::App for DuplicateApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        let mut rng = self.rng.borrow_mut();
+        while !link::empty(&input) {
+            let p = link::receive(&mut input);
+            let u = correlated(rng.gen::<f64>(), &self.last, self.correlation);
+            if u < self.probability && !queue.full() {
+                self.duplicates.set(self.duplicates.get() + 1);
+                queue.enqueue(arrival(&p) + self.delay, packet::clone(&p));
+            }
+            link::transmit(&mut output, p);
+        }
+        // Forward duplicates without delay right away
+        queue.transmit_ready(&mut output);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        // Forward delayed duplicates
+        queue.transmit_ready(&mut output);
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        println!("  {} packets duplicated", self.duplicates.get());
+    }
+}
+
+
+// Corrupt app: flip random bits in packets like netem(8)
+//
+// Packets are corrupted with probability `probability' (correlated with the
+// last random value, see correlated()) by flipping `bits' random bits within
+// the region selected by `mode':
+//
+//   Payload - the TCP or UDP payload of IPv4 packets; the TCP or UDP checksum
+//             is recomputed so that the corrupted data reaches applications
+//   Invalid - the TCP or UDP payload of IPv4 packets; checksums are left
+//             as-is so that the receiving network stack drops the packets
+//   Header  - the given (offset, length) byte ranges, with offsets relative to
+//             the start of the Ethernet header; checksums are left as-is
+//
+// Packets without anything to corrupt in the selected region (e.g., non-IPv4
+// packets, or TCP segments without payload) are forwarded untouched.
+// NB: UDP checksums that are omitted (zero) are not filled in.
+
+#[derive(Clone,Debug)]
+pub struct Corrupt {
+    pub probability: f64, // probability of corrupting a packet (0..1)
+    pub correlation: f64, // correlation between consecutive packets (0..1)
+    pub bits: u32, // bits flipped per corrupted packet
+    pub mode: CorruptMode,
+    pub seed: Option<u64> // random seed (see rng())
+}
+#[derive(Clone,Debug)]
+pub enum CorruptMode {
+    Payload,
+    Invalid,
+    Header(Vec<(usize, usize)>) // byte ranges (offset, length)
+}
+impl engine::AppConfig for Corrupt {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!((0.0..=1.0).contains(&self.probability),
+                "Probability must be within 0.0 and 1.0");
+        assert!((0.0..=1.0).contains(&self.correlation),
+                "Correlation must be within 0.0 and 1.0");
+        Box::new(CorruptApp {
+            probability: self.probability,
+            correlation: self.correlation,
+            bits: self.bits,
+            mode: self.mode.clone(),
+            rng: RefCell::new(rng(self.seed)),
+            last: Cell::new(0.5),
+            corrupted: Cell::new(0)
+        })
+    }
+}
+pub struct CorruptApp {
+    probability: f64,
+    correlation: f64,
+    bits: u32,
+    mode: CorruptMode,
+    rng: RefCell<StdRng>,
+    last: Cell<f64>, // last (correlated) uniform random value
+    corrupted: Cell<u64> // total packets corrupted
+}
+impl engine::App for CorruptApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut rng = self.rng.borrow_mut();
+        while !link::empty(&input) {
+            let mut p = link::receive(&mut input);
+            let u = correlated(rng.gen::<f64>(), &self.last, self.correlation);
+            if u < self.probability && self.corrupt(&mut p, &mut *rng) {
+                self.corrupted.set(self.corrupted.get() + 1);
+            }
+            link::transmit(&mut output, p);
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        println!("  {} packets corrupted", self.corrupted.get());
+    }
+}
+impl CorruptApp {
+    // Corrupt packet according to mode, returns false if there was nothing
+    // to corrupt
+    fn corrupt<R: Rng>(&self, p: &mut packet::Packet, rng: &mut R) -> bool {
+        let length = p.length as usize;
+        match &self.mode {
+            CorruptMode::Header(ranges) =>
+                flip_bits(&mut p.data[..length], ranges, self.bits, rng),
+            mode => {
+                let (protocol, proto_ofs, proto_length) = match l4_segment(p) {
+                    Some(segment) => segment,
+                    None => return false
+                };
+                let header_length = match protocol {
+                    ipv4::PROTOCOL_TCP =>
+                        header::from_mem::<TCP>(&mut p.data[proto_ofs..]).size(),
+                    _ => header::size_of::<UDP>()
+                };
+                let payload = (proto_ofs + header_length,
+                               proto_length.saturating_sub(header_length));
+                if !flip_bits(&mut p.data[..length], &[payload], self.bits, rng) {
+                    return false
+                }
+                if let CorruptMode::Payload = mode {
+                    l4_checksum_compute(p);
+                }
+                true
+            }
+        }
+    }
+}
+
+// Flip `bits' random bits within byte ranges (offset, length) of data
+// (ranges are clipped to data). Returns false if there are no bits to flip.
+fn flip_bits<R: Rng>(data: &mut [u8], ranges: &[(usize, usize)], bits: u32,
+                     rng: &mut R) -> bool
+{
+    let size = data.len();
+    let clip = |&(offset, length): &(usize, usize)| {
+        let offset = offset.min(size);
+        (offset, length.min(size - offset))
+    };
+    let total: usize = ranges.iter().map(clip).map(|(_, length)| length).sum();
+    if total == 0 { return false }
+    for _ in 0..bits {
+        let mut bit = rng.gen_range(0..total * 8);
+        for (offset, length) in ranges.iter().map(clip) {
+            if bit < length * 8 {
+                data[offset + bit / 8] ^= 1 << (bit % 8);
+                break
+            }
+            bit -= length * 8;
+        }
+    }
+    true
+}
+
+// Protocol, offset, and length of the TCP or UDP segment of an IPv4 packet
+fn l4_segment(p: &mut packet::Packet) -> Option<(u8, usize, usize)> {
+    let eth = header::from_mem::<Ethernet>(&mut p.data);
+    if eth.ethertype() != ethernet::TYPE_IPV4 { return None }
+    let ip_ofs = header::size_of::<Ethernet>();
+    let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+    let protocol = ip.protocol();
+    if protocol != ipv4::PROTOCOL_TCP && protocol != ipv4::PROTOCOL_UDP {
+        return None
+    }
+    let proto_ofs = ip_ofs + ip.ihl() as usize * 4;
+    let end = min(ip_ofs + ip.total_length() as usize, p.length as usize);
+    let header_length = match protocol {
+        ipv4::PROTOCOL_TCP => header::size_of::<TCP>(),
+        _ => header::size_of::<UDP>()
+    };
+    if end < proto_ofs + header_length { return None }
+    Some((protocol, proto_ofs, end - proto_ofs))
+}
+
+// Recompute the TCP or UDP checksum of an IPv4 packet (see l4_segment())
+fn l4_checksum_compute(p: &mut packet::Packet) {
+    let (protocol, proto_ofs, proto_length) = match l4_segment(p) {
+        Some(segment) => segment,
+        None => return
+    };
+    let ip_ofs = header::size_of::<Ethernet>();
+    let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+    let pseudo_csum = ip.pseudo_checksum(protocol, proto_length as u16);
+    if protocol == ipv4::PROTOCOL_TCP {
+        let mut tcp = header::from_mem::<TCP>(&mut p.data[proto_ofs..]);
+        let payload_ofs = proto_ofs + header::size_of::<TCP>();
+        let payload_length = proto_length - header::size_of::<TCP>();
+        tcp.checksum_compute(
+            &p.data[payload_ofs..], payload_length as u16, !pseudo_csum
+        );
+    } else {
+        let mut udp = header::from_mem::<UDP>(&mut p.data[proto_ofs..]);
+        if udp.checksum() == 0 { return } // Checksum omitted
+        let payload_ofs = proto_ofs + header::size_of::<UDP>();
+        let payload_length = proto_length - header::size_of::<UDP>();
+        udp.checksum_compute(
+            &p.data[payload_ofs..], payload_length as u16, !pseudo_csum
+        );
     }
 }
 
@@ -49,33 +782,40 @@ impl engine::App for LossApp {
 #[derive(Clone,Debug)]
 pub struct Latency {
     pub ms: u64, // milliseconds of latency
-    pub capacity: usize // delay queue capacity
+    pub capacity: usize, // delay queue capacity
+    pub schedule: Option<Schedule> // schedule of `ms'
 }
 impl engine::AppConfig for Latency {
     fn new(&self) -> Box<dyn engine::App> {
         Box::new(LatencyApp {
             ms: self.ms,
-            queue: RefCell::new(DelayQueue::new(self.capacity))
+            queue: RefCell::new(DelayQueue::new(self.capacity)),
+            schedule: self.schedule.clone(),
+            start: Cell::new(None)
         })
     }
 }
 pub struct LatencyApp {
     ms: u64,
-    queue: RefCell<DelayQueue>
+    queue: RefCell<DelayQueue>,
+    schedule: Option<Schedule>,
+    start: Cell<Option<Instant>>
 }
 impl engine::App for LatencyApp {
     fn has_push(&self) -> bool { true }
     fn push(&self, app: &engine::AppState) {
         let mut input = app.input.get("input").unwrap().borrow_mut();
         let mut queue = self.queue.borrow_mut();
-        // Enqueue delay
-        if !link::empty(&input) && !queue.full() {
-            let ttx = engine::now() + Duration::from_millis(self.ms);
-            queue.enqueue_delay(ttx);
-        }
-        // Enqueue packet batch
+        let delay = match &self.schedule {
+            Some(schedule) => Duration::from_secs_f64(
+                schedule.value_since(&self.start).max(0.0) / 1000.0
+            ),
+            None => Duration::from_millis(self.ms)
+        };
+        // Enqueue packets, delayed relative to their arrival
         while !link::empty(&input) && !queue.full() {
-            queue.enqueue_packet(link::receive(&mut input));
+            let p = link::receive(&mut input);
+            queue.enqueue(arrival(&p) + delay, p);
         }
     }
     fn has_pull(&self) -> bool { true }
@@ -83,37 +823,89 @@ impl engine::App for LatencyApp {
         let mut output = app.output.get("output").unwrap().borrow_mut();
         let mut queue = self.queue.borrow_mut();
         // Forward queued packets ready to transmit
-        while !queue.empty() && queue.need_tx() {
-            link::transmit(&mut output, queue.dequeue_packet());
-        }
+        queue.transmit_ready(&mut output);
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        self.queue.borrow().report();
     }
 }
 
 // Jitter app: simulate random latency jitter
-// XXX - jitter should probably be normally distributed?
+//
+// Each packet is delayed (with probability `strength') by
+//
+//   mean + ms * X
+//
+// where X is drawn from `distribution'. For Uniform, X is within 0..1 (i.e.,
+// by default jitter is within 0..ms). The other distributions are standardized
+// to a mean of zero and a standard deviation of one (i.e., `ms' is the
+// standard deviation), and clamped to ±4 like netem’s distribution tables.
+// Negative delays are truncated to zero.
+//
+// Table distributes X according to a netem(8) distribution table (.dist
+// files as shipped with iproute2, see read_netem_table).
+//
+// Histogram is the odd one out: it is a histogram of measured delays, and
+// packets are delayed by `mean' plus a delay sampled from the histogram (i.e.,
+// `ms' is ignored, see read_histogram).
+//
+// Consecutive random values can be correlated like netem does it, i.e.
+//
+//   U(n) = (1 - correlation) * U + correlation * U(n-1)
+//
+// where U is a uniform random value that is then mapped to X via the inverse
+// of the distribution’s CDF.
 
 #[derive(Clone,Debug)]
 pub struct Jitter {
-    pub ms: u64, // milliseconds of maximum jitter
+    pub ms: u64, // milliseconds of jitter (see above)
+    pub mean: u64, // milliseconds of mean jitter
+    pub distribution: Distribution, // jitter distribution
+    pub correlation: f64, // correlation between consecutive packets (0..1)
     pub strength: f64, // jitter strength (0.0 → no jitter, 1.0 → very strong jitter)
     pub reorder: bool, // should jitter reorder packets?
-    pub capacity: usize // delay queue capacity
+    pub capacity: usize, // delay queue capacity
+    pub seed: Option<u64>, // random seed (see rng())
+    pub schedule: Option<Schedule> // schedule of `ms'
+}
+#[derive(Clone,Debug)]
+pub enum Distribution {
+    Uniform, Normal, Pareto, ParetoNormal,
+    Table(Vec<i16>), // inverse CDF scaled by NETEM_DIST_SCALE
+    Histogram(Vec<(f64, f64)>) // (delay in us, cumulative probability)
 }
 impl engine::AppConfig for Jitter {
     fn new(&self) -> Box<dyn engine::App> {
+        assert!((0.0..=1.0).contains(&self.correlation),
+                "Correlation must be within 0.0 and 1.0");
         Box::new(JitterApp {
             us: self.ms as f64 * 1000.0,
+            mean_us: self.mean as f64 * 1000.0,
+            distribution: self.distribution.clone(),
+            correlation: self.correlation,
+            last: Cell::new(0.5),
             strength: self.strength,
             reorder: self.reorder,
-            queue: RefCell::new(DelayQueue::new(self.capacity))
+            queue: RefCell::new(DelayQueue::new(self.capacity)),
+            rng: RefCell::new(rng(self.seed)),
+            schedule: self.schedule.clone(),
+            start: Cell::new(None)
         })
     }
 }
 pub struct JitterApp {
     us: f64,
+    mean_us: f64,
+    distribution: Distribution,
+    correlation: f64,
+    last: Cell<f64>, // last (correlated) uniform random value
     strength: f64,
     reorder: bool,
-    queue: RefCell<DelayQueue>
+    queue: RefCell<DelayQueue>,
+    rng: RefCell<StdRng>,
+    schedule: Option<Schedule>,
+    start: Cell<Option<Instant>>
 }
 impl engine::App for JitterApp {
     fn has_push(&self) -> bool { true }
@@ -121,22 +913,33 @@ impl engine::App for JitterApp {
         let mut input = app.input.get("input").unwrap().borrow_mut();
         let mut output = app.output.get("output").unwrap().borrow_mut();
         let mut queue = self.queue.borrow_mut();
-        let mut rng = rand::thread_rng();
+        let mut rng = self.rng.borrow_mut();
+        let us = match &self.schedule {
+            Some(schedule) => schedule.value_since(&self.start).max(0.0) * 1000.0,
+            None => self.us
+        };
         // Add jitter to incoming packets
         while !link::empty(&input) && !queue.full() {
+            let p = link::receive(&mut input);
             let add_jitter = rng.gen::<f64>() < self.strength;
-            if add_jitter {
-                let jitter = (self.us * rng.gen::<f64>()) as u64;
-                let ttx = engine::now() + Duration::from_micros(jitter);
-                queue.enqueue_delay(ttx);
-            }
             if !add_jitter && self.reorder {
                 // If reorder=true then forward packets without added jitter
                 // immediately, effectively reordering them
-                link::transmit(&mut output, link::receive(&mut input));
-            } else if !queue.full() {
-                queue.enqueue_packet(link::receive(&mut input));
+                link::transmit(&mut output, p);
+                continue
+            }
+            let mut jitter = 0;
+            if add_jitter {
+                let u = correlated(rng.gen::<f64>(), &self.last, self.correlation);
+                let us = match &self.distribution {
+                    Distribution::Histogram(histogram) =>
+                        self.mean_us + histogram_value(histogram, u),
+                    distribution =>
+                        self.mean_us + us * jitter_value(distribution, u)
+                };
+                jitter = us.max(0.0) as u64;
             }
+            queue.enqueue(arrival(&p) + Duration::from_micros(jitter), p);
         }
     }
     fn has_pull(&self) -> bool { true }
@@ -144,25 +947,175 @@ impl engine::App for JitterApp {
         let mut output = app.output.get("output").unwrap().borrow_mut();
         let mut queue = self.queue.borrow_mut();
         // Forward packets with jitter delay
-        while !queue.empty() && queue.need_tx() {
-            link::transmit(&mut output, queue.dequeue_packet());
+        queue.transmit_ready(&mut output);
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        self.queue.borrow().report();
+    }
+}
+
+// Correlate uniform random value u with the last value (updates last)
+fn correlated(u: f64, last: &Cell<f64>, correlation: f64) -> f64 {
+    let u = (1.0 - correlation) * u + correlation * last.get();
+    last.set(u);
+    u
+}
+
+// Map uniform random value u (0..1) to a value of distribution
+fn jitter_value(distribution: &Distribution, u: f64) -> f64 {
+    // Keep u off the poles of the inverse CDFs
+    let u = u.clamp(1e-9, 1.0 - 1e-9);
+    let x = match distribution {
+        Distribution::Uniform => return u,
+        Distribution::Normal => normal_quantile(u),
+        Distribution::Pareto => pareto_quantile(u),
+        // Mixture as used by iproute2’s paretonormal.dist
+        Distribution::ParetoNormal =>
+            0.25 * normal_quantile(u) + 0.75 * pareto_quantile(u),
+        Distribution::Table(table) =>
+            table[(u * table.len() as f64) as usize] as f64
+                / NETEM_DIST_SCALE as f64,
+        Distribution::Histogram(_) => panic!("Histogram has no standard form")
+    };
+    x.clamp(-4.0, 4.0)
+}
+
+// Map uniform random value u (0..1) to a delay (in us) from histogram
+fn histogram_value(histogram: &[(f64, f64)], u: f64) -> f64 {
+    let index = histogram.partition_point(|&(_, cumulative)| cumulative < u);
+    histogram[index.min(histogram.len() - 1)].0
+}
+
+// Read a netem distribution table (as generated by iproute2’s maketable,
+// e.g. /usr/lib/tc/normal.dist)
+//
+// The file consists of whitespace separated signed 16-bit integers, lines
+// starting with # are comments. The table is a sampled inverse CDF of a
+// distribution with mean zero and standard deviation NETEM_DIST_SCALE.
+pub const NETEM_DIST_SCALE: i64 = 8192;
+const NETEM_DIST_MAX: usize = 16384;
+pub fn read_netem_table(path: &str) -> Result<Distribution, Box<dyn Error>> {
+    let mut table = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim_start().starts_with('#') { continue }
+        for value in line.split_whitespace() {
+            table.push(value.parse::<i16>()?);
         }
     }
+    if table.is_empty() || table.len() > NETEM_DIST_MAX {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid netem table size ({}) in {}", table.len(), path)
+        )))
+    }
+    Ok(Distribution::Table(table))
 }
 
-struct DelayQueue {
-    packets: VecDeque<DelayedPacket>,
-    capacity: usize
+// Read a delay histogram from a CSV file
+//
+// Each line consists of a delay in milliseconds (may be fractional) and a
+// weight (e.g., number of samples), separated by a comma. Lines starting with
+// # and lines that do not start with a number (i.e., a header) are skipped.
+pub fn read_histogram(path: &str) -> Result<Distribution, Box<dyn Error>> {
+    let mut bins = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if !fields[0].starts_with(|c: char| c.is_ascii_digit()) { continue }
+        if fields.len() != 2 {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected <delay>,<weight> in {}: {}", path, line)
+            )))
+        }
+        let (delay, weight) = (fields[0].parse::<f64>()?,
+                               fields[1].parse::<f64>()?);
+        if weight < 0.0 {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Negative weight in {}: {}", path, line)
+            )))
+        }
+        bins.push((delay * 1000.0, weight));
+    }
+    let total: f64 = bins.iter().map(|&(_, weight)| weight).sum();
+    if total <= 0.0 {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Empty histogram in {}", path)
+        )))
+    }
+    bins.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut cumulative = 0.0;
+    for bin in bins.iter_mut() {
+        cumulative += bin.1 / total;
+        bin.1 = cumulative;
+    }
+    Ok(Distribution::Histogram(bins))
+}
+
+// Inverse CDF of the standard normal distribution
+// P. J. Acklam’s rational approximation (relative error < 1.15e-9)
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e+01,  2.209460984245205e+02,
+                         -2.759285104469687e+02,  1.38357751867269e+02,
+                         -3.066479806614716e+01,  2.506628277459239e+00];
+    const B: [f64; 5] = [-5.447609879822406e+01,  1.615858368580409e+02,
+                         -1.556989798598866e+02,  6.680131188771972e+01,
+                         -1.328068155288572e+01];
+    const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01,
+                         -2.400758277161838e+00, -2.549732539343734e+00,
+                          4.374664141464968e+00,  2.938163982698783e+00];
+    const D: [f64; 4] = [ 7.784695709041462e-03,  3.224671290700398e-01,
+                          2.445134137142996e+00,  3.754408661907416e+00];
+    let p_low = 0.02425;
+    if p < p_low {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0]*q+C[1])*q+C[2])*q+C[3])*q+C[4])*q+C[5]) /
+            ((((D[0]*q+D[1])*q+D[2])*q+D[3])*q+1.0)
+    } else if p <= 1.0 - p_low {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0]*r+A[1])*r+A[2])*r+A[3])*r+A[4])*r+A[5])*q /
+            (((((B[0]*r+B[1])*r+B[2])*r+B[3])*r+B[4])*r+1.0)
+    } else {
+        -normal_quantile(1.0 - p)
+    }
 }
-enum DelayedPacket {
-    Delay(Instant),
-    Packet(Box<packet::Packet>)
+
+// Inverse CDF of a Pareto distribution (shape 3, like netem’s pareto.dist),
+// standardized to mean zero and standard deviation one
+fn pareto_quantile(p: f64) -> f64 {
+    let shape: f64 = 3.0;
+    let mean = shape / (shape - 1.0);
+    let stddev = (shape / ((shape - 1.0) * (shape - 1.0) * (shape - 2.0))).sqrt();
+    ((1.0 - p).powf(-1.0 / shape) - mean) / stddev
+}
+
+// FIFO queue of packets delayed until their time to transmit
+//
+// Packets are released in order of arrival, each once its time to transmit
+// has passed (i.e., packets are not reordered). Released packets are stamped
+// with their time to transmit (so that delays accumulate accurately across
+// apps), and the queue keeps track of how late packets are released (latency
+// error).
+struct DelayQueue {
+    packets: VecDeque<(Instant, Box<packet::Packet>)>,
+    capacity: usize,
+    released: u64, // packets released
+    error_total: Duration, // sum of latency errors
+    error_max: Duration // maximum latency error
 }
 impl DelayQueue {
     fn new(capacity: usize) -> DelayQueue {
         DelayQueue {
             packets: VecDeque::with_capacity(capacity),
-            capacity: capacity
+            capacity,
+            released: 0,
+            error_total: Duration::ZERO,
+            error_max: Duration::ZERO
         }
     }
     fn full(&self) -> bool {
@@ -171,151 +1124,1758 @@ impl DelayQueue {
     fn empty(&self) -> bool {
         self.packets.is_empty()
     }
-    fn peek(&mut self) -> &DelayedPacket {
-        match self.packets.front() {
+    fn enqueue(&mut self, ttx: Instant, p: Box<packet::Packet>) {
+        if self.full() { panic!("Queue overflow.") }
+        self.packets.push_back((ttx, p));
+    }
+    fn dequeue(&mut self) -> (Instant, Box<packet::Packet>) {
+        match self.packets.pop_front() {
             Some(entry) => entry,
             None => panic!("Queue underflow.")
         }
     }
-    fn enqueue_delay(&mut self, ttx: Instant) {
-        if self.full() { panic!("Queue overflow.") }
-        self.packets.push_back(DelayedPacket::Delay(ttx));
-    }
-    fn enqueue_packet(&mut self, p: Box<packet::Packet>) {
-        if self.full() { panic!("Queue overflow.") }
-        self.packets.push_back(DelayedPacket::Packet(p));
-    }
-    fn need_tx(&mut self) -> bool {
-        match self.peek() {
-            DelayedPacket::Packet(_) => true,
-            DelayedPacket::Delay(ttx) => {
-                if engine::now() >= *ttx {
-                    self.packets.pop_front();
-                    true
-                } else {
-                    false
-                }
-            }
+    // Forward packets whose time to transmit has passed
+    fn transmit_ready(&mut self, output: &mut link::Link) {
+        let now = engine::now();
+        while let Some(&(ttx, _)) = self.packets.front() {
+            if ttx > now { break }
+            let (ttx, mut p) = self.dequeue();
+            let error = now - ttx;
+            self.released += 1;
+            self.error_total += error;
+            self.error_max = self.error_max.max(error);
+            p.timestamp = Some(ttx);
+            link::transmit(output, p);
         }
     }
-    fn dequeue_packet(&mut self) -> Box<packet::Packet> {
-        match self.packets.pop_front() {
-            Some(DelayedPacket::Packet(p)) => p,
-            Some(DelayedPacket::Delay(_)) => panic!("Expected packet."),
-            None => panic!("Queue underflow.")
-        }
+    fn report(&self) {
+        let mean = match self.released {
+            0 => Duration::ZERO,
+            n => self.error_total / n as u32
+        };
+        println!("  latency error: mean {} us, max {} us ({} packets)",
+                 mean.as_micros(), self.error_max.as_micros(), self.released);
     }
 }
 impl Drop for DelayQueue {
     fn drop(&mut self) {
-        while !self.empty() {
-            match self.peek() {
-                DelayedPacket::Packet(_) => packet::free(self.dequeue_packet()),
-                DelayedPacket::Delay(_) => { self.packets.pop_front(); () }
-            }
-        }
+        while !self.empty() { packet::free(self.dequeue().1); }
     }
 }
 
+// Time at which packet arrived (or engine::now() if unknown)
+fn arrival(p: &packet::Packet) -> Instant {
+    p.timestamp.unwrap_or_else(engine::now)
+}
+
 
 // RateLimiter app: limit throughput to bitrate
 
 // uses http://en.wikipedia.org/wiki/Token_bucket algorithm
-// single bucket, drop non-conformant packets
+// single bucket, drop non-conformant packets (policing) or queue them until
+// enough tokens become available (shaping)
+//
+// When shaping, non-conformant packets are queued in a FIFO of the given
+// size. The size can be specified in bytes, or in milliseconds worth of
+// traffic at `rate'. Packets that would overflow the queue are dropped
+// (tail-drop).
+//
+// The rate applies to the length of packets on-the-wire, including the
+// overhead of the link-layer `framing' (see packet::Framing).
 #[derive(Clone,Debug)]
 pub struct RateLimiter {
-    pub rate: u64 // bits per second (bps)
+    pub rate: u64, // bits per second (bps)
+    pub queue: Option<QueueSize>, // queue size (None → police, no queue)
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+#[derive(Clone,Debug)]
+pub enum QueueSize {
+    Bytes(usize),
+    Millis(u64)
+}
+impl QueueSize {
+    // Return queue size in bytes for a given rate
+    pub fn bytes(&self, rate: u64) -> usize {
+        match *self {
+            QueueSize::Bytes(bytes) => bytes,
+            QueueSize::Millis(ms) => (rate * ms / 8_000) as usize
+        }
+    }
 }
 impl engine::AppConfig for RateLimiter {
     fn new(&self) -> Box<dyn engine::App> {
-        // Late limiting with a single token bucket is not an excact science
-        // (imagine bursty traffic, and limitations of time-keeping in context
-        // of the implementation)
-        //
-        // We do two things here to behave reasonable:
-        //   - avoid IEEE floating point math by scaling our integer values
-        //   - operate on discrete ticks of time (100 us per tick)
-        //   - choose bucket capacity and initial token values to hopefully
-        //     cover our operational range
-        //
-        // The result should be good enough to shape bandwidths between ~50 Kbps
-        // and 10 Gbps within 10% accuracy over a 100 ms time window.
-        // Below ~50 Kbps accuracy decreases significantly.
-        //
-        // `scale' is set to the number of microseconds in a second.
-        // NB: if you change this value you have to change how tokens are
-        // replenished in push() accordingly.
-        //
-        // `capacity' is set to the scaled rate over 1 second, and directly
-        // affects the permitted burstiness of traffic. I.e., RateLimiter will
-        // allow bursts of up to `rate` bits without throttling.
-        //
-        // `initial_tokens` is choosen to cover bandwidth expected between two
-        // ticks. Roughly speaking, if you set this to higher values, the rate
-        // limit will take longer to take effect (i.e., larger initial bursts).
-        //
-        let scale = 1_000_000;
-        let tick = 100; // us
-        let capacity = self.rate*scale;
-        let initial_tokens = self.rate*scale / (1_000_000 / tick);
+        let bucket = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
         Box::new(RateLimiterApp {
-            rate: self.rate,
-            scale: scale,
-            tick: tick,
-            bucket: RefCell::new(BitrateBucket {
-                capacity: capacity,
-                tokens: initial_tokens,
-                last_time: None
-            })
+            bucket: RefCell::new(bucket),
+            queue: self.queue.as_ref().map(|size| RefCell::new(
+                PacketQueue::new(size.bytes(self.rate))
+            ))
         })
     }
 }
-pub struct RateLimiterApp {
-    rate: u64,
-    scale: u64,
-    tick: u64,
-    bucket: RefCell<BitrateBucket>
+pub struct RateLimiterApp {
+    bucket: RefCell<BitrateBucket>,
+    queue: Option<RefCell<PacketQueue>>
+}
+impl engine::App for RateLimiterApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+
+        bucket.replenish();
+
+        if let Some(queue) = &self.queue {
+            // Enqueue packets (drop packets that would overflow the queue),
+            // and forward queued packets as tokens permit
+            let mut queue = queue.borrow_mut();
+            while !link::empty(&input) {
+                let p = link::receive(&mut input);
+                if queue.fits(&p) {
+                    queue.enqueue(engine::now(), p);
+                } else {
+                    packet::free(p);
+                }
+            }
+            drain(&mut queue, &mut bucket, &mut output);
+            return
+        }
+
+        // Forward packets, consuming bucket tokens
+        while !link::empty(&input) {
+            let p = link::receive(&mut input);
+            if bucket.conforms(&p) {
+                bucket.take(&p);
+                link::transmit(&mut output, p);
+            } else {
+                // Out of tokens: drop packet
+                packet::free(p);
+            }
+        }
+    }
+    fn has_pull(&self) -> bool { self.queue.is_some() }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+        let mut queue = self.queue.as_ref().unwrap().borrow_mut();
+        // Forward queued packets as tokens become available
+        bucket.replenish();
+        drain(&mut queue, &mut bucket, &mut output);
+    }
+}
+
+// Forward queued packets, consuming bucket tokens
+fn drain(queue: &mut PacketQueue, bucket: &mut BitrateBucket,
+         output: &mut link::Link) {
+    while !queue.empty() && !link::full(output) {
+        if !bucket.conforms(queue.peek()) { break }
+        let (_, p) = queue.dequeue();
+        bucket.take(&p);
+        link::transmit(output, p);
+    }
+}
+
+struct BitrateBucket {
+    rate: u64,
+    scale: u64,
+    tick: u64,
+    capacity: u64,
+    tokens: u64,
+    last_time: Option<Instant>,
+    schedule: Option<(Schedule, Cell<Option<Instant>>)>, // schedule of rate
+    framing: packet::Framing // framing of packets (see packet::bitlength())
+}
+impl BitrateBucket {
+    fn new(rate: u64) -> BitrateBucket {
+        // Late limiting with a single token bucket is not an excact science
+        // (imagine bursty traffic, and limitations of time-keeping in context
+        // of the implementation)
+        //
+        // We do two things here to behave reasonable:
+        //   - avoid IEEE floating point math by scaling our integer values
+        //   - operate on discrete ticks of time (100 us per tick)
+        //   - choose bucket capacity and initial token values to hopefully
+        //     cover our operational range
+        //
+        // The result should be good enough to shape bandwidths between ~50 Kbps
+        // and 10 Gbps within 10% accuracy over a 100 ms time window.
+        // Below ~50 Kbps accuracy decreases significantly.
+        //
+        // `scale' is set to the number of microseconds in a second.
+        // NB: if you change this value you have to change how tokens are
+        // replenished in replenish() accordingly.
+        //
+        // `capacity' is set to the scaled rate over 1 second, and directly
+        // affects the permitted burstiness of traffic. I.e., RateLimiter will
+        // allow bursts of up to `rate` bits without throttling.
+        //
+        // `initial_tokens` is choosen to cover bandwidth expected between two
+        // ticks. Roughly speaking, if you set this to higher values, the rate
+        // limit will take longer to take effect (i.e., larger initial bursts).
+        //
+        let scale = 1_000_000;
+        let tick = 100; // us
+        let capacity = rate*scale;
+        let initial_tokens = rate*scale / (1_000_000 / tick);
+        BitrateBucket {
+            rate,
+            scale,
+            tick,
+            capacity,
+            tokens: initial_tokens,
+            last_time: None,
+            schedule: None,
+            framing: packet::ETHERNET
+        }
+    }
+
+    // New bucket with rate that follows schedule (if any)
+    fn scheduled(rate: u64, schedule: &Option<Schedule>) -> BitrateBucket {
+        let mut bucket = BitrateBucket::new(rate);
+        bucket.schedule = schedule.clone()
+            .map(|schedule| (schedule, Cell::new(None)));
+        bucket
+    }
+
+    // Bucket that accounts for the link-layer framing of packets
+    fn framed(mut self, framing: packet::Framing) -> BitrateBucket {
+        self.framing = framing;
+        self
+    }
+
+    // Replenish bucket tokens (once every tick at most)
+    fn replenish(&mut self) {
+        if let Some((schedule, start)) = &self.schedule {
+            let rate = schedule.value_since(start).max(0.0) as u64;
+            self.rate = rate;
+            self.capacity = rate*self.scale;
+            self.tokens = min(self.tokens, self.capacity);
+        }
+        let now = engine::now();
+        if let Some(last_time) = self.last_time {
+            let us_elapsed = (now - last_time).as_micros() as u64;
+            if us_elapsed >= self.tick {
+                self.last_time = Some(engine::now());
+                self.tokens = min(
+                    self.tokens + (self.rate * us_elapsed),
+                    self.capacity
+                );
+            }
+        } else {
+            self.last_time = Some(engine::now());
+        }
+    }
+
+    // Are there enough tokens to forward packet?
+    fn conforms(&self, p: &packet::Packet) -> bool {
+        packet::framed_bitlength(p, &self.framing) * self.scale <= self.tokens
+    }
+
+    // Consume tokens for packet (packet must conform)
+    fn take(&mut self, p: &packet::Packet) {
+        self.tokens -= packet::framed_bitlength(p, &self.framing) * self.scale;
+    }
+}
+
+// Byte-limited FIFO queue of packets, remembers when each packet was enqueued
+struct PacketQueue {
+    packets: VecDeque<(Instant, Box<packet::Packet>)>,
+    bytes: usize, // bytes currently enqueued
+    limit: usize // maximum bytes enqueued
+}
+impl PacketQueue {
+    fn new(limit: usize) -> PacketQueue {
+        PacketQueue { packets: VecDeque::new(), bytes: 0, limit }
+    }
+    fn empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+    fn fits(&self, p: &packet::Packet) -> bool {
+        self.bytes + p.length as usize <= self.limit
+    }
+    fn enqueue(&mut self, time: Instant, p: Box<packet::Packet>) {
+        self.bytes += p.length as usize;
+        self.packets.push_back((time, p));
+    }
+    fn peek(&self) -> &packet::Packet {
+        match self.packets.front() {
+            Some((_, p)) => p,
+            None => panic!("Queue underflow.")
+        }
+    }
+    fn dequeue(&mut self) -> (Instant, Box<packet::Packet>) {
+        match self.packets.pop_front() {
+            Some((time, mut p)) => {
+                self.bytes -= p.length as usize;
+                // Packet is released now (see packet::Packet)
+                p.timestamp = Some(engine::now());
+                (time, p)
+            },
+            None => panic!("Queue underflow.")
+        }
+    }
+    // Put back packet previously dequeued (at the head of the queue)
+    fn requeue(&mut self, time: Instant, p: Box<packet::Packet>) {
+        self.bytes += p.length as usize;
+        self.packets.push_front((time, p));
+    }
+}
+impl Drop for PacketQueue {
+    fn drop(&mut self) {
+        while !self.empty() { packet::free(self.dequeue().1); }
+    }
+}
+
+
+
+// SerialLink app: simulate a serial link (transmission and propagation delay)
+//
+// Unlike with RateLimiter followed by Latency, packets are transmitted onto
+// the link one after another: each packet is transmitted once the link has
+// finished transmitting the previous packet, which takes the packet’s length
+// on-the-wire (see packet::Framing) divided by `rate', and then takes
+// `propagation' milliseconds to reach the other end of the link:
+//
+//   departure = max(arrival, link free) + bits / rate + propagation
+//
+// I.e., large packets take longer than small ones, and packets arriving while
+// the link is busy wait for the packets ahead of them. Packets that would
+// exceed `queue' bytes waiting to be transmitted are dropped (tail-drop).
+
+#[derive(Clone,Debug)]
+pub struct SerialLink {
+    pub rate: u64, // bits per second (bps)
+    pub propagation: u64, // milliseconds of propagation delay
+    pub framing: packet::Framing, // link-layer framing
+    pub queue: QueueSize, // maximum bytes waiting to be transmitted
+    pub capacity: usize // delay queue capacity
+}
+impl engine::AppConfig for SerialLink {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!(self.rate > 0, "Rate must be positive");
+        Box::new(SerialLinkApp {
+            rate: self.rate,
+            propagation: Duration::from_millis(self.propagation),
+            framing: self.framing,
+            queue: self.queue.bytes(self.rate),
+            free: Cell::new(None),
+            packets: RefCell::new(DelayQueue::new(self.capacity)),
+            drops: Cell::new(0)
+        })
+    }
+}
+pub struct SerialLinkApp {
+    rate: u64,
+    propagation: Duration,
+    framing: packet::Framing,
+    queue: usize,
+    free: Cell<Option<Instant>>, // time the link has finished transmitting
+    packets: RefCell<DelayQueue>, // packets being transmitted or propagated
+    drops: Cell<u64> // total packets dropped
+}
+impl engine::App for SerialLinkApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut packets = self.packets.borrow_mut();
+        while !link::empty(&input) {
+            let p = link::receive(&mut input);
+            let arrival = arrival(&p);
+            let start = self.free.get().map_or(arrival, |free| free.max(arrival));
+            // Bytes waiting to be transmitted ahead of packet
+            let backlog = (start - arrival).as_nanos() * self.rate as u128
+                / 8_000_000_000;
+            if packets.full()
+                || backlog as usize + p.length as usize > self.queue
+            {
+                self.drops.set(self.drops.get() + 1);
+                packet::free(p);
+                continue
+            }
+            let bits = packet::framed_bitlength(&p, &self.framing);
+            let free = start + Duration::from_nanos(
+                bits * 1_000_000_000 / self.rate
+            );
+            self.free.set(Some(free));
+            packets.enqueue(free + self.propagation, p);
+        }
+        // Forward packets that are already due
+        packets.transmit_ready(&mut output);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut packets = self.packets.borrow_mut();
+        // Forward packets that have arrived at the other end of the link
+        packets.transmit_ready(&mut output);
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        println!("  {} packets dropped", self.drops.get());
+        self.packets.borrow().report();
+    }
+}
+
+
+
+// Policer app: two rate three color marker (RFC 2698)
+//
+// Packets are metered against a committed rate `cir' with a committed burst
+// size `cbs', and a peak rate `pir' with a peak burst size `pbs' (bursts in
+// bytes). Each rate has a token bucket that starts full. Packets that exceed
+// the peak bucket are red, packets that exceed only the committed bucket are
+// yellow, and all other packets are green:
+//
+//   red    - dropped
+//   yellow - dropped, or remarked with DSCP `yellow' (if given) and forwarded
+//   green  - forwarded
+//
+// Unlike RateLimiter, tokens are accounted for with nanosecond precision, so
+// that bursts can be limited to a few packets even at low rates. Packet sizes
+// include the overhead of the link-layer `framing' (see packet::Framing).
+// NB: only IP packets are remarked (see dscp_mark()), other yellow packets
+// are forwarded unchanged.
+
+#[derive(Clone,Debug)]
+pub struct Policer {
+    pub cir: u64, // committed information rate (bps)
+    pub cbs: u64, // committed burst size (bytes)
+    pub pir: u64, // peak information rate (bps)
+    pub pbs: u64, // peak burst size (bytes)
+    pub yellow: Option<u8>, // DSCP for yellow packets (None → drop)
+    pub framing: packet::Framing // link-layer framing
+}
+impl engine::AppConfig for Policer {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!(self.pir >= self.cir, "PIR must not be below CIR");
+        assert!(self.yellow.is_none_or(|dscp| dscp < 64),
+                "DSCP must be below 64");
+        Box::new(PolicerApp {
+            committed: RefCell::new(TokenBucket::new(self.cir, self.cbs)),
+            peak: RefCell::new(TokenBucket::new(self.pir, self.pbs)),
+            yellow: self.yellow,
+            framing: self.framing,
+            last: Cell::new(None),
+            colors: Cell::new((0, 0, 0))
+        })
+    }
+}
+pub struct PolicerApp {
+    committed: RefCell<TokenBucket>,
+    peak: RefCell<TokenBucket>,
+    yellow: Option<u8>,
+    framing: packet::Framing,
+    last: Cell<Option<Instant>>, // time tokens were last added
+    colors: Cell<(u64, u64, u64)> // total packets (green, yellow, red)
+}
+impl engine::App for PolicerApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut committed = self.committed.borrow_mut();
+        let mut peak = self.peak.borrow_mut();
+        let (mut green, mut yellow, mut red) = self.colors.get();
+        while !link::empty(&input) {
+            let mut p = link::receive(&mut input);
+            // Add tokens for time elapsed since last packet arrived
+            let time = arrival(&p);
+            let last = self.last.get().unwrap_or(time);
+            committed.fill(time.saturating_duration_since(last));
+            peak.fill(time.saturating_duration_since(last));
+            self.last.set(Some(last.max(time)));
+            let bits = packet::framed_bitlength(&p, &self.framing);
+            if !peak.conforms(bits) {
+                // Red
+                red += 1;
+                packet::free(p);
+            } else if !committed.conforms(bits) {
+                // Yellow
+                yellow += 1;
+                peak.take(bits);
+                match self.yellow {
+                    Some(dscp) => {
+                        dscp_mark(&mut p, dscp);
+                        link::transmit(&mut output, p);
+                    },
+                    None => packet::free(p)
+                }
+            } else {
+                // Green
+                green += 1;
+                peak.take(bits);
+                committed.take(bits);
+                link::transmit(&mut output, p);
+            }
+        }
+        self.colors.set((green, yellow, red));
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        let (green, yellow, red) = self.colors.get();
+        println!("  {} green, {} yellow, {} red packets", green, yellow, red);
+    }
+}
+
+// Token bucket that holds up to `size' bits (in units of nanobits, i.e.,
+// tokens are added at `rate' per nanosecond)
+// NB: nanobits overflow u64 for buckets larger than ~2.3 GB, hence u128.
+struct TokenBucket {
+    rate: u64, // bits per second (bps)
+    size: u128, // nanobits
+    tokens: u128 // nanobits
+}
+impl TokenBucket {
+    // New (full) token bucket
+    fn new(rate: u64, bytes: u64) -> TokenBucket {
+        let size = bytes as u128 * 8 * 1_000_000_000;
+        TokenBucket { rate, size, tokens: size }
+    }
+    // Add tokens for elapsed time
+    fn fill(&mut self, elapsed: Duration) {
+        let tokens = self.tokens + self.rate as u128 * elapsed.as_nanos();
+        self.tokens = min(tokens, self.size);
+    }
+    // Are there tokens for `bits'?
+    fn conforms(&self, bits: u64) -> bool {
+        bits as u128 * 1_000_000_000 <= self.tokens
+    }
+    // Consume tokens for `bits' (must conform)
+    fn take(&mut self, bits: u64) {
+        self.tokens -= bits as u128 * 1_000_000_000;
+    }
+}
+
+// Set the DSCP of an IP packet, and update its header checksum (IPv6 has
+// none). Returns true if the packet was remarked (i.e., if it is an IP
+// packet).
+// NB: packets with IPv4 options are not remarked (see ecn_mark()).
+pub fn dscp_mark(p: &mut packet::Packet, dscp: u8) -> bool {
+    let eth = header::from_mem::<Ethernet>(&mut p.data);
+    let ip_ofs = header::size_of::<Ethernet>();
+    match eth.ethertype() {
+        ethernet::TYPE_IPV4 => {
+            let mut ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            if ip.ihl() != 5 { return false }
+            ip.set_dscp(dscp);
+            ip.checksum_compute();
+        }
+        ethernet::TYPE_IPV6 =>
+            header::from_mem::<IPv6>(&mut p.data[ip_ofs..]).set_dscp(dscp),
+        _ => return false
+    }
+    true
+}
+
+
+
+// Quota app: switch between links once a quota is exceeded
+//
+// Packets received on the input link are forwarded on the "quota" output
+// until either `bytes' bytes have been delivered, or `time' milliseconds have
+// passed since the first packet was received, and on the "exceeded" output
+// afterwards. E.g., to throttle traffic once a data cap has been reached, or
+// to only allow a burst at the start of a connection. If `reset' is given,
+// the quota is reset (i.e., bytes and time count from zero) every `reset'
+// milliseconds.
+//
+// Packets are delivered by looping them back to the app: packets received
+// on the "quota" and "exceeded" inputs are forwarded on the output link, and
+// count towards the quota. I.e., the "quota" and "exceeded" links are
+// expected to lead through apps that regulate traffic before and after the
+// quota is exceeded, respectively, and back to the app.
+
+#[derive(Clone,Debug)]
+pub struct Quota {
+    pub bytes: Option<u64>, // bytes within quota
+    pub time: Option<u64>, // milliseconds within quota
+    pub reset: Option<u64> // milliseconds between resets
+}
+impl engine::AppConfig for Quota {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!(self.reset != Some(0), "Reset period must be positive");
+        Box::new(QuotaApp {
+            bytes: self.bytes,
+            time: self.time.map(Duration::from_millis),
+            reset: self.reset.map(Duration::from_millis),
+            start: Cell::new(None),
+            delivered: Cell::new(0),
+            resets: Cell::new(0)
+        })
+    }
+}
+pub struct QuotaApp {
+    bytes: Option<u64>,
+    time: Option<Duration>,
+    reset: Option<Duration>,
+    start: Cell<Option<Instant>>, // start of current quota period
+    delivered: Cell<u64>, // bytes delivered in current quota period
+    resets: Cell<u64> // total quota resets
+}
+impl engine::App for QuotaApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let now = engine::now();
+        // Quota starts with the first packet received
+        let mut start = match self.start.get() {
+            Some(start) => start,
+            None if !link::empty(&input) => now,
+            None => return
+        };
+        // Reset quota at the start of each period
+        if let Some(reset) = self.reset {
+            let periods = ((now - start).as_nanos() / reset.as_nanos()) as u32;
+            if periods > 0 {
+                start += reset * periods;
+                self.delivered.set(0);
+                self.resets.set(self.resets.get() + periods as u64);
+            }
+        }
+        self.start.set(Some(start));
+        // Deliver packets looped back to us
+        for name in ["quota", "exceeded"] {
+            let mut input = app.input.get(name).unwrap().borrow_mut();
+            while !link::empty(&input) {
+                let p = link::receive(&mut input);
+                self.delivered.set(self.delivered.get() + p.length as u64);
+                link::transmit(&mut output, p);
+            }
+        }
+        // Forward incoming packets depending on whether quota is exceeded
+        let exceeded = self.time.is_some_and(|time| now - start >= time)
+            || self.bytes.is_some_and(|bytes| self.delivered.get() >= bytes);
+        let mut output = match exceeded {
+            false => app.output.get("quota").unwrap().borrow_mut(),
+            true => app.output.get("exceeded").unwrap().borrow_mut()
+        };
+        while !link::empty(&input) {
+            link::transmit(&mut output, link::receive(&mut input));
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        println!("  {} bytes delivered within quota period, {} resets",
+                 self.delivered.get(), self.resets.get());
+    }
+}
+
+
+
+// CoDel app: rate limiter with a CoDel managed queue
+//
+// Like RateLimiter in shaping mode, but packets are additionally dropped from
+// the head of the queue according to the CoDel (Controlled Delay) active
+// queue management algorithm as specified in RFC 8289. I.e., once the sojourn
+// time of packets in the queue has exceeded `target' for at least `interval',
+// CoDel starts dropping packets at an increasing rate until the sojourn time
+// falls below `target' again.
+//
+// If `ecn' is true, ECN capable packets are marked CE instead of being
+// dropped (see ecn_mark).
+
+#[derive(Clone,Debug)]
+pub struct CoDel {
+    pub rate: u64, // bits per second (bps)
+    pub queue: QueueSize, // queue size (packets exceeding it are tail-dropped)
+    pub target: u64, // target sojourn time (us)
+    pub interval: u64, // sliding minimum window (us)
+    pub ecn: bool, // mark ECN capable packets instead of dropping them
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+impl engine::AppConfig for CoDel {
+    fn new(&self) -> Box<dyn engine::App> {
+        let bucket = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
+        Box::new(CoDelApp {
+            bucket: RefCell::new(bucket),
+            queue: RefCell::new(PacketQueue::new(self.queue.bytes(self.rate))),
+            codel: RefCell::new(
+                CoDelState::new(self.target, self.interval, self.ecn)
+            ),
+            tail_drops: Cell::new(0)
+        })
+    }
+}
+pub struct CoDelApp {
+    bucket: RefCell<BitrateBucket>,
+    queue: RefCell<PacketQueue>,
+    codel: RefCell<CoDelState>,
+    tail_drops: Cell<u64>
+}
+impl engine::App for CoDelApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        while !link::empty(&input) {
+            let p = link::receive(&mut input);
+            if queue.fits(&p) {
+                queue.enqueue(engine::now(), p);
+            } else {
+                self.tail_drops.set(self.tail_drops.get() + 1);
+                packet::free(p);
+            }
+        }
+        drop(queue);
+        self.pull(app);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        let mut codel = self.codel.borrow_mut();
+        bucket.replenish();
+        // Dequeue packets (via CoDel) as tokens permit
+        while !queue.empty() && !link::full(&output) {
+            if !bucket.conforms(queue.peek()) { break }
+            match codel.dequeue(&mut queue, engine::now()) {
+                Some((time, p)) => {
+                    if !bucket.conforms(&p) {
+                        // CoDel dropped the head, and the new head
+                        // exceeds the available tokens
+                        queue.requeue(time, p);
+                        break
+                    }
+                    bucket.take(&p);
+                    link::transmit(&mut output, p);
+                },
+                None => break
+            }
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        let codel = self.codel.borrow();
+        println!("  {} tail drops, {} CoDel drops, {} CoDel marks",
+                 self.tail_drops.get(), codel.drops, codel.marks);
+    }
+}
+
+// CoDel state machine (see RFC 8289, section 5)
+struct CoDelState {
+    target: Duration,
+    interval: Duration,
+    first_above_time: Option<Instant>,
+    drop_next: Instant,
+    count: u32,
+    lastcount: u32,
+    dropping: bool,
+    ecn: bool,
+    drops: u64, // total packets dropped by CoDel
+    marks: u64 // total packets marked CE by CoDel
+}
+// Maximum packet size (queues smaller than this are never considered
+// “above target”)
+const CODEL_MTU: usize = 1514;
+impl CoDelState {
+    fn new(target: u64, interval: u64, ecn: bool) -> CoDelState {
+        CoDelState {
+            target: Duration::from_micros(target),
+            interval: Duration::from_micros(interval),
+            first_above_time: None,
+            drop_next: engine::now(),
+            count: 0,
+            lastcount: 0,
+            dropping: false,
+            ecn,
+            drops: 0,
+            marks: 0
+        }
+    }
+
+    // Dequeue packet, and decide whether it is OK to drop it
+    fn dodequeue(&mut self, queue: &mut PacketQueue, now: Instant)
+                 -> (Option<(Instant, Box<packet::Packet>)>, bool) {
+        if queue.empty() {
+            self.first_above_time = None;
+            return (None, false)
+        }
+        let (time, p) = queue.dequeue();
+        let sojourn = now.saturating_duration_since(time);
+        let mut ok_to_drop = false;
+        if sojourn < self.target || queue.bytes <= CODEL_MTU {
+            self.first_above_time = None;
+        } else {
+            match self.first_above_time {
+                None => self.first_above_time = Some(now + self.interval),
+                Some(first_above_time) =>
+                    if now >= first_above_time { ok_to_drop = true; }
+            }
+        }
+        (Some((time, p)), ok_to_drop)
+    }
+
+    // Dequeue next packet that is not dropped by CoDel
+    fn dequeue(&mut self, queue: &mut PacketQueue, now: Instant)
+               -> Option<(Instant, Box<packet::Packet>)> {
+        let (mut entry, mut ok_to_drop) = self.dodequeue(queue, now);
+        if self.dropping {
+            if !ok_to_drop {
+                // Sojourn time below target: leave dropping state
+                self.dropping = false;
+            }
+            while self.dropping && now >= self.drop_next {
+                self.count += 1;
+                if self.mark(&mut entry) {
+                    // Packet was marked instead of dropped: deliver it
+                    self.drop_next = self.control_law(self.drop_next);
+                    break
+                }
+                self.drop(entry.take());
+                let next = self.dodequeue(queue, now);
+                entry = next.0; ok_to_drop = next.1;
+                if !ok_to_drop {
+                    self.dropping = false;
+                } else {
+                    self.drop_next = self.control_law(self.drop_next);
+                }
+            }
+        } else if ok_to_drop {
+            if !self.mark(&mut entry) {
+                self.drop(entry.take());
+                entry = self.dodequeue(queue, now).0;
+            }
+            self.dropping = true;
+            // If we recently left dropping state, resume with a drop rate
+            // close to the previous one
+            let delta = self.count.saturating_sub(self.lastcount);
+            self.count = 1;
+            if delta > 1 &&
+                now.saturating_duration_since(self.drop_next)
+                < 16 * self.interval
+            {
+                self.count = delta;
+            }
+            self.drop_next = self.control_law(now);
+            self.lastcount = self.count;
+        }
+        entry
+    }
+
+    fn control_law(&self, t: Instant) -> Instant {
+        t + self.interval.div_f64((self.count as f64).sqrt())
+    }
+
+    fn drop(&mut self, entry: Option<(Instant, Box<packet::Packet>)>) {
+        if let Some((_, p)) = entry {
+            self.drops += 1;
+            packet::free(p);
+        }
+    }
+
+    // Mark packet CE if ECN is enabled, return true if the packet was marked
+    fn mark(&mut self, entry: &mut Option<(Instant, Box<packet::Packet>)>)
+            -> bool {
+        if let Some((_, p)) = entry {
+            if self.ecn && ecn_mark(p) {
+                self.marks += 1;
+                return true
+            }
+        }
+        false
+    }
+}
+
+
+
+// FqCoDel app: rate limiter with a FQ-CoDel managed queue
+//
+// Implements FlowQueue-CoDel as specified in RFC 8290: packets are hashed by
+// their 5-tuple (see flow::flow_hash) into one of `flows' queues, each of
+// which is managed by its own CoDel instance. Queues are served by deficit
+// round robin (`quantum' bytes per round), with newly active flows being
+// served before old ones. When the total queue size is exceeded, packets are
+// dropped from the head of the longest queue.
+//
+// If `ecn' is true, CoDel marks ECN capable packets instead of dropping them.
+
+#[derive(Clone,Debug)]
+pub struct FqCoDel {
+    pub rate: u64, // bits per second (bps)
+    pub queue: QueueSize, // total size of all queues
+    pub target: u64, // target sojourn time (us)
+    pub interval: u64, // sliding minimum window (us)
+    pub flows: usize, // number of flow queues (must be a power of two)
+    pub quantum: usize, // bytes to dequeue from a flow per round
+    pub ecn: bool, // mark ECN capable packets instead of dropping them
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+impl engine::AppConfig for FqCoDel {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!(self.flows.is_power_of_two(),
+                "Number of flows must be a power of two");
+        assert!(self.quantum > 0, "Quantum must be greater than zero");
+        let bucket = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
+        Box::new(FqCoDelApp {
+            bucket: RefCell::new(bucket),
+            fq: RefCell::new(FlowQueues::new(self))
+        })
+    }
+}
+pub struct FqCoDelApp {
+    bucket: RefCell<BitrateBucket>,
+    fq: RefCell<FlowQueues>
+}
+impl engine::App for FqCoDelApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut fq = self.fq.borrow_mut();
+        while !link::empty(&input) {
+            fq.enqueue(engine::now(), link::receive(&mut input));
+        }
+        drop(fq);
+        self.pull(app);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+        let mut fq = self.fq.borrow_mut();
+        bucket.replenish();
+        // Dequeue packets (via FQ-CoDel) as tokens permit
+        while !link::full(&output) {
+            match fq.dequeue(&bucket, engine::now()) {
+                Some(p) => {
+                    bucket.take(&p);
+                    link::transmit(&mut output, p);
+                },
+                None => break
+            }
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        let fq = self.fq.borrow();
+        let codel_drops: u64 = fq.flows.iter().map(|f| f.codel.drops).sum();
+        let codel_marks: u64 = fq.flows.iter().map(|f| f.codel.marks).sum();
+        println!("  {} overflow drops, {} CoDel drops, {} CoDel marks",
+                 fq.overflow_drops, codel_drops, codel_marks);
+    }
+}
+
+struct FlowQueues {
+    flows: Vec<FqFlow>,
+    new_flows: VecDeque<usize>, // indexes of flows in new list
+    old_flows: VecDeque<usize>, // indexes of flows in old list
+    bytes: usize, // total bytes enqueued
+    limit: usize, // maximum total bytes enqueued
+    quantum: i64,
+    overflow_drops: u64
+}
+struct FqFlow {
+    queue: PacketQueue,
+    codel: CoDelState,
+    deficit: i64,
+    list: FqList // list the flow is currently on
+}
+#[derive(PartialEq)]
+enum FqList { None, New, Old }
+impl FlowQueues {
+    fn new(conf: &FqCoDel) -> FlowQueues {
+        let flows = (0..conf.flows).map(|_| FqFlow {
+            // NB: the overall queue limit is enforced by FlowQueues
+            queue: PacketQueue::new(usize::MAX),
+            codel: CoDelState::new(conf.target, conf.interval, conf.ecn),
+            deficit: 0,
+            list: FqList::None
+        }).collect();
+        FlowQueues {
+            flows,
+            new_flows: VecDeque::new(),
+            old_flows: VecDeque::new(),
+            bytes: 0,
+            limit: conf.queue.bytes(conf.rate),
+            quantum: conf.quantum as i64,
+            overflow_drops: 0
+        }
+    }
+
+    fn enqueue(&mut self, time: Instant, mut p: Box<packet::Packet>) {
+        let index = flow::flow_hash(&mut p) as usize & (self.flows.len() - 1);
+        // Drop from the head of the longest queue(s) until packet fits
+        while self.bytes + p.length as usize > self.limit {
+            let fattest = (0..self.flows.len())
+                .max_by_key(|&i| self.flows[i].queue.bytes).unwrap();
+            if self.flows[fattest].queue.empty() {
+                // Packet exceeds limit by itself
+                self.overflow_drops += 1;
+                packet::free(p);
+                return
+            }
+            let (_, dropped) = self.flows[fattest].queue.dequeue();
+            self.bytes -= dropped.length as usize;
+            self.overflow_drops += 1;
+            packet::free(dropped);
+        }
+        self.bytes += p.length as usize;
+        let flow = &mut self.flows[index];
+        flow.queue.enqueue(time, p);
+        if flow.list == FqList::None {
+            flow.list = FqList::New;
+            flow.deficit = self.quantum;
+            self.new_flows.push_back(index);
+        }
+    }
+
+    // Dequeue next packet if it conforms to bucket
+    fn dequeue(&mut self, bucket: &BitrateBucket, now: Instant)
+               -> Option<Box<packet::Packet>> {
+        loop {
+            let (index, list) = match self.new_flows.front() {
+                Some(&index) => (index, FqList::New),
+                None => match self.old_flows.front() {
+                    Some(&index) => (index, FqList::Old),
+                    None => return None
+                }
+            };
+            let flow = &mut self.flows[index];
+            if flow.deficit <= 0 {
+                // Flow used up its quantum: move to end of old list
+                flow.deficit += self.quantum;
+                self.pop_list(&list);
+                self.flows[index].list = FqList::Old;
+                self.old_flows.push_back(index);
+                continue
+            }
+            if !flow.queue.empty() && !bucket.conforms(flow.queue.peek()) {
+                return None
+            }
+            let before = flow.queue.bytes;
+            let entry = flow.codel.dequeue(&mut flow.queue, now);
+            self.bytes -= before - flow.queue.bytes;
+            match entry {
+                Some((time, p)) => {
+                    if !bucket.conforms(&p) {
+                        self.bytes += p.length as usize;
+                        flow.queue.requeue(time, p);
+                        return None
+                    }
+                    flow.deficit -= p.length as i64;
+                    return Some(p)
+                },
+                None => {
+                    // Flow is empty: a new flow becomes an old flow (to
+                    // prevent starvation of old flows), an old flow becomes
+                    // inactive
+                    self.pop_list(&list);
+                    if list == FqList::New && !self.old_flows.is_empty() {
+                        self.flows[index].list = FqList::Old;
+                        self.old_flows.push_back(index);
+                    } else {
+                        self.flows[index].list = FqList::None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop_list(&mut self, list: &FqList) {
+        match list {
+            FqList::New => self.new_flows.pop_front(),
+            FqList::Old => self.old_flows.pop_front(),
+            FqList::None => panic!("Flow not on a list")
+        };
+    }
+}
+
+
+
+// Red app: rate limiter with a RED managed queue
+//
+// Like RateLimiter in shaping mode, but arriving packets are additionally
+// dropped according to the Random Early Detection algorithm, see
+//   “Random Early Detection Gateways for Congestion Avoidance”
+//   (S. Floyd, V. Jacobson)
+// I.e., RED maintains an exponentially weighted moving average of the queue
+// size (with weight `weight'). While the average is between `min' and `max'
+// packets are dropped with a probability that increases linearly from zero
+// to `max_p' (and with the number of packets since the last drop). Above
+// `max' all arriving packets are dropped.
+//
+// If `ecn' is true, ECN capable packets are marked CE instead of being
+// dropped (see ecn_mark).
+
+#[derive(Clone,Debug)]
+pub struct Red {
+    pub rate: u64, // bits per second (bps)
+    pub queue: QueueSize, // queue size (packets exceeding it are tail-dropped)
+    pub min: QueueSize, // minimum threshold of average queue size
+    pub max: QueueSize, // maximum threshold of average queue size
+    pub max_p: f64, // drop probability at `max' (0.0..1.0)
+    pub weight: f64, // weight of average queue size (0.0..1.0, e.g. 0.002)
+    pub ecn: bool, // mark ECN capable packets instead of dropping them
+    pub seed: Option<u64>, // random seed (see rng())
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+impl engine::AppConfig for Red {
+    fn new(&self) -> Box<dyn engine::App> {
+        assert!((0.0..=1.0).contains(&self.max_p),
+                "Max. probability must be between 0.0 and 1.0");
+        assert!(self.weight > 0.0 && self.weight <= 1.0,
+                "Weight must be greater than 0.0 and at most 1.0");
+        let bucket = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
+        Box::new(RedApp {
+            bucket: RefCell::new(bucket),
+            queue: RefCell::new(PacketQueue::new(self.queue.bytes(self.rate))),
+            red: RefCell::new(RedState::new(self)),
+            rng: RefCell::new(rng(self.seed)),
+            tail_drops: Cell::new(0)
+        })
+    }
+}
+pub struct RedApp {
+    bucket: RefCell<BitrateBucket>,
+    queue: RefCell<PacketQueue>,
+    red: RefCell<RedState>,
+    rng: RefCell<StdRng>,
+    tail_drops: Cell<u64>
+}
+impl engine::App for RedApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        let mut input = app.input.get("input").unwrap().borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        let mut red = self.red.borrow_mut();
+        let mut rng = self.rng.borrow_mut();
+        while !link::empty(&input) {
+            let mut p = link::receive(&mut input);
+            if !red.admit(&mut p, &queue, engine::now(), &mut *rng) {
+                packet::free(p);
+            } else if queue.fits(&p) {
+                queue.enqueue(engine::now(), p);
+            } else {
+                self.tail_drops.set(self.tail_drops.get() + 1);
+                packet::free(p);
+            }
+        }
+        drop(queue); drop(red);
+        self.pull(app);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        bucket.replenish();
+        drain(&mut queue, &mut bucket, &mut output);
+        if queue.empty() {
+            self.red.borrow_mut().idle(engine::now());
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        let red = self.red.borrow();
+        println!("  {} tail drops, {} RED drops, {} RED marks",
+                 self.tail_drops.get(), red.drops, red.marks);
+    }
+}
+
+// RED state (see Floyd & Jacobson, section 4)
+struct RedState {
+    min: f64, // bytes
+    max: f64, // bytes
+    max_p: f64,
+    weight: f64,
+    ecn: bool,
+    avg: f64, // average queue size (bytes)
+    count: i64, // packets since last drop/mark (-1 if below `min')
+    idle_since: Option<Instant>, // time the queue became empty
+    packet_time: f64, // typical transmission time of a packet (seconds)
+    drops: u64, // total packets dropped by RED
+    marks: u64 // total packets marked CE by RED
+}
+// Typical packet size used to decay the average queue size while idle
+const RED_PACKET_SIZE: f64 = 1000.0;
+impl RedState {
+    fn new(conf: &Red) -> RedState {
+        RedState {
+            min: conf.min.bytes(conf.rate) as f64,
+            max: conf.max.bytes(conf.rate) as f64,
+            max_p: conf.max_p,
+            weight: conf.weight,
+            ecn: conf.ecn,
+            avg: 0.0,
+            count: -1,
+            idle_since: None,
+            packet_time: RED_PACKET_SIZE * 8.0 / conf.rate.max(1) as f64,
+            drops: 0,
+            marks: 0
+        }
+    }
+
+    // Record that the queue is empty
+    fn idle(&mut self, now: Instant) {
+        if self.idle_since.is_none() {
+            self.idle_since = Some(now);
+        }
+    }
+
+    // Update average queue size for arriving packet, and decide whether to
+    // admit the packet to the queue. Returns false if the packet is dropped,
+    // packets might be marked CE instead.
+    fn admit<R: Rng>(&mut self, p: &mut packet::Packet, queue: &PacketQueue,
+                     now: Instant, rng: &mut R) -> bool {
+        match self.idle_since.take() {
+            Some(since) if queue.empty() => {
+                // Decay average as if `m' small packets had been sent while
+                // the queue was idle
+                let idle = now.saturating_duration_since(since);
+                let m = idle.as_secs_f64() / self.packet_time;
+                self.avg *= (1.0 - self.weight).powf(m);
+            },
+            _ => self.avg += self.weight * (queue.bytes as f64 - self.avg)
+        }
+        let congested = if self.avg < self.min {
+            self.count = -1;
+            false
+        } else if self.avg < self.max {
+            self.count += 1;
+            let pb = self.max_p * (self.avg - self.min) / (self.max - self.min);
+            let pa = if self.count as f64 * pb < 1.0 {
+                pb / (1.0 - self.count as f64 * pb)
+            } else {
+                1.0
+            };
+            rng.gen::<f64>() < pa
+        } else {
+            true
+        };
+        if !congested {
+            true
+        } else if self.ecn && ecn_mark(p) {
+            self.count = 0;
+            self.marks += 1;
+            true
+        } else {
+            self.count = 0;
+            self.drops += 1;
+            false
+        }
+    }
+}
+
+// Set the ECN codepoint of an ECN capable (ECT) IP packet to Congestion
+// Experienced (CE), and update its header checksum (IPv6 has none). Returns
+// true if the packet is marked CE (i.e., if it was ECN capable).
+// NB: packets with IPv4 options are treated as not ECN capable.
+pub fn ecn_mark(p: &mut packet::Packet) -> bool {
+    let eth = header::from_mem::<Ethernet>(&mut p.data);
+    let ip_ofs = header::size_of::<Ethernet>();
+    match eth.ethertype() {
+        ethernet::TYPE_IPV4 => {
+            let mut ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            if ip.ihl() != 5 { return false }
+            match ip.ecn() {
+                ipv4::ECN_NOT_ECT => false,
+                ipv4::ECN_CE => true,
+                _ => {
+                    ip.set_ecn(ipv4::ECN_CE);
+                    ip.checksum_compute();
+                    true
+                }
+            }
+        }
+        ethernet::TYPE_IPV6 => {
+            let mut ip = header::from_mem::<IPv6>(&mut p.data[ip_ofs..]);
+            match ip.ecn() {
+                ipv4::ECN_NOT_ECT => false,
+                _ => {
+                    ip.set_ecn(ipv4::ECN_CE);
+                    true
+                }
+            }
+        }
+        _ => false
+    }
+}
+
+
+
+// Htb app: hierarchical rate limiter for classes sharing a parent link
+//
+// Each class has an input and an output link of the same name, and its own
+// queue. The combined output of all classes is limited to `rate' (the rate
+// of the parent link). Like with Linux’s HTB queueing discipline, classes
+// are guaranteed their assured `rate', and can borrow unused capacity from
+// the parent up to their `ceil' rate. Capacity is first given to classes
+// within their assured rate, and then lent to borrowing classes in order of
+// `priority' (lower values take precedence). Classes of equal priority are
+// served in round robin order.
+
+#[derive(Clone,Debug)]
+pub struct Htb {
+    pub rate: u64, // rate of parent link (bps)
+    pub classes: Vec<HtbClass>,
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+#[derive(Clone,Debug)]
+pub struct HtbClass {
+    pub name: String, // name of input and output links
+    pub rate: u64, // assured rate (bps)
+    pub ceil: u64, // maximum rate including borrowed capacity (bps)
+    pub priority: u8, // borrowing priority (lower values take precedence)
+    pub queue: QueueSize // queue size (packets exceeding it are tail-dropped)
+}
+impl engine::AppConfig for Htb {
+    fn new(&self) -> Box<dyn engine::App> {
+        let classes = self.classes.iter().map(|class| {
+            assert!(class.rate <= class.ceil,
+                    "Class rate must not exceed ceil");
+            HtbClassState {
+                name: class.name.clone(),
+                priority: class.priority,
+                assured: BitrateBucket::new(class.rate).framed(self.framing),
+                ceil: BitrateBucket::new(class.ceil).framed(self.framing),
+                queue: PacketQueue::new(class.queue.bytes(class.ceil)),
+                packets: 0,
+                borrowed: 0,
+                drops: 0
+            }
+        }).collect();
+        let parent = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
+        Box::new(HtbApp {
+            parent: RefCell::new(parent),
+            classes: RefCell::new(classes),
+            last: Cell::new(0)
+        })
+    }
+}
+pub struct HtbApp {
+    parent: RefCell<BitrateBucket>,
+    classes: RefCell<Vec<HtbClassState>>,
+    last: Cell<usize> // index of class served last
+}
+struct HtbClassState {
+    name: String,
+    priority: u8,
+    assured: BitrateBucket,
+    ceil: BitrateBucket,
+    queue: PacketQueue,
+    packets: u64, // total packets forwarded
+    borrowed: u64, // packets forwarded using borrowed capacity
+    drops: u64 // packets tail-dropped
+}
+impl engine::App for HtbApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        for class in self.classes.borrow_mut().iter_mut() {
+            if let Some(input) = app.input.get(&class.name) {
+                let mut input = input.borrow_mut();
+                while !link::empty(&input) {
+                    let p = link::receive(&mut input);
+                    if class.queue.fits(&p) {
+                        class.queue.enqueue(engine::now(), p);
+                    } else {
+                        class.drops += 1;
+                        packet::free(p);
+                    }
+                }
+            }
+        }
+        self.pull(app);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut parent = self.parent.borrow_mut();
+        let mut classes = self.classes.borrow_mut();
+        parent.replenish();
+        for class in classes.iter_mut() {
+            class.assured.replenish();
+            class.ceil.replenish();
+        }
+        // Serve classes within their assured rate first, then lend
+        // remaining capacity to borrowing classes
+        loop {
+            let (index, borrow) =
+                match self.select(&classes, &parent, app, false) {
+                    Some(index) => (index, false),
+                    None => match self.select(&classes, &parent, app, true) {
+                        Some(index) => (index, true),
+                        None => break
+                    }
+                };
+            let class = &mut classes[index];
+            let (_, p) = class.queue.dequeue();
+            parent.take(&p);
+            class.ceil.take(&p);
+            if borrow {
+                class.borrowed += 1;
+            } else {
+                class.assured.take(&p);
+            }
+            class.packets += 1;
+            let mut output = app.output.get(&class.name).unwrap().borrow_mut();
+            link::transmit(&mut output, p);
+            self.last.set(index);
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        for class in self.classes.borrow().iter() {
+            println!("  {}: {} packets ({} borrowed), {} tail drops",
+                     class.name, class.packets, class.borrowed, class.drops);
+        }
+    }
+}
+impl HtbApp {
+    // Select class to serve next: the class of highest priority that can
+    // send its next packet (within its assured rate unless `borrow' is true).
+    // Ties are broken in round robin order.
+    fn select(&self, classes: &[HtbClassState], parent: &BitrateBucket,
+              app: &engine::AppState, borrow: bool) -> Option<usize> {
+        let n = classes.len();
+        let mut selected: Option<(u8, usize)> = None;
+        for offset in 1..=n {
+            let index = (self.last.get() + offset) % n;
+            let class = &classes[index];
+            if borrow {
+                if let Some((priority, _)) = selected {
+                    if class.priority >= priority { continue }
+                }
+            } else if selected.is_some() {
+                break
+            }
+            if class.queue.empty() { continue }
+            let p = class.queue.peek();
+            if !parent.conforms(p) || !class.ceil.conforms(p) { continue }
+            if !borrow && !class.assured.conforms(p) { continue }
+            match app.output.get(&class.name) {
+                Some(output) if !link::full(&output.borrow()) => (),
+                _ => continue
+            }
+            selected = Some((class.priority, index));
+        }
+        selected.map(|(_, index)| index)
+    }
+}
+
+
+
+// Scheduler app: rate limiter that serves multiple inputs by priority
+//
+// Each class has an input link of the same name, and its own queue. Queued
+// packets are forwarded on the "output" link at up to `rate'. Classes of
+// higher priority (lower values) are served strictly before classes of lower
+// priority. Classes of equal priority share capacity according to their
+// `weight' using deficit round robin, i.e., each round a class may send
+// `weight' * SCHEDULER_QUANTUM bytes.
+
+#[derive(Clone,Debug)]
+pub struct Scheduler {
+    pub rate: u64, // bits per second (bps)
+    pub classes: Vec<SchedulerClass>,
+    pub framing: packet::Framing, // link-layer framing
+    pub schedule: Option<Schedule> // schedule of `rate'
+}
+#[derive(Clone,Debug)]
+pub struct SchedulerClass {
+    pub name: String, // name of input link
+    pub priority: u8, // strict priority (lower values take precedence)
+    pub weight: u32, // share of capacity among classes of equal priority
+    pub queue: QueueSize // queue size (packets exceeding it are tail-dropped)
+}
+impl engine::AppConfig for Scheduler {
+    fn new(&self) -> Box<dyn engine::App> {
+        let bucket = BitrateBucket::scheduled(self.rate, &self.schedule)
+            .framed(self.framing);
+        Box::new(SchedulerApp {
+            bucket: RefCell::new(bucket),
+            state: RefCell::new(SchedulerState::new(self))
+        })
+    }
+}
+pub struct SchedulerApp {
+    bucket: RefCell<BitrateBucket>,
+    state: RefCell<SchedulerState>
+}
+struct SchedulerState {
+    classes: Vec<SchedulerClassState>,
+    levels: Vec<SchedulerLevel> // in order of priority
+}
+struct SchedulerClassState {
+    name: String,
+    quantum: i64, // bytes per round
+    deficit: i64,
+    queue: PacketQueue,
+    packets: u64, // total packets forwarded
+    drops: u64 // packets tail-dropped
+}
+struct SchedulerLevel {
+    priority: u8,
+    classes: Vec<usize>, // indexes of classes of this priority
+    current: usize, // class currently served (index into `classes')
+    visited: bool // has the current class received its quantum?
+}
+// Bytes per round for classes of weight one
+const SCHEDULER_QUANTUM: i64 = 1514;
+impl engine::App for SchedulerApp {
+    fn has_push(&self) -> bool { true }
+    fn push(&self, app: &engine::AppState) {
+        for class in self.state.borrow_mut().classes.iter_mut() {
+            if let Some(input) = app.input.get(&class.name) {
+                let mut input = input.borrow_mut();
+                while !link::empty(&input) {
+                    let p = link::receive(&mut input);
+                    if class.queue.fits(&p) {
+                        class.queue.enqueue(engine::now(), p);
+                    } else {
+                        class.drops += 1;
+                        packet::free(p);
+                    }
+                }
+            }
+        }
+        self.pull(app);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut bucket = self.bucket.borrow_mut();
+        let mut state = self.state.borrow_mut();
+        bucket.replenish();
+        while !link::full(&output) {
+            match state.dequeue(&bucket) {
+                Some(p) => {
+                    bucket.take(&p);
+                    link::transmit(&mut output, p);
+                },
+                None => break
+            }
+        }
+    }
+    fn has_report(&self) -> bool { true }
+    fn report(&self) {
+        for class in self.state.borrow().classes.iter() {
+            println!("  {}: {} packets, {} tail drops",
+                     class.name, class.packets, class.drops);
+        }
+    }
+}
+impl SchedulerState {
+    fn new(conf: &Scheduler) -> SchedulerState {
+        let mut levels: Vec<SchedulerLevel> = Vec::new();
+        let mut classes = Vec::new();
+        for (index, class) in conf.classes.iter().enumerate() {
+            assert!(class.weight > 0, "Weight must be greater than zero");
+            classes.push(SchedulerClassState {
+                name: class.name.clone(),
+                quantum: class.weight as i64 * SCHEDULER_QUANTUM,
+                deficit: 0,
+                queue: PacketQueue::new(class.queue.bytes(conf.rate)),
+                packets: 0,
+                drops: 0
+            });
+            match levels.iter_mut().find(|l| l.priority == class.priority) {
+                Some(level) => level.classes.push(index),
+                None => levels.push(SchedulerLevel {
+                    priority: class.priority,
+                    classes: vec![index],
+                    current: 0,
+                    visited: false
+                })
+            }
+        }
+        levels.sort_by_key(|level| level.priority);
+        SchedulerState {classes, levels}
+    }
+
+    // Dequeue next packet if it conforms to bucket
+    fn dequeue(&mut self, bucket: &BitrateBucket)
+               -> Option<Box<packet::Packet>> {
+        let classes = &mut self.classes;
+        // Serve the first level (in order of priority) with queued packets
+        let level = self.levels.iter_mut().find(|level| {
+            level.classes.iter().any(|&index| !classes[index].queue.empty())
+        })?;
+        // Deficit round robin among classes of level
+        loop {
+            let class = &mut classes[level.classes[level.current]];
+            if class.queue.empty() {
+                class.deficit = 0;
+            } else {
+                if !level.visited {
+                    class.deficit += class.quantum;
+                    level.visited = true;
+                }
+                let p = class.queue.peek();
+                if p.length as i64 <= class.deficit {
+                    if !bucket.conforms(p) { return None }
+                    class.deficit -= p.length as i64;
+                    class.packets += 1;
+                    return Some(class.queue.dequeue().1)
+                }
+            }
+            level.current = (level.current + 1) % level.classes.len();
+            level.visited = false;
+        }
+    }
+}
+
+
+
+// Trace app: emulate link from a packet-delivery trace (like mm-link)
+//
+// A trace is a list of delivery opportunities as used by Mahimahi’s mm-link:
+// each opportunity is a time in milliseconds (relative to when the app first
+// processed packets) at which up to TRACE_MTU bytes can be delivered. Times
+// must be in non-decreasing order, and multiple opportunities can share the
+// same time. The trace repeats after its last opportunity.
+//
+// Packets are queued in a FIFO of the given size (tail-drop), and the packet
+// at the head of the queue is delivered once the opportunities since its
+// arrival add up to its length (packets larger than TRACE_MTU span multiple
+// opportunities). Opportunities not used when they occur are lost.
+//
+// Packet lengths include the overhead of the link-layer `framing' (see
+// packet::Framing), and each opportunity delivers as many bytes as a TRACE_MTU
+// sized Ethernet frame takes up on-the-wire.
+
+#[derive(Clone,Debug)]
+pub struct Trace {
+    pub opportunities: Vec<u64>, // ms
+    pub queue: QueueSize, // Millis are relative to the average rate of trace
+    pub framing: packet::Framing // link-layer framing
 }
-struct BitrateBucket {
-    capacity: u64,
-    tokens: u64,
-    last_time: Option<Instant>
+// Bytes per delivery opportunity (Mahimahi’s 1500 byte MTU, plus Ethernet
+// header)
+pub const TRACE_MTU: usize = 1514;
+impl Trace {
+    // Average rate of trace in bits per second
+    pub fn rate(&self) -> u64 {
+        match self.opportunities.last() {
+            Some(&period) if period > 0 =>
+                self.opportunities.len() as u64 * TRACE_MTU as u64 * 8
+                    * 1000 / period,
+            _ => 0
+        }
+    }
 }
-impl engine::App for RateLimiterApp {
+impl engine::AppConfig for Trace {
+    fn new(&self) -> Box<dyn engine::App> {
+        let period = *self.opportunities.last()
+            .expect("Trace has no delivery opportunities");
+        assert!(period > 0, "Trace must end after 0 ms");
+        Box::new(TraceApp {
+            opportunities: self.opportunities.clone(),
+            period,
+            start: Cell::new(None),
+            next: Cell::new(0),
+            offset: Cell::new(0),
+            sent: Cell::new(0),
+            queue: RefCell::new(PacketQueue::new(self.queue.bytes(self.rate()))),
+            framing: self.framing
+        })
+    }
+}
+pub struct TraceApp {
+    opportunities: Vec<u64>,
+    period: u64, // ms
+    start: Cell<Option<Instant>>,
+    next: Cell<usize>, // index of next opportunity
+    offset: Cell<u64>, // ms (start of current repetition of trace)
+    sent: Cell<usize>, // bytes of packet at head of queue delivered so far
+    queue: RefCell<PacketQueue>,
+    framing: packet::Framing
+}
+impl engine::App for TraceApp {
     fn has_push(&self) -> bool { true }
     fn push(&self, app: &engine::AppState) {
         let mut input = app.input.get("input").unwrap().borrow_mut();
         let mut output = app.output.get("output").unwrap().borrow_mut();
-        let mut bucket = self.bucket.borrow_mut();
-
-        // Replenish bucket tokens (once every tick at most)
-        let now = engine::now();
-        if let Some(last_time) = bucket.last_time {
-            let us_elapsed = (now - last_time).as_micros() as u64;
-            if us_elapsed >= self.tick {
-                bucket.last_time = Some(engine::now());
-                bucket.tokens = min(
-                    bucket.tokens + (self.rate * us_elapsed),
-                    bucket.capacity
-                );
-            }
-        } else {
-            bucket.last_time = Some(engine::now());
-        }
-
-        // Forward packets, consuming bucket tokens
+        let mut queue = self.queue.borrow_mut();
+        // Enqueue packets (drop packets that would overflow the queue)
         while !link::empty(&input) {
             let p = link::receive(&mut input);
-            let tokens = packet::bitlength(&p) * self.scale;
-            if tokens <= bucket.tokens {
-                bucket.tokens -= tokens;
-                link::transmit(&mut output, p);
+            if queue.fits(&p) {
+                queue.enqueue(engine::now(), p);
             } else {
-                // Out of tokens: drop packet
                 packet::free(p);
             }
         }
+        self.deliver(&mut queue, &mut output);
+    }
+    fn has_pull(&self) -> bool { true }
+    fn pull(&self, app: &engine::AppState) {
+        let mut output = app.output.get("output").unwrap().borrow_mut();
+        let mut queue = self.queue.borrow_mut();
+        self.deliver(&mut queue, &mut output);
+    }
+}
+impl TraceApp {
+    // Use delivery opportunities up to now to forward queued packets
+    fn deliver(&self, queue: &mut PacketQueue, output: &mut link::Link) {
+        let now = engine::now();
+        if self.start.get().is_none() { self.start.set(Some(now)) }
+        let elapsed = now.saturating_duration_since(self.start.get().unwrap())
+            .as_millis() as u64;
+        let (mut next, mut offset) = (self.next.get(), self.offset.get());
+        let mut sent = self.sent.get();
+        while offset + self.opportunities[next] <= elapsed {
+            let mut budget = TRACE_MTU + packet::ETHERNET.overhead as usize;
+            while !queue.empty() && !link::full(output) {
+                let length =
+                    packet::framed_bitlength(queue.peek(), &self.framing) / 8;
+                let remaining = length as usize - sent;
+                if remaining > budget {
+                    sent += budget;
+                    break
+                }
+                budget -= remaining;
+                sent = 0;
+                link::transmit(output, queue.dequeue().1);
+            }
+            next += 1;
+            if next == self.opportunities.len() {
+                next = 0;
+                offset += self.period;
+            }
+        }
+        self.next.set(next);
+        self.offset.set(offset);
+        self.sent.set(sent);
+    }
+}
+
+// Read a Mahimahi packet-delivery trace (e.g., from mahimahi/traces)
+//
+// Each line holds the time of a delivery opportunity in milliseconds. Empty
+// lines are skipped.
+pub fn read_mahimahi_trace(path: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut opportunities = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() { continue }
+        let time = line.trim().parse::<u64>()?;
+        if opportunities.last().is_some_and(|&last| time < last) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Decreasing trace time in {}: {}", path, line)
+            )))
+        }
+        opportunities.push(time);
+    }
+    if opportunities.last().is_none_or(|&last| last == 0) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Trace must end after 0 ms in {}", path)
+        )))
     }
+    Ok(opportunities)
 }
 
 
@@ -324,6 +2884,48 @@ mod selftest {
     use super::*;
     use crate::config;
     use crate::basic_apps;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    // Instantiate app outside of the engine, with new links attached to the
+    // given input and output ports
+    fn app_state<T: engine::AppConfig + Clone + 'static>
+        (conf: &T, inputs: &[&str], outputs: &[&str]) -> engine::AppState
+    {
+        let links = |ports: &[&str]| -> HashMap<String, engine::SharedLink> {
+            ports.iter()
+                .map(|port| (port.to_string(),
+                             Rc::new(RefCell::new(link::new()))))
+                .collect()
+        };
+        engine::AppState {
+            app: engine::AppConfig::new(conf),
+            conf: Box::new(conf.clone()),
+            input: links(inputs),
+            output: links(outputs)
+        }
+    }
+
+    // Receive all packets from link
+    fn drain(link: &engine::SharedLink) -> Vec<Box<packet::Packet>> {
+        let mut link = link.borrow_mut();
+        let mut packets = Vec::new();
+        while !link::empty(&link) {
+            packets.push(link::receive(&mut link));
+        }
+        packets
+    }
+
+    // Transmit packets to app’s input, push app, and return the packets it
+    // forwarded to its output
+    fn run_push(app: &engine::AppState, packets: Vec<Box<packet::Packet>>)
+                -> Vec<Box<packet::Packet>> {
+        for p in packets {
+            link::transmit(&mut app.input["input"].borrow_mut(), p);
+        }
+        app.app.push(app);
+        drain(&app.output["output"])
+    }
 
     #[test]
     fn loss() {
@@ -331,7 +2933,11 @@ mod selftest {
         let mut c = config::new();
         let loss_rate = 0.1;
         config::app(&mut c, "source", &basic_apps::Source {size: 60});
-        config::app(&mut c, "loss", &Loss {ratio: loss_rate});
+        config::app(&mut c, "loss", &Loss {
+            model: LossModel::Uniform(loss_rate),
+            seed: None,
+            schedule: None
+        });
         config::app(&mut c, "sink", &basic_apps::Sink {});
         config::link(&mut c, "source.output -> loss.input");
         config::link(&mut c, "loss.output -> sink.input");
@@ -355,6 +2961,85 @@ mod selftest {
         assert!((loss - loss_rate).abs() < tolerance);
     }
 
+    #[test]
+    fn loss_models() {
+        let mut rng = rng(Some(42));
+        let npackets = 1_000_000;
+        let measure = |model: &LossModel, rng: &mut StdRng| {
+            let (mut state, mut lost, mut bursts) = (1, 0, 0);
+            let mut last_lost = false;
+            for _ in 0..npackets {
+                let is_lost = loss_step(model, &mut state, rng);
+                if is_lost { lost += 1; }
+                if is_lost && !last_lost { bursts += 1; }
+                last_lost = is_lost;
+            }
+            (lost as f64 / npackets as f64, lost as f64 / bursts as f64)
+        };
+        // Gilbert-Elliott: stationary loss = p/(p+r), mean burst = 1/r
+        let (p, r) = (0.01, 0.25);
+        let (loss, burst) = measure(&LossModel::GilbertElliott {
+            p, r, loss_good: 0.0, loss_bad: 1.0
+        }, &mut rng);
+        println!("Gilbert-Elliott: loss={:.4} mean burst={:.2}", loss, burst);
+        assert!((loss - p/(p+r)).abs() < 0.005);
+        assert!((burst - 1.0/r).abs() < 0.2);
+        // Four-state with only gap losses degrades to uniform loss
+        let (loss, burst) = measure(&LossModel::FourState {
+            p13: 0.0, p31: 1.0, p32: 0.0, p23: 1.0, p14: 0.05
+        }, &mut rng);
+        println!("Four-state (isolated): loss={:.4} mean burst={:.2}",
+                 loss, burst);
+        assert!((loss - 0.05/1.05).abs() < 0.005);
+        assert!(burst < 1.1);
+        // Four-state bursts: enter burst with p13, leave with p31
+        let (p13, p31) = (0.01, 0.5);
+        let (loss, burst) = measure(&LossModel::FourState {
+            p13, p31, p32: 0.0, p23: 1.0, p14: 0.0
+        }, &mut rng);
+        println!("Four-state (bursts): loss={:.4} mean burst={:.2}",
+                 loss, burst);
+        assert!((loss - p13/(p13+p31)).abs() < 0.005);
+        assert!((burst - 1.0/p31).abs() < 0.2);
+    }
+
+    #[test]
+    fn loss_seed() {
+        let run = |seed: u64| {
+            engine::configure(&config::new());
+            let mut c = config::new();
+            config::app(&mut c, "source", &basic_apps::Source {size: 60});
+            config::app(&mut c, "loss", &Loss {
+                model: LossModel::GilbertElliott {
+                    p: 0.05, r: 0.5, loss_good: 0.01, loss_bad: 0.9
+                },
+                seed: Some(seed),
+                schedule: None
+            });
+            config::app(&mut c, "sink", &basic_apps::Sink {});
+            config::link(&mut c, "source.output -> loss.input");
+            config::link(&mut c, "loss.output -> sink.input");
+            engine::configure(&c);
+            let breaths = Cell::new(0);
+            engine::main(Some(engine::Options {
+                done: Some(Box::new(move || {
+                    breaths.set(breaths.get() + 1);
+                    breaths.get() >= 100
+                })),
+                no_report: true,
+                ..Default::default()
+            }));
+            let output = engine::state().link_table
+                .get("loss.output -> sink.input").unwrap();
+            let received = output.borrow().rxpackets;
+            received
+        };
+        let (run1, run2, run3) = (run(42), run(42), run(43));
+        println!("seed=42: {} {} seed=43: {}", run1, run2, run3);
+        assert!(run1 == run2);
+        assert!(run1 != run3);
+    }
+
    #[test]
     fn latency() {
         packet::preallocate(10_000);
@@ -362,7 +3047,11 @@ mod selftest {
         let delay = 100; // ms
         let capacity = 3000;
         config::app(&mut c, "source", &basic_apps::Source {size: 60});
-        config::app(&mut c, "latency", &Latency {ms: delay, capacity: capacity});
+        config::app(&mut c, "latency", &Latency {
+            ms: delay,
+            capacity,
+            schedule: None
+        });
         config::app(&mut c, "sink", &basic_apps::Sink {});
         config::link(&mut c, "source.output -> latency.input");
         config::link(&mut c, "latency.output -> sink.input");
@@ -402,6 +3091,295 @@ mod selftest {
         assert!((expected as i64 - sent as i64).abs() < tolerance);
     }
 
+    #[test]
+    fn reorder() {
+        // Every third packet is overtaken by the two packets after it
+        let conf = Reorder {
+            probability: 1.0,
+            correlation: 0.0,
+            gap: 3,
+            distance: 2,
+            timeout: 1000,
+            seed: Some(42)
+        };
+        let app = app_state(&conf, &["input"], &["output"]);
+        let packets = (0..9).map(|seq| {
+            let mut p = packet::allocate();
+            p.data[0] = seq;
+            p.length = 60;
+            p
+        }).collect();
+        let mut order = Vec::new();
+        for p in run_push(&app, packets) {
+            order.push(p.data[0]);
+            packet::free(p);
+        }
+        assert_eq!(order, vec![0, 1, 3, 4, 2, 6, 7, 5]);
+        app.app.report();
+        // Packet 8 is still held back (freed by Drop)
+    }
+
+    #[test]
+    fn duplicate() {
+        // Push packets through Duplicate app, return sequence numbers of
+        // forwarded packets
+        let run = |conf: Duplicate, npackets: usize| {
+            let app = app_state(&conf, &["input"], &["output"]);
+            let mut forwarded = Vec::new();
+            for seq in 0..npackets {
+                let mut p = packet::allocate();
+                p.data[0] = seq as u8;
+                p.length = 60;
+                for p in run_push(&app, vec![p]) {
+                    forwarded.push(p.data[0]);
+                    packet::free(p);
+                }
+            }
+            forwarded
+        };
+        let conf = |probability: f64, delay: u64| Duplicate {
+            probability,
+            correlation: 0.0,
+            delay,
+            capacity: 1000,
+            seed: Some(42)
+        };
+        assert_eq!(run(conf(1.0, 0), 3), vec![0, 0, 1, 1, 2, 2]);
+        // Delayed duplicates are still queued (freed by Drop)
+        assert_eq!(run(conf(1.0, 1000), 3), vec![0, 1, 2]);
+        let npackets = 100_000;
+        let forwarded = run(conf(0.1, 0), npackets).len();
+        let duplicated = (forwarded - npackets) as f64 / npackets as f64;
+        println!("duplicated={:.3}", duplicated);
+        assert!((duplicated - 0.1).abs() < 0.005);
+    }
+
+    #[test]
+    fn corrupt() {
+        use crate::checksum;
+        let ip_ofs = header::size_of::<Ethernet>();
+        let udp_ofs = ip_ofs + header::size_of::<IPv4>();
+        let payload_ofs = udp_ofs + header::size_of::<UDP>();
+        // UDP packet with valid checksums (see udp_packet())
+        let valid = |ethertype: u16| {
+            let mut p = udp_packet(1, 0);
+            let mut ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            ip.set_total_length(p.length - ip_ofs as u16);
+            ip.checksum_compute();
+            let mut udp = header::from_mem::<UDP>(&mut p.data[udp_ofs..]);
+            udp.set_len(p.length - udp_ofs as u16);
+            udp.set_checksum(0xffff);
+            l4_checksum_compute(&mut p);
+            header::from_mem::<Ethernet>(&mut p.data).set_ethertype(ethertype);
+            p
+        };
+        // Are the IPv4 header and UDP checksums of packet correct?
+        let checksums_ok = |p: &mut packet::Packet| {
+            let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+            let length = p.length as usize - udp_ofs;
+            let pseudo_csum = ip.pseudo_checksum(ipv4::PROTOCOL_UDP,
+                                                 length as u16);
+            ip.checksum_ok() &&
+                checksum::ipsum(&p.data[udp_ofs..], length, !pseudo_csum) == 0
+        };
+        // Push a valid packet through Corrupt app, return offsets of flipped
+        // bits and whether its checksums are still correct
+        let run = |mode: CorruptMode, ethertype: u16| {
+            let conf = Corrupt {
+                probability: 1.0,
+                correlation: 0.0,
+                bits: 1,
+                mode,
+                seed: Some(42)
+            };
+            let app = app_state(&conf, &["input"], &["output"]);
+            let original = valid(ethertype);
+            let mut p = run_push(&app, vec![packet::clone(&original)])
+                .pop().unwrap();
+            let flipped: Vec<usize> = (0..p.length as usize * 8)
+                .filter(|bit| (p.data[bit / 8] ^ original.data[bit / 8])
+                              & (1 << (bit % 8)) != 0)
+                .collect();
+            let ok = checksums_ok(&mut p);
+            packet::free(original);
+            packet::free(p);
+            (flipped, ok)
+        };
+        let mut p = valid(ethernet::TYPE_IPV4);
+        assert!(checksums_ok(&mut p));
+        packet::free(p);
+        // Corrupt payload and update UDP checksum
+        let (flipped, ok) = run(CorruptMode::Payload, ethernet::TYPE_IPV4);
+        let payload_bits = |flipped: &[usize]|
+            flipped.iter().filter(|&&bit| bit >= payload_ofs * 8).count();
+        assert!(payload_bits(&flipped) == 1 && ok);
+        // Corrupt payload only
+        let (flipped, ok) = run(CorruptMode::Invalid, ethernet::TYPE_IPV4);
+        assert!(flipped.len() == 1 && payload_bits(&flipped) == 1 && !ok);
+        // Corrupt TTL
+        let ttl = (ip_ofs + 8, 1);
+        let (flipped, ok) = run(CorruptMode::Header(vec![ttl]),
+                                ethernet::TYPE_IPV4);
+        assert!(flipped.len() == 1 && flipped[0] / 8 == ttl.0 && !ok);
+        // Non-IPv4 packets (here: IPv6) have no payload to corrupt
+        let (flipped, _) = run(CorruptMode::Payload, 0x86dd);
+        assert!(flipped.is_empty());
+    }
+
+    #[test]
+    fn serial_link() {
+        let conf = SerialLink {
+            rate: 1_000_000,
+            propagation: 10,
+            framing: packet::ETHERNET,
+            queue: QueueSize::Bytes(2000),
+            capacity: 100
+        };
+        let app = app_state(&conf, &["input"], &["output"]);
+        // Packets (length, arrival in ms) that arrived a while ago
+        let start = Instant::now() - Duration::from_secs(1);
+        let packets = [(1500, 0), (100, 0), (1500, 0), (100, 100)].iter()
+            .map(|&(length, ms)| {
+                let mut p = packet::allocate();
+                p.length = length;
+                p.timestamp = Some(start + Duration::from_millis(ms));
+                p
+            }).collect();
+        // Return departures (us since start) of forwarded packets
+        let mut departures = Vec::new();
+        for p in run_push(&app, packets) {
+            departures.push((p.timestamp.unwrap() - start).as_micros());
+            packet::free(p);
+        }
+        // 1,500 byte packet takes 12.192 ms to transmit (including Ethernet
+        // framing), 100 byte packet takes 0.992 ms, and the second 1,500
+        // byte packet exceeds the queue
+        assert_eq!(departures, vec![22_192, 23_184, 110_992]);
+    }
+
+    #[test]
+    fn policer() {
+        // Push packets (arrival in ms) through Policer app, return DSCPs of
+        // forwarded packets
+        let run = |yellow: Option<u8>, arrivals: &[u64]| {
+            let conf = Policer {
+                cir: 1_000_000,
+                cbs: 3000,
+                pir: 2_000_000,
+                pbs: 6000,
+                yellow,
+                framing: packet::Framing {
+                    overhead: 0, min_length: 0, atm: false
+                }
+            };
+            let app = app_state(&conf, &["input"], &["output"]);
+            let start = Instant::now();
+            let ip_ofs = header::size_of::<Ethernet>();
+            let packets = arrivals.iter().map(|&ms| {
+                let mut p = udp_packet(1, 0); // 1,000 bytes
+                header::from_mem::<IPv4>(&mut p.data[ip_ofs..])
+                    .checksum_compute();
+                p.timestamp = Some(start + Duration::from_millis(ms));
+                p
+            }).collect();
+            let mut forwarded = Vec::new();
+            for mut p in run_push(&app, packets) {
+                let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+                assert!(ip.checksum_ok());
+                forwarded.push(ip.dscp());
+                packet::free(p);
+            }
+            forwarded
+        };
+        // Burst: three green packets (CBS), three yellow packets (PBS), and
+        // red packets. After 4 ms the peak bucket holds 1,000 bytes again,
+        // but the committed bucket only 500 bytes: yellow.
+        let arrivals = [0, 0, 0, 0, 0, 0, 0, 0, 4];
+        assert_eq!(run(None, &arrivals), vec![0, 0, 0]);
+        assert_eq!(run(Some(10), &arrivals), vec![0, 0, 0, 10, 10, 10, 10]);
+        // After 8 ms the committed bucket holds 1,000 bytes as well: green
+        assert_eq!(run(None, &[0, 0, 0, 0, 0, 0, 8]), vec![0, 0, 0, 0]);
+        // Buckets larger than ~2.3 GB do not overflow
+        let mut bucket = TokenBucket::new(1_000_000, 10_000_000_000);
+        assert!(bucket.conforms(80_000_000_000));
+        bucket.take(80_000_000_000);
+        assert!(!bucket.conforms(1));
+    }
+
+    #[test]
+    fn quota() {
+        let quota = |bytes: Option<u64>, time: Option<u64>, reset| {
+            let conf = Quota { bytes, time, reset };
+            app_state(&conf, &["input", "quota", "exceeded"],
+                      &["output", "quota", "exceeded"])
+        };
+        // Push packets of 1,000 bytes, loop them back (i.e., deliver them),
+        // and return how many packets were forwarded within quota and
+        // after the quota was exceeded
+        let run = |app: &engine::AppState, npackets: usize| {
+            let mut forwarded = (0, 0);
+            for _ in 0..npackets {
+                let mut p = packet::allocate();
+                p.length = 1000;
+                for p in run_push(app, vec![p]) {
+                    packet::free(p);
+                }
+                for name in ["quota", "exceeded"] {
+                    for p in drain(&app.output[name]) {
+                        match name {
+                            "quota" => forwarded.0 += 1,
+                            _ => forwarded.1 += 1
+                        }
+                        link::transmit(&mut app.input[name].borrow_mut(), p);
+                    }
+                }
+            }
+            for p in run_push(app, Vec::new()) {
+                packet::free(p);
+            }
+            forwarded
+        };
+        // Packets are within quota until 2,500 bytes have been delivered
+        let app = quota(Some(2500), None, Some(50));
+        assert_eq!(run(&app, 2), (2, 0));
+        assert_eq!(run(&app, 3), (1, 2));
+        // Quota is reset after 50 ms
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(run(&app, 4), (3, 1));
+        // Time quota of 50 ms starts with the first packet, not with the
+        // first push (i.e., the app may be idle before)
+        let app = quota(None, Some(50), None);
+        assert_eq!(run(&app, 0), (0, 0));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(run(&app, 2), (2, 0));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(run(&app, 1), (0, 1));
+    }
+
+    #[test]
+    fn delay_queue() {
+        let mut queue = DelayQueue::new(10);
+        let mut output = link::new();
+        let now = Instant::now();
+        // Packet that arrived 3 ms ago is delayed by 2 ms relative to its
+        // arrival, regardless of when it is enqueued
+        let mut p = packet::allocate();
+        p.timestamp = Some(now - Duration::from_millis(3));
+        let ttx = arrival(&p) + Duration::from_millis(2);
+        queue.enqueue(ttx, p);
+        // Packets are not reordered
+        queue.enqueue(now + Duration::from_secs(60), packet::allocate());
+        queue.enqueue(now, packet::allocate());
+        queue.transmit_ready(&mut output);
+        let p = link::receive(&mut output);
+        assert_eq!(p.timestamp, Some(ttx));
+        packet::free(p);
+        assert!(link::empty(&output));
+        assert_eq!(queue.released, 1);
+        assert!(queue.error_max >= Duration::from_millis(1));
+        queue.report();
+    }
+
     #[test]
     fn ratelimit() {
         packet::preallocate(2000);
@@ -410,7 +3388,12 @@ mod selftest {
         let packet_size = 60;
         let duration_ms = 100;
         config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
-        config::app(&mut c, "limit", &RateLimiter {rate: rate});
+        config::app(&mut c, "limit", &RateLimiter {
+            rate,
+            queue: None,
+            framing: packet::ETHERNET,
+            schedule: None
+        });
         config::app(&mut c, "sink", &basic_apps::Sink {});
         config::link(&mut c, "source.output -> limit.input");
         config::link(&mut c, "limit.output -> sink.input");
@@ -438,6 +3421,58 @@ mod selftest {
         assert!((expected - bits as f64).abs() < tolerance);
     }
 
+    #[test]
+    fn ratelimit_queue() {
+        let rate = 10_000_000; // 10 Mbps
+        let packet_size = 1000;
+        // Send a single burst of packets through the rate limiter and return
+        // the number of packets delivered after 150 ms.
+        let run = |queue: Option<QueueSize>| {
+            engine::configure(&config::new());
+            let mut c = config::new();
+            config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
+            config::app(&mut c, "limit", &RateLimiter {
+                rate,
+                queue,
+                framing: packet::ETHERNET,
+                schedule: None
+            });
+            config::app(&mut c, "sink", &basic_apps::Sink {});
+            config::link(&mut c, "source.output -> limit.input");
+            config::link(&mut c, "limit.output -> sink.input");
+            engine::configure(&c);
+            engine::main(Some(engine::Options {
+                done: Some(Box::new(|| true)), // single breath
+                no_report: true,
+                ..Default::default()
+            }));
+            config::app(&mut c, "source", &basic_apps::Sink {});
+            engine::configure(&c);
+            engine::main(Some(engine::Options {
+                duration: Some(Duration::from_millis(150)),
+                no_report: true,
+                ..Default::default()
+            }));
+            let output = engine::state().link_table
+                .get("limit.output -> sink.input").unwrap();
+            let delivered = output.borrow().txpackets;
+            delivered
+        };
+        let burst = engine::PULL_NPACKETS as u64;
+        // Policer: drops all but the first packets of the burst
+        let policed = run(None);
+        // Shaper: delivers all packets (burst takes ~84 ms at 10 Mbps)
+        let shaped = run(Some(QueueSize::Bytes(burst as usize * 1000)));
+        // Shaper with a 20 ms buffer: tail-drops what does not fit
+        let buffered = run(Some(QueueSize::Millis(20)));
+        let fits = QueueSize::Millis(20).bytes(rate) / packet_size as usize;
+        println!("burst={} policed={} shaped={} buffered={} (fits {})",
+                 burst, policed, shaped, buffered, fits);
+        assert!(policed < 5);
+        assert!(shaped == burst);
+        assert!(buffered >= fits as u64 && buffered <= fits as u64 + 5);
+    }
+
     #[test]
     fn jitter() {
         // This is really just a basic “don’t crash” test
@@ -445,9 +3480,14 @@ mod selftest {
         config::app(&mut c, "source", &basic_apps::Source {size: 60});
         config::app(&mut c, "jitter", &Jitter {
             ms: 10,
+            mean: 0,
+            distribution: Distribution::Uniform,
+            correlation: 0.0,
             strength: 0.1,
             reorder: true,
-            capacity: 10_000
+            capacity: 10_000,
+            seed: None,
+            schedule: None
         });
         config::app(&mut c, "sink", &basic_apps::Sink {});
         config::link(&mut c, "source.output -> jitter.input");
@@ -474,5 +3514,628 @@ mod selftest {
         let received = output.borrow().rxpackets as f64;
         assert!(sent == received);
     }
-}
 
+    #[test]
+    fn jitter_distributions() {
+        let mut rng = rng(Some(42));
+        let nsamples = 1_000_000;
+        let stats = |distribution: &Distribution, correlation: f64,
+                     rng: &mut StdRng| {
+            let last = Cell::new(0.5);
+            let (mut sum, mut sum2, mut lag) = (0.0, 0.0, 0.0);
+            let mut prev = 0.0;
+            for _ in 0..nsamples {
+                let u = correlated(rng.gen::<f64>(), &last, correlation);
+                let x = jitter_value(distribution, u);
+                sum += x; sum2 += x * x; lag += x * prev;
+                prev = x;
+            }
+            let n = nsamples as f64;
+            let mean = sum / n;
+            let var = sum2 / n - mean * mean;
+            let autocorrelation = (lag / n - mean * mean) / var;
+            (mean, var.sqrt(), autocorrelation)
+        };
+        for distribution in &[Distribution::Normal,
+                              Distribution::Pareto,
+                              Distribution::ParetoNormal] {
+            let (mean, stddev, ac) = stats(distribution, 0.0, &mut rng);
+            println!("{:?}: mean={:.3} stddev={:.3} autocorrelation={:.3}",
+                     distribution, mean, stddev, ac);
+            // NB: clamping cuts off the heavy tail of Pareto, reducing the
+            // standard deviation
+            assert!(mean.abs() < 0.05);
+            assert!((stddev - 1.0).abs() < 0.3);
+            assert!(ac.abs() < 0.01);
+        }
+        let (mean, _, ac) = stats(&Distribution::Uniform, 0.0, &mut rng);
+        assert!((mean - 0.5).abs() < 0.01);
+        assert!(ac.abs() < 0.01);
+        let (_, _, ac) = stats(&Distribution::Normal, 0.75, &mut rng);
+        println!("Normal (correlation=0.75): autocorrelation={:.3}", ac);
+        assert!(ac > 0.5);
+    }
+
+    // Write file with contents to the temporary directory, and return its
+    // path (unique to the test process)
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("rush_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn jitter_tables() {
+        let mut rng = rng(Some(42));
+        // netem table approximating a standard normal distribution
+        let mut dist = String::from("# Test table\n");
+        for i in 0..4096 {
+            let x = normal_quantile((i as f64 + 0.5) / 4096.0);
+            dist.push_str(&format!("{} ", (x * NETEM_DIST_SCALE as f64) as i16));
+            if i % 8 == 7 { dist.push('\n'); }
+        }
+        let path = temp_file("jitter_tables.dist", &dist);
+        let table = read_netem_table(&path);
+        let _ = std::fs::remove_file(&path);
+        let table = table.unwrap();
+        let nsamples = 100_000;
+        let (mut sum, mut sum2) = (0.0, 0.0);
+        for _ in 0..nsamples {
+            let x = jitter_value(&table, rng.gen::<f64>());
+            sum += x; sum2 += x * x;
+        }
+        let mean = sum / nsamples as f64;
+        let stddev = (sum2 / nsamples as f64 - mean * mean).sqrt();
+        println!("Table: mean={:.3} stddev={:.3}", mean, stddev);
+        assert!(mean.abs() < 0.05);
+        assert!((stddev - 1.0).abs() < 0.05);
+        // Histogram: 20ms (3/4), 100ms (1/4)
+        let path = temp_file("jitter_tables.csv",
+                             "delay_ms,count\n100,25\n20,75\n");
+        let histogram = read_histogram(&path);
+        let _ = std::fs::remove_file(&path);
+        let histogram = match histogram.unwrap() {
+            Distribution::Histogram(histogram) => histogram,
+            _ => panic!("Expected histogram")
+        };
+        let mut long = 0;
+        for _ in 0..nsamples {
+            let delay = histogram_value(&histogram, rng.gen::<f64>());
+            assert!(delay == 20_000.0 || delay == 100_000.0);
+            if delay == 100_000.0 { long += 1; }
+        }
+        println!("Histogram: {}/{} long delays", long, nsamples);
+        assert!((long as f64 / nsamples as f64 - 0.25).abs() < 0.01);
+        // Invalid files
+        let path = temp_file("jitter_tables.invalid", "1,2,3\n");
+        let histogram = read_histogram(&path);
+        std::fs::write(&path, "# nothing\n").unwrap();
+        let table = read_netem_table(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(histogram.is_err());
+        assert!(table.is_err());
+    }
+
+    // UDP 10.0.0.1:<sport> -> 10.0.0.2:5000 (1000 bytes)
+    fn udp_packet(sport: u8, tos: u8) -> Box<packet::Packet> {
+        let mut p = packet::allocate();
+        let data = [
+            /*Dst MAC*/ 0x52, 0x54, 0x00, 0x02, 0x02, 0x02,
+            /*Src MAC*/ 0x52, 0x54, 0x00, 0x01, 0x01, 0x01,
+            /*Ethertype*/ 0x08, 0x00,
+            /*IPv4 version, IHL*/ 0x45, /*TOS*/ tos,
+            /*Total length*/ 0x03, 0xd6, /*ID*/ 0x00, 0x00,
+            /*Flags, frag. offset*/ 0x40, 0x00, /*TTL*/ 0x40,
+            /*Protocol*/ 0x11, /*Checksum*/ 0x00, 0x00,
+            /*Src addr*/ 10, 0, 0, 1,
+            /*Dst addr*/ 10, 0, 0, 2,
+            /*Src port*/ 0, sport, /*Dst port*/ 0x13, 0x88];
+        p.data[..data.len()].copy_from_slice(&data);
+        p.length = 1000;
+        p
+    }
+
+    #[test]
+    fn codel() {
+        // Simulate a link that forwards one packet per millisecond with
+        // 10% more packets arriving, and return the maximum sojourn time
+        // during the last second.
+        let run = |aqm: bool| {
+            let start = Instant::now();
+            let mut queue = PacketQueue::new(usize::MAX);
+            let mut codel = CoDelState::new(5_000, 100_000, false);
+            let mut max_sojourn = Duration::from_millis(0);
+            for ms in 0..10_000 {
+                let now = start + Duration::from_millis(ms);
+                for _ in 0..(if ms % 10 == 0 { 2 } else { 1 }) {
+                    let mut p = packet::allocate();
+                    p.length = 1000;
+                    queue.enqueue(now, p);
+                }
+                let entry = if aqm {
+                    codel.dequeue(&mut queue, now)
+                } else {
+                    Some(queue.dequeue())
+                };
+                if let Some((time, p)) = entry {
+                    if ms >= 9_000 {
+                        max_sojourn = max_sojourn.max(now - time);
+                    }
+                    packet::free(p);
+                }
+            }
+            (max_sojourn, codel.drops)
+        };
+        let (tail, _) = run(false);
+        let (codel, drops) = run(true);
+        println!("max. sojourn: tail-drop={:?} codel={:?} ({} drops)",
+                 tail, codel, drops);
+        assert!(tail > Duration::from_millis(800));
+        assert!(codel < Duration::from_millis(50));
+        assert!(drops > 0);
+    }
+
+    #[test]
+    fn fq_codel() {
+        let mut fq = FlowQueues::new(&FqCoDel {
+            rate: 10_000_000_000,
+            queue: QueueSize::Bytes(1_000_000),
+            target: 5_000,
+            interval: 100_000,
+            flows: 1024,
+            quantum: 1514,
+            ecn: false,
+            framing: packet::ETHERNET,
+            schedule: None
+        });
+        // Tokens are never taken from bucket: all packets conform
+        let bucket = BitrateBucket::new(10_000_000_000);
+        let (mut bulk, mut sparse) = (udp_packet(1, 0), udp_packet(2, 0));
+        assert!(flow::flow_hash(&mut bulk) & 1023 !=
+                flow::flow_hash(&mut sparse) & 1023);
+        packet::free(bulk); packet::free(sparse);
+        // Forward one packet per millisecond, while a bulk flow sends 20%
+        // more packets than that, and a sparse flow sends one packet every
+        // ten milliseconds.
+        let start = Instant::now();
+        let mut sparse_max = 0;
+        let mut arrivals = VecDeque::new();
+        for ms in 0..5_000u64 {
+            let now = start + Duration::from_millis(ms);
+            for _ in 0..(if ms % 5 == 0 { 2 } else { 1 }) {
+                fq.enqueue(now, udp_packet(1, 0));
+            }
+            if ms % 10 == 0 {
+                fq.enqueue(now, udp_packet(2, 0));
+                arrivals.push_back(ms);
+            }
+            if let Some(p) = fq.dequeue(&bucket, now) {
+                if p.data[35] == 2 {
+                    let delay = ms - arrivals.pop_front().unwrap();
+                    if ms >= 1_000 { sparse_max = sparse_max.max(delay); }
+                }
+                packet::free(p);
+            }
+        }
+        let drops: u64 = fq.flows.iter().map(|f| f.codel.drops).sum();
+        println!("sparse flow max. delay: {} ms ({} drops)", sparse_max, drops);
+        assert!(sparse_max <= 1);
+        assert!(drops > 0);
+        assert!(fq.overflow_drops == 0);
+    }
+
+    #[test]
+    fn ecn() {
+        let ip_ofs = header::size_of::<Ethernet>();
+        // ECT packets are marked CE (and their checksum is updated)
+        let mut p = udp_packet(1, ipv4::ECN_ECT0);
+        assert!(ecn_mark(&mut p));
+        let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+        assert!(ip.ecn() == ipv4::ECN_CE);
+        assert!(ip.checksum_ok());
+        assert!(ecn_mark(&mut p));
+        packet::free(p);
+        // Not-ECT packets are left alone
+        let mut p = udp_packet(1, ipv4::ECN_NOT_ECT);
+        assert!(!ecn_mark(&mut p));
+        let ip = header::from_mem::<IPv4>(&mut p.data[ip_ofs..]);
+        assert!(ip.ecn() == ipv4::ECN_NOT_ECT);
+        packet::free(p);
+        // Likewise for IPv6 packets (and their DSCP can be remarked, too)
+        let ipv6 = |ecn: u8| {
+            let mut p = udp_packet(1, 0);
+            header::from_mem::<Ethernet>(&mut p.data)
+                .set_ethertype(ethernet::TYPE_IPV6);
+            let mut ip = IPv6::new();
+            ip.set_ecn(ecn);
+            ip.copy(&mut p.data[ip_ofs..]);
+            p
+        };
+        let mut p = ipv6(ipv4::ECN_ECT1);
+        assert!(ecn_mark(&mut p) && dscp_mark(&mut p, 46));
+        let ip = header::from_mem::<IPv6>(&mut p.data[ip_ofs..]);
+        assert!(ip.ecn() == ipv4::ECN_CE && ip.dscp() == 46);
+        packet::free(p);
+        let mut p = ipv6(ipv4::ECN_NOT_ECT);
+        assert!(!ecn_mark(&mut p));
+        packet::free(p);
+        // CoDel marks instead of dropping ECT packets
+        let start = Instant::now();
+        let mut queue = PacketQueue::new(usize::MAX);
+        let mut codel = CoDelState::new(5_000, 100_000, true);
+        for ms in 0..1_000 {
+            let now = start + Duration::from_millis(ms);
+            for _ in 0..2 { queue.enqueue(now, udp_packet(1, ipv4::ECN_ECT1)); }
+            if let Some((_, p)) = codel.dequeue(&mut queue, now) {
+                packet::free(p);
+            }
+        }
+        println!("CoDel: {} drops, {} marks", codel.drops, codel.marks);
+        assert!(codel.drops == 0 && codel.marks > 0);
+    }
+
+    #[test]
+    fn red() {
+        // Simulate a link that forwards one packet per millisecond (8 Mbps)
+        // with 20% more packets arriving, and return the maximum queue size
+        // (in packets) during the last second and the RED state.
+        let run = |ecn: bool, tos: u8| {
+            let start = Instant::now();
+            let mut queue = PacketQueue::new(usize::MAX);
+            let mut red = RedState::new(&Red {
+                rate: 8_000_000,
+                queue: QueueSize::Bytes(usize::MAX),
+                min: QueueSize::Millis(5),
+                max: QueueSize::Millis(15),
+                max_p: 0.1,
+                weight: 0.002,
+                ecn,
+                seed: Some(42),
+                framing: packet::ETHERNET,
+                schedule: None
+            });
+            let mut rng = rng(Some(42));
+            let mut max_queue = 0;
+            for ms in 0..10_000 {
+                let now = start + Duration::from_millis(ms);
+                for _ in 0..(if ms % 5 == 0 { 2 } else { 1 }) {
+                    let mut p = udp_packet(1, tos);
+                    if red.admit(&mut p, &queue, now, &mut rng) {
+                        queue.enqueue(now, p);
+                    } else {
+                        packet::free(p);
+                    }
+                }
+                if !queue.empty() {
+                    let (_, p) = queue.dequeue();
+                    packet::free(p);
+                }
+                if queue.empty() { red.idle(now); }
+                if ms >= 9_000 {
+                    max_queue = max_queue.max(queue.bytes / 1000);
+                }
+            }
+            (max_queue, red)
+        };
+        // RED drops packets to keep the average queue size near thresholds
+        let (max_queue, red) = run(false, ipv4::ECN_ECT0);
+        println!("RED: max. queue {} packets, avg. {:.0} bytes, \
+                  {} drops, {} marks",
+                 max_queue, red.avg, red.drops, red.marks);
+        assert!(red.drops > 0 && red.marks == 0);
+        assert!(red.avg > 5_000.0 && red.avg < 20_000.0);
+        assert!(max_queue < 30);
+        // With ECN enabled ECT packets are marked instead
+        let (_, red) = run(true, ipv4::ECN_ECT0);
+        println!("RED (ECN): {} drops, {} marks", red.drops, red.marks);
+        assert!(red.drops == 0 && red.marks > 0);
+        // ...but Not-ECT packets are still dropped
+        let (_, red) = run(true, ipv4::ECN_NOT_ECT);
+        assert!(red.drops > 0 && red.marks == 0);
+    }
+
+    #[test]
+    fn htb() {
+        // 5 Mbps link shared by video (2 Mbps assured, high priority) and
+        // bulk (1 Mbps assured, low priority), and default traffic (2 Mbps).
+        // Returns the rates (Mbps) achieved by each class over 500 ms.
+        let rate = 5_000_000;
+        let packet_size = 1000;
+        let duration_ms = 500;
+        let run = |active: &[&str]| {
+            engine::configure(&config::new());
+            let mut c = config::new();
+            let class = |name: &str, rate, priority| HtbClass {
+                name: name.to_string(),
+                rate,
+                ceil: 5_000_000,
+                priority,
+                queue: QueueSize::Millis(100)
+            };
+            config::app(&mut c, "htb", &Htb {rate, classes: vec![
+                class("video", 2_000_000, 0),
+                class("bulk", 1_000_000, 1),
+                class("default", 2_000_000, 2)
+            ], framing: packet::ETHERNET, schedule: None});
+            config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
+            config::app(&mut c, "sink", &basic_apps::Sink {});
+            for name in active {
+                config::link(&mut c, &format!("source.{} -> htb.{}", name, name));
+            }
+            for name in ["video", "bulk", "default"] {
+                config::link(&mut c, &format!("htb.{} -> sink.{}", name, name));
+            }
+            engine::configure(&c);
+            engine::main(Some(engine::Options {
+                duration: Some(Duration::from_millis(duration_ms)),
+                no_report: true,
+                ..Default::default()
+            }));
+            let mut p = packet::allocate();
+            p.length = packet_size;
+            let bits = packet::bitlength(&p);
+            packet::free(p);
+            let mbps = |name: &str| {
+                let output = engine::state().link_table
+                    .get(&format!("htb.{} -> sink.{}", name, name)).unwrap();
+                let sent = output.borrow().txpackets;
+                (sent * bits) as f64 / (duration_ms as f64 * 1000.0)
+            };
+            (mbps("video"), mbps("bulk"), mbps("default"))
+        };
+        let close = |actual: f64, expected: f64| (actual - expected).abs() < 0.25;
+        // All classes busy: each gets its assured rate
+        let (video, bulk, default) = run(&["video", "bulk", "default"]);
+        println!("video={:.2} bulk={:.2} default={:.2}", video, bulk, default);
+        assert!(close(video, 2.0) && close(bulk, 1.0) && close(default, 2.0));
+        // Default idle: video borrows its capacity first
+        let (video, bulk, default) = run(&["video", "bulk"]);
+        println!("video={:.2} bulk={:.2} default={:.2}", video, bulk, default);
+        assert!(close(video, 4.0) && close(bulk, 1.0) && default == 0.0);
+        // Only bulk busy: it may use the whole link
+        let (video, bulk, default) = run(&["bulk"]);
+        println!("video={:.2} bulk={:.2} default={:.2}", video, bulk, default);
+        assert!(video == 0.0 && close(bulk, 5.0) && default == 0.0);
+    }
+
+    #[test]
+    fn scheduler() {
+        // Audio (high priority), and video and bulk (equal priority, 3:1
+        // weights)
+        let class = |name: &str, priority, weight| SchedulerClass {
+            name: name.to_string(),
+            priority,
+            weight,
+            queue: QueueSize::Millis(100)
+        };
+        let conf = Scheduler {rate: 5_000_000, classes: vec![
+            class("audio", 0, 1),
+            class("video", 1, 3),
+            class("bulk", 1, 1)
+        ], framing: packet::ETHERNET, schedule: None};
+        // Tokens are never taken from bucket: all packets conform
+        let bucket = BitrateBucket::new(10_000_000_000);
+        let mut state = SchedulerState::new(&conf);
+        let now = Instant::now();
+        for (index, npackets) in [(0, 10), (1, 50), (2, 50)] {
+            for _ in 0..npackets {
+                let mut p = packet::allocate();
+                p.length = 1000;
+                p.data[0] = index;
+                state.classes[index as usize].queue.enqueue(now, p);
+            }
+        }
+        let mut served = [0; 3];
+        for n in 0..50 {
+            let p = state.dequeue(&bucket).unwrap();
+            // Audio is served strictly first
+            assert!((n < 10) == (p.data[0] == 0));
+            served[p.data[0] as usize] += 1;
+            packet::free(p);
+        }
+        println!("audio={} video={} bulk={}", served[0], served[1], served[2]);
+        assert!(served[1] >= 28 && served[1] <= 32);
+
+        // Output is limited to `rate'
+        let packet_size = 1000;
+        let duration_ms = 500;
+        let mut c = config::new();
+        config::app(&mut c, "scheduler", &conf);
+        config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.video -> scheduler.video");
+        config::link(&mut c, "source.bulk -> scheduler.bulk");
+        config::link(&mut c, "scheduler.output -> sink.input");
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            duration: Some(Duration::from_millis(duration_ms)),
+            no_report: true,
+            ..Default::default()
+        }));
+        let mut p = packet::allocate();
+        p.length = packet_size;
+        let bits = packet::bitlength(&p);
+        packet::free(p);
+        let output = engine::state().link_table
+            .get("scheduler.output -> sink.input").unwrap();
+        let sent = output.borrow().txpackets;
+        let mbps = (sent * bits) as f64 / (duration_ms as f64 * 1000.0);
+        println!("rate={:.2} Mbps", mbps);
+        assert!((mbps - 5.0).abs() < 0.25);
+    }
+
+    #[test]
+    fn schedule() {
+        let mut schedule = Schedule {
+            keyframes: vec![(100, 1.0), (200, 3.0), (400, 5.0)],
+            interpolation: Interpolation::Step,
+            period: None
+        };
+        assert_eq!(schedule.value(0.0), 1.0); // hold first keyframe
+        assert_eq!(schedule.value(150.0), 1.0);
+        assert_eq!(schedule.value(200.0), 3.0);
+        assert_eq!(schedule.value(1000.0), 5.0); // hold last keyframe
+        schedule.interpolation = Interpolation::Linear;
+        assert_eq!(schedule.value(0.0), 1.0);
+        assert_eq!(schedule.value(150.0), 2.0);
+        assert_eq!(schedule.value(300.0), 4.0);
+        assert_eq!(schedule.value(1000.0), 5.0);
+        // Loop every 500 ms: wraps from 5.0 at 400 ms to 1.0 at 600 ms
+        schedule.period = Some(500);
+        assert_eq!(schedule.value(500.0), 3.0);
+        assert_eq!(schedule.value(650.0), 2.0);
+        assert_eq!(schedule.value(1300.0), 4.0);
+        schedule.interpolation = Interpolation::Step;
+        assert_eq!(schedule.value(550.0), 5.0);
+        assert_eq!(schedule.value(1150.0), 1.0);
+
+        // Rate limiter at 1 Mbps for 50 ms, then at 3 Mbps for 50 ms
+        packet::preallocate(2000);
+        let mut c = config::new();
+        let packet_size = 60;
+        config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
+        config::app(&mut c, "scheduled", &RateLimiter {
+            rate: 1_000_000,
+            queue: None,
+            framing: packet::ETHERNET,
+            schedule: Some(Schedule {
+                keyframes: vec![(0, 1e6), (50, 3e6)],
+                interpolation: Interpolation::Step,
+                period: None
+            })
+        });
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> scheduled.input");
+        config::link(&mut c, "scheduled.output -> sink.input");
+        engine::configure(&c);
+        // Run for 100 ms of schedule time, i.e. since the first breath (when
+        // the schedule starts), and measure the time actually elapsed until
+        // the last breath
+        let first = Rc::new(Cell::new(None));
+        let last = Rc::new(Cell::new(None));
+        let (start, end) = (first.clone(), last.clone());
+        engine::main(Some(engine::Options{
+            done: Some(Box::new(move || {
+                let now = engine::now();
+                if start.get().is_none() { start.set(Some(now)) }
+                end.set(Some(now));
+                now - start.get().unwrap() >= Duration::from_millis(100)
+            })),
+            report_links: true,
+            ..Default::default()
+        }));
+        let elapsed = (last.get().unwrap() - first.get().unwrap())
+            .as_secs_f64();
+        let output = engine::state().app_table
+            .get("scheduled").unwrap()
+            .output.get("output").unwrap();
+        let sent = output.borrow().txpackets;
+        let mut p = packet::allocate();
+        p.length = packet_size;
+        let bits = sent * packet::bitlength(&p);
+        packet::free(p);
+        let expected = 1e6 * 0.05 + 3e6 * (elapsed - 0.05);
+        println!("expected={:.0} received={} elapsed={:.4}",
+                 expected, bits, elapsed);
+        assert!((expected - bits as f64).abs() < expected * 0.03);
+    }
+
+    #[test]
+    fn trace() {
+        // Trace with 11 delivery opportunities per 10 ms
+        let path = temp_file("trace.mahimahi",
+                             "1\n2\n3\n4\n5\n5\n6\n7\n8\n9\n10\n");
+        let opportunities = read_mahimahi_trace(&path);
+        let _ = std::fs::remove_file(&path);
+        let opportunities = opportunities.unwrap();
+        assert_eq!(opportunities.len(), 11);
+        let trace = Trace {
+            opportunities,
+            queue: QueueSize::Millis(100),
+            framing: packet::ETHERNET
+        };
+        assert_eq!(trace.rate(), 11 * TRACE_MTU as u64 * 8 * 100);
+        packet::preallocate(2000);
+        let mut c = config::new();
+        let packet_size = 1000;
+        let duration_ms = 100;
+        config::app(&mut c, "source", &basic_apps::Source {size: packet_size});
+        config::app(&mut c, "trace", &trace);
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> trace.input");
+        config::link(&mut c, "trace.output -> sink.input");
+        engine::configure(&c);
+        engine::main(Some(engine::Options{
+            duration: Some(Duration::from_millis(duration_ms)),
+            report_links: true,
+            ..Default::default()
+        }));
+        let output = engine::state().app_table
+            .get("trace").unwrap()
+            .output.get("output").unwrap();
+        let delivered = output.borrow().txbytes as f64;
+        // Packets span delivery opportunities
+        let expected = (duration_ms * 11 / 10) as f64 * TRACE_MTU as f64;
+        println!("expected={:.0} delivered={:.0}", expected, delivered);
+        assert!((expected - delivered).abs() < expected * 0.03);
+    }
+
+    #[test]
+    fn outage() {
+        let app = |pattern: OutagePattern| OutageApp {
+            pattern,
+            queue: None,
+            start: Cell::new(None),
+            rng: RefCell::new(rng(Some(42))),
+            down: Cell::new(false),
+            transition: Cell::new(0.0),
+            outages: RefCell::new(Vec::new())
+        };
+        let once = app(OutagePattern::Once { start: 100, duration: 50 });
+        assert!(!once.down_at(99.0));
+        assert!(once.down_at(100.0) && once.down_at(149.0));
+        assert!(!once.down_at(150.0) && !once.down_at(1000.0));
+        let periodic = app(OutagePattern::Periodic {
+            start: 10, period: 100, duration: 30
+        });
+        assert!(!periodic.down_at(0.0) && periodic.down_at(10.0));
+        assert!(!periodic.down_at(40.0) && periodic.down_at(139.0));
+        // Random: fraction of time down is mean_down / (mean_up + mean_down)
+        let random = app(OutagePattern::Random { mean_up: 30.0, mean_down: 10.0 });
+        let mut down_ms = 0;
+        for ms in 0..1_000_000 {
+            let down = random.down_at(ms as f64);
+            random.down.set(down);
+            if down { down_ms += 1; }
+        }
+        let fraction = down_ms as f64 / 1_000_000.0;
+        println!("Random: down {:.3} of the time", fraction);
+        assert!((fraction - 0.25).abs() < 0.02);
+
+        // Drop all packets during a 20 ms outage
+        packet::preallocate(2000);
+        let mut c = config::new();
+        config::app(&mut c, "source", &basic_apps::Source {size: 60});
+        config::app(&mut c, "outage", &Outage {
+            pattern: OutagePattern::Once { start: 20, duration: 20 },
+            hold: None,
+            seed: None
+        });
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> outage.input");
+        config::link(&mut c, "outage.output -> sink.input");
+        engine::configure(&c);
+        engine::main(Some(engine::Options{
+            duration: Some(Duration::from_millis(60)),
+            report_apps: true,
+            ..Default::default()
+        }));
+        let app = engine::state().app_table.get("outage").unwrap();
+        let input = app.input.get("input").unwrap().borrow().rxpackets;
+        let output = app.output.get("output").unwrap().borrow().txpackets;
+        let lost = (input - output) as f64 / input as f64;
+        println!("lost={:.3}", lost);
+        assert!(lost > 0.2 && lost < 0.5);
+    }
+}
@@ -7,8 +7,12 @@ use std::cell::RefCell;
 use std::ffi;
 use std::mem;
 use std::ptr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // RAW socket app: interface with Linux network devices
+//
+// Received packets are stamped with their kernel receive timestamps (see
+// packet::Packet).
 
 #[derive(Clone,Debug)]
 pub struct RawSocket { pub ifname: String }
@@ -31,9 +35,10 @@ impl engine::App for RawSocketApp {
             let mut output = output.borrow_mut();
             let mut limit = engine::PULL_NPACKETS;
             let mut fdset = self.fdset.borrow_mut();
+            let now = (Instant::now(), SystemTime::now());
             while limit > 0 && can_receive(self.sock, &mut fdset) {
                 limit -= 1;
-                link::transmit(&mut output, receive(self.sock));
+                link::transmit(&mut output, receive(self.sock, now));
             }
         }
     }
@@ -76,6 +81,14 @@ fn open_raw_socket(ifname: &str) -> i32 {
             libc::close(sock);
             panic!("cannot bind to interface");
         }
+        // Request receive timestamps (SCM_TIMESTAMPNS control messages)
+        let enable: libc::c_int = 1;
+        if libc::setsockopt(sock, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS,
+                            &enable as *const libc::c_int as *const ffi::c_void,
+                            mem::size_of::<libc::c_int>() as u32) == -1 {
+            libc::close(sock);
+            panic!("cannot enable receive timestamps");
+        }
     }
     sock
 }
@@ -99,16 +112,52 @@ fn can_receive (sock: i32, fdset: &mut FdSet) -> bool {
     ret == 1
 }
 
-fn receive (sock: i32) -> Box<packet::Packet> {
+// Receive packet, `now' is a pair of monotonic and real time taken at the
+// same moment (used to convert the kernel receive timestamp)
+fn receive (sock: i32, now: (Instant, SystemTime)) -> Box<packet::Packet> {
     let mut p = packet::allocate();
-    let read = unsafe {
-        libc::read(sock, cptr(&mut p.data), packet::PAYLOAD_SIZE)
+    let mut iov = libc::iovec {
+        iov_base: cptr(&mut p.data),
+        iov_len: packet::PAYLOAD_SIZE
     };
-    assert!(read > 0, "cannot read(2) packet");
+    let mut control = [0u64; 8]; // aligned for cmsghdr
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cptr(&mut control);
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let read = unsafe { libc::recvmsg(sock, &mut msg, 0) };
+    assert!(read > 0, "cannot recvmsg(2) packet");
     p.length = read as u16;
+    p.timestamp = Some(receive_timestamp(&msg, now));
     p
 }
 
+// Return kernel receive timestamp of message as an Instant (falls back to
+// `now' if the message carries no timestamp)
+fn receive_timestamp (msg: &libc::msghdr, now: (Instant, SystemTime))
+                      -> Instant {
+    let (instant, system) = now;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET
+            && header.cmsg_type == libc::SCM_TIMESTAMPNS
+        {
+            let ts = unsafe {
+                ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec)
+            };
+            let received = UNIX_EPOCH
+                + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+            // Kernel timestamps are CLOCK_REALTIME: convert via packet age
+            let age = system.duration_since(received).unwrap_or_default();
+            return instant.checked_sub(age).unwrap_or(instant)
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    instant
+}
+
 fn can_transmit (sock: i32, fdset: &mut FdSet) -> bool {
     let fdmax = sock + 1;
     let readfds = ptr::null_mut();
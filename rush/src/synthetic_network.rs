@@ -1,10 +1,14 @@
 use super::engine;
 use super::config;
-use super::basic_apps;
 use super::rawsocket_app;
 use super::qos;
 use super::offload;
 use super::flow;
+use super::filter;
+use super::packet;
+use super::ipv4;
+use super::ipv6;
+use super::tcp;
 
 use std::env;
 use std::process;
@@ -14,6 +18,10 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io;
 use std::collections::HashSet;
+use std::collections::HashMap;
+use std::cmp::min;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 use regex::Regex;
 use once_cell::sync::Lazy;
@@ -75,7 +83,8 @@ fn print_usage(exe: &str) {
                 latency: 0,
                 jitter: 0,
                 jitter_strength: 0.0,
-                reorder_packets: false
+                reorder_packets: false,
+                ..Default::default()
             },
             egress: QoS {
                 rate: 1_000_000,
@@ -83,18 +92,19 @@ fn print_usage(exe: &str) {
                 latency: 0,
                 jitter: 0,
                 jitter_strength: 0.0,
-                reorder_packets: false
+                reorder_packets: false,
+                ..Default::default()
             }
         },
         flows: vec![
             SyntheticFlow {
                 label: "http".to_string(),
-                flow: Flow {
-                    ip: 0,
+                flow: Some(Flow {
                     protocol: 6,
-                    port_min: 80,
-                    port_max: 80
-                },
+                    src_ports: Some("80".to_string()),
+                    ..Default::default()
+                }),
+                filter: None,
                 link: SyntheticLink {
                     ingress: QoS {
                         rate: 100_000_000,
@@ -102,7 +112,8 @@ fn print_usage(exe: &str) {
                         latency: 0,
                         jitter: 0,
                         jitter_strength: 0.0,
-                        reorder_packets: false
+                        reorder_packets: false,
+                        ..Default::default()
                     },
                     egress: QoS {
                         rate: 100_000_000,
@@ -110,11 +121,14 @@ fn print_usage(exe: &str) {
                         latency: 0,
                         jitter: 0,
                         jitter_strength: 0.0,
-                        reorder_packets: false
+                        reorder_packets: false,
+                        ..Default::default()
                     }
                 }
             }
-        ]
+        ],
+        seed: None,
+        hierarchical: false
     };
     println!("Example config for <specpath>: {}",
              serde_json::to_string(&spec).unwrap());
@@ -144,23 +158,26 @@ fn configure_synthetic_network
     let inner_tx = format!("{}.input", inner_ifname);
     
     let outer_top = format!("{}_top", outer_ifname);
+    let outer_top_default = format!("{}.default", outer_top);
     configure_top(config, &outer_top, &inner_tx,
-                  ingress_profile, flow::Dir::Src);
+                  ingress_profile, spec, flow::Dir::Src);
 
     let outer_split = format!("{}_split", outer_ifname);
     let outer_split_default = format!("{}.default", outer_split);
     configure_split(config, &outer_split, &outer_rx,
                     &spec.flows, flow::Dir::Src);
 
-    let inner_join = format!("{}_join", inner_ifname);
-    let inner_join_default = format!("{}.default", inner_join);
-    configure_join(config, &inner_join, &outer_top);
-
-    configure_qos(config, "ingress", &outer_split_default, &inner_join_default,
-                  &spec.default_link.ingress);
+    if spec.hierarchical {
+        configure_htb(config, "ingress", &outer_split, &outer_top,
+                      spec, flow::Dir::Src);
+    } else {
+        configure_qos(config, "ingress",
+                      &outer_split_default, &outer_top_default,
+                      &spec.default_link.ingress, spec.seed);
 
-    configure_flows(config, &outer_split, &inner_join,
-                    &spec.flows, flow::Dir::Src);
+        configure_flows(config, &outer_split, &outer_top,
+                        &spec.flows, flow::Dir::Src, spec.seed);
+    }
 
     // Egress path: inner → outer
 
@@ -174,23 +191,26 @@ fn configure_synthetic_network
     let outer_tx = format!("{}.input", outer_ifname);
     
     let inner_top = format!("{}_top", inner_ifname);
+    let inner_top_default = format!("{}.default", inner_top);
     configure_top(config, &inner_top, &outer_tx,
-                  egress_profile, flow::Dir::Dst);
+                  egress_profile, spec, flow::Dir::Dst);
 
     let inner_split = format!("{}_split", inner_ifname);
     let inner_split_default = format!("{}.default", inner_split);
     configure_split(config, &inner_split, &inner_rx,
                     &spec.flows, flow::Dir::Dst);
 
-    let outer_join = format!("{}_join", outer_ifname);
-    let outer_join_default = format!("{}.default", outer_join);
-    configure_join(config, &outer_join, &inner_top);
-
-    configure_qos(config, "egress", &inner_split_default, &outer_join_default,
-                  &spec.default_link.egress);
+    if spec.hierarchical {
+        configure_htb(config, "egress", &inner_split, &inner_top,
+                      spec, flow::Dir::Dst);
+    } else {
+        configure_qos(config, "egress",
+                      &inner_split_default, &inner_top_default,
+                      &spec.default_link.egress, spec.seed);
 
-    configure_flows(config, &inner_split, &outer_join,
-                    &spec.flows, flow::Dir::Dst);
+        configure_flows(config, &inner_split, &inner_top,
+                        &spec.flows, flow::Dir::Dst, spec.seed);
+    }
 }
 
 fn configure_interface
@@ -211,12 +231,33 @@ fn configure_tsd
     config::link(config, &output_to_tsd);
 }
 
+// Configure flow profiler that joins the default link and the flows’ links,
+// and counts their packets with the framing of each link (in hierarchical
+// networks all links share the framing of the default link)
 fn configure_top
     (config: &mut config::Config,
-     name: &str, output: &str, path: &str, dir: flow::Dir)
+     name: &str, output: &str, path: &str,
+     spec: &SyntheticNetwork, dir: flow::Dir)
 {
+    let link_framing = |link: &SyntheticLink| match dir {
+        flow::Dir::Src => framing(&link.ingress.framing),
+        flow::Dir::Dst => framing(&link.egress.framing)
+    };
+    let mut framings = HashMap::new();
+    framings.insert("default".to_string(), link_framing(&spec.default_link));
+    for synthetic_flow in &spec.flows {
+        let link = match spec.hierarchical {
+            true => &spec.default_link,
+            false => &synthetic_flow.link
+        };
+        framings.insert(synthetic_flow.label.to_string(), link_framing(link));
+    }
     let top_to_input = format!("{}.output -> {}", name, output);
-    config::app(config, name, &flow::Top {path: path.to_string(), dir: dir});
+    config::app(config, name, &flow::Top {
+        path: path.to_string(),
+        dir,
+        framing: framings
+    });
     config::link(config, &top_to_input);
 }
 
@@ -236,33 +277,18 @@ fn configure_split
 {
     let mut flows = Vec::new();
     for synthetic_flow in synthetic_flows {
-        flows.push(flow::Flow {
-            label: synthetic_flow.label.to_string(),
-            dir: dir,
-            ip: synthetic_flow.flow.ip,
-            protocol: synthetic_flow.flow.protocol,
-            port_min: synthetic_flow.flow.port_min,
-            port_max: synthetic_flow.flow.port_max
-        });
+        flows.extend(split_flows(synthetic_flow, dir));
     }
     let input_to_split = format!("{} -> {}.input", input, name);
     config::app(config, name, &flow::Split {flows: flows});
     config::link(config, &input_to_split);
 }
 
-fn configure_join
-    (config: &mut config::Config,
-     name: &str, output: &str)
-{
-    let join_to_output = format!("{}.output -> {}.input", name, output);
-    config::app(config, name, &basic_apps::Join {});
-    config::link(config, &join_to_output);
-}
-
 fn configure_flows
     (config: &mut config::Config,
      split: &str, join: &str,
-     synthetic_flows: &Vec<SyntheticFlow>, dir: flow::Dir)
+     synthetic_flows: &Vec<SyntheticFlow>, dir: flow::Dir,
+     seed: Option<u64>)
 {
     let prefix = match dir {
         flow::Dir::Src => "ingress",
@@ -276,92 +302,965 @@ fn configure_flows
             flow::Dir::Src => &synthetic_flow.link.ingress,
             flow::Dir::Dst => &synthetic_flow.link.egress
         };
-        configure_qos(config, &app_label, &input, &output, qos);
+        configure_qos(config, &app_label, &input, &output, qos, seed);
     }
 }
 
+fn configure_htb
+    (config: &mut config::Config,
+     label: &str, split: &str, join: &str,
+     spec: &SyntheticNetwork, dir: flow::Dir)
+{
+    let htb = format!("htb_{}", label);
+    let mut classes = Vec::new();
+    let parent = match dir {
+        flow::Dir::Src => &spec.default_link.ingress,
+        flow::Dir::Dst => &spec.default_link.egress
+    };
+    let seed = spec.seed;
+
+    // Flows are assured their rate as long as the parent’s rate suffices
+    let mut assured = 0;
+    for synthetic_flow in &spec.flows {
+        let qos = match dir {
+            flow::Dir::Src => &synthetic_flow.link.ingress,
+            flow::Dir::Dst => &synthetic_flow.link.egress
+        };
+        let rate = min(qos.rate, parent.rate - assured);
+        assured += rate;
+        classes.push(qos::HtbClass {
+            name: synthetic_flow.label.to_string(),
+            rate,
+            ceil: qos.ceil.unwrap_or(parent.rate).clamp(rate, parent.rate),
+            priority: qos.priority,
+            queue: qos.queue.as_ref().map(queue_size)
+                .unwrap_or_else(default_queue_size)
+        });
+        let input = format!("{}.{}", split, synthetic_flow.label);
+        let class = format!("{}.{}", htb, synthetic_flow.label);
+        let output = format!("{}.{}", join, synthetic_flow.label);
+        let app_label = format!("{}_{}", label, synthetic_flow.label);
+        config::link(config, &format!("{} -> {}", input, class));
+        configure_impairments(config, &app_label, &class, &output, qos, seed);
+    }
+
+    // Unclassified traffic is assured the remaining rate
+    classes.push(qos::HtbClass {
+        name: "default".to_string(),
+        rate: parent.rate - assured,
+        ceil: parent.rate,
+        priority: parent.priority,
+        queue: parent.queue.as_ref().map(queue_size)
+            .unwrap_or_else(default_queue_size)
+    });
+    let input = format!("{}.default", split);
+    let class = format!("{}.default", htb);
+    let output = format!("{}.default", join);
+    config::link(config, &format!("{} -> {}", input, class));
+    configure_impairments(config, label, &class, &output, parent, seed);
+
+    config::app(config, &htb, &qos::Htb {
+        rate: parent.rate,
+        classes,
+        framing: framing(&parent.framing),
+        schedule: schedule(parent, |keyframe| keyframe.rate.map(|r| r as f64))
+    });
+}
+
 fn configure_qos
     (config: &mut config::Config,
-     label: &str, input: &str, output: &str, qos: &QoS)
+     label: &str, input: &str, output: &str, qos: &QoS,
+     seed: Option<u64>)
+{
+    match &qos.quota {
+        Some(quota) =>
+            configure_quota(config, label, input, output, qos, quota, seed),
+        None =>
+            configure_qos_apps(config, label, input, output, qos, seed)
+    }
+}
+
+// Configure quota app that switches from `qos' to the QoS of the exceeded
+// quota
+fn configure_quota
+    (config: &mut config::Config,
+     label: &str, input: &str, output: &str, qos: &QoS, quota: &Quota,
+     seed: Option<u64>)
+{
+    let name = format!("quota_{}", label);
+    config::link(config, &format!("{} -> {}.input", input, name));
+    config::app(config, &name, &qos::Quota {
+        bytes: quota.bytes,
+        time: quota.time,
+        reset: quota.reset
+    });
+    config::link(config, &format!("{}.output -> {}", name, output));
+    // Loop both QoS back to the quota app, which counts delivered bytes
+    let quota_output = format!("{}.quota", name);
+    configure_qos_apps(config, label, &quota_output, &quota_output,
+                       qos, seed);
+    let exceeded_output = format!("{}.exceeded", name);
+    configure_qos_apps(config, &format!("exceeded_{}", label),
+                       &exceeded_output, &exceeded_output,
+                       &quota.exceeded, seed);
+}
+
+// Configure policer, rate limiter (or its replacement), and impairments
+fn configure_qos_apps
+    (config: &mut config::Config,
+     label: &str, input: &str, output: &str, qos: &QoS,
+     seed: Option<u64>)
 {
-    // Capacity of queues used to delay packets
-    // Hardcoded to a value we’re likely not to exceed, i.e:
-    //  100,000 is good for delaying ~100K packets per second for 1 second
-    //  (or ~1 Mpps for 100ms, etc.)
-    // If this value is too small we’ll start dropping packets that would
-    // overflow the queues, so re-evaluate once we have a good idea of our peak
-    // pps, and pick a value that can generously handle that (like 3x or
-    // something) for a feel-good margin and reasonable memory use.
-    let delay_queue_capacity = 100_000;
+    // Police traffic before it enters the rate limiter
+    let input = match &qos.policer {
+        Some(policer) => {
+            let name = format!("policer_{}", label);
+            config::link(config, &format!("{} -> {}.input", input, name));
+            config::app(config, &name, &qos::Policer {
+                cir: policer.cir,
+                cbs: policer.cbs,
+                pir: policer.pir,
+                pbs: policer.pbs,
+                yellow: policer.yellow_dscp,
+                framing: framing(&qos.framing)
+            });
+            format!("{}.output", name)
+        },
+        None => input.to_string()
+    };
+    let input = input.as_str();
 
     let rate = format!("rate_{}", label);
     let input_to_rate = format!("{} -> {}.input", input, rate);
+
+    let queue = qos.queue.as_ref().map(queue_size);
+    let aqm_queue = || queue.clone().unwrap_or_else(default_queue_size);
+    let us = |ms: f64| (ms.max(0.0) * 1000.0) as u64;
+    let rate_schedule = || {
+        schedule(qos, |keyframe| keyframe.rate.map(|rate| rate as f64))
+    };
+
+    if !qos.scheduler.is_empty() {
+        configure_scheduler(config, label, input, qos);
+    } else if let Some(opportunities) = &qos.trace_data {
+        config::link(config, &input_to_rate);
+        config::app(config, &rate, &qos::Trace {
+            opportunities: opportunities.clone(),
+            queue: aqm_queue(),
+            framing: framing(&qos.framing)
+        });
+    } else if qos.serial {
+        config::link(config, &input_to_rate);
+        config::app(config, &rate, &qos::SerialLink {
+            rate: qos.rate,
+            propagation: qos.latency,
+            framing: framing(&qos.framing),
+            queue: aqm_queue(),
+            capacity: delay_queue_capacity()
+        });
+    } else {
+        config::link(config, &input_to_rate);
+        match &qos.aqm {
+            None => config::app(config, &rate, &qos::RateLimiter {
+                rate: qos.rate,
+                queue: queue.clone(),
+                framing: framing(&qos.framing),
+                schedule: rate_schedule()
+            }),
+            Some(Aqm::Codel {target, interval, ecn}) =>
+                config::app(config, &rate, &qos::CoDel {
+                    rate: qos.rate,
+                    queue: aqm_queue(),
+                    target: us(*target),
+                    interval: us(*interval),
+                    ecn: *ecn,
+                    framing: framing(&qos.framing),
+                    schedule: rate_schedule()
+                }),
+            Some(Aqm::FqCodel {target, interval, flows, quantum, ecn}) =>
+                config::app(config, &rate, &qos::FqCoDel {
+                    rate: qos.rate,
+                    queue: aqm_queue(),
+                    target: us(*target),
+                    interval: us(*interval),
+                    flows: (*flows).max(1).next_power_of_two(),
+                    quantum: (*quantum).max(1),
+                    ecn: *ecn,
+                    framing: framing(&qos.framing),
+                    schedule: rate_schedule()
+                }),
+            Some(Aqm::Red {min, max, max_p, weight, ecn}) => {
+                let min = queue_size(min).bytes(qos.rate);
+                let max = queue_size(max).bytes(qos.rate).max(min + 1);
+                config::app(config, &rate, &qos::Red {
+                    rate: qos.rate,
+                    queue: aqm_queue(),
+                    min: qos::QueueSize::Bytes(min),
+                    max: qos::QueueSize::Bytes(max),
+                    max_p: max_p.clamp(0.0, 1.0),
+                    weight: weight.clamp(f64::MIN_POSITIVE, 1.0),
+                    ecn: *ecn,
+                    seed: app_seed(seed, &rate),
+                    framing: framing(&qos.framing),
+                    schedule: rate_schedule()
+                })
+            }
+        }
+    }
+
+    configure_impairments(config, label, &format!("{}.output", rate), output,
+                          qos, seed);
+}
+
+// Configure DSCP classifier and scheduler (in place of rate limiter)
+fn configure_scheduler
+    (config: &mut config::Config,
+     label: &str, input: &str, qos: &QoS)
+{
+    let classify = format!("classify_{}", label);
+    let input_to_classify = format!("{} -> {}.input", input, classify);
+    let rate = format!("rate_{}", label);
+
+    let mut dscp_classes = Vec::new();
+    let mut classes = Vec::new();
+    for class in &qos.scheduler {
+        dscp_classes.push(flow::DscpClass {
+            label: class.label.to_string(),
+            dscp: class.dscp.to_vec()
+        });
+        classes.push(qos::SchedulerClass {
+            name: class.label.to_string(),
+            priority: class.priority,
+            weight: class.weight.max(1),
+            queue: class.queue.as_ref().map(queue_size)
+                .unwrap_or_else(default_queue_size)
+        });
+    }
+    // Unclassified packets share the lowest priority, unless there is an
+    // explicit "default" class
+    if !qos.scheduler.iter().any(|class| class.label == "default") {
+        classes.push(qos::SchedulerClass {
+            name: "default".to_string(),
+            priority: classes.iter().map(|class| class.priority).max()
+                .unwrap_or(0),
+            weight: 1,
+            queue: default_queue_size()
+        });
+    }
+
+    config::link(config, &input_to_classify);
+    config::app(config, &classify, &flow::DscpSplit {classes: dscp_classes});
+    for class in &classes {
+        let classify_to_rate = format!("{}.{} -> {}.{}",
+                                       classify, class.name, rate, class.name);
+        config::link(config, &classify_to_rate);
+    }
+    config::app(config, &rate, &qos::Scheduler {
+        rate: qos.rate,
+        classes,
+        framing: framing(&qos.framing),
+        schedule: schedule(qos, |keyframe| keyframe.rate.map(|r| r as f64))
+    });
+}
+
+// Configure loss, latency, and jitter apps
+fn configure_impairments
+    (config: &mut config::Config,
+     label: &str, input: &str, output: &str, qos: &QoS,
+     seed: Option<u64>)
+{
+    let input = match &qos.outage {
+        Some(outage) => {
+            let name = format!("outage_{}", label);
+            config::link(config, &format!("{} -> {}.input", input, name));
+            config::app(config, &name, &qos::Outage {
+                pattern: match outage.pattern {
+                    OutagePattern::Once { start, duration } =>
+                        qos::OutagePattern::Once { start, duration },
+                    OutagePattern::Periodic { start, period, duration } =>
+                        qos::OutagePattern::Periodic { start, period, duration },
+                    OutagePattern::Random { mean_up, mean_down } =>
+                        qos::OutagePattern::Random { mean_up, mean_down }
+                },
+                hold: match outage.hold {
+                    true => Some(qos.queue.as_ref().map(queue_size)
+                                 .unwrap_or_else(default_queue_size)
+                                 .bytes(qos.rate)),
+                    false => None
+                },
+                seed: app_seed(seed, &name)
+            });
+            format!("{}.output", name)
+        },
+        None => input.to_string()
+    };
+    let input = match &qos.reorder {
+        Some(reorder) => {
+            let name = format!("reorder_{}", label);
+            config::link(config, &format!("{} -> {}.input", input, name));
+            config::app(config, &name, &qos::Reorder {
+                probability: reorder.probability.clamp(0.0, 1.0),
+                correlation: reorder.correlation.clamp(0.0, 1.0),
+                gap: reorder.gap,
+                distance: reorder.distance,
+                timeout: reorder.timeout,
+                seed: app_seed(seed, &name)
+            });
+            format!("{}.output", name)
+        },
+        None => input
+    };
+    let input = match &qos.duplicate {
+        Some(duplicate) => {
+            let name = format!("duplicate_{}", label);
+            config::link(config, &format!("{} -> {}.input", input, name));
+            config::app(config, &name, &qos::Duplicate {
+                probability: duplicate.probability.clamp(0.0, 1.0),
+                correlation: duplicate.correlation.clamp(0.0, 1.0),
+                delay: duplicate.delay,
+                capacity: delay_queue_capacity(),
+                seed: app_seed(seed, &name)
+            });
+            format!("{}.output", name)
+        },
+        None => input
+    };
+    let input = match &qos.corrupt {
+        Some(corrupt) => {
+            let name = format!("corrupt_{}", label);
+            config::link(config, &format!("{} -> {}.input", input, name));
+            config::app(config, &name, &qos::Corrupt {
+                probability: corrupt.probability.clamp(0.0, 1.0),
+                correlation: corrupt.correlation.clamp(0.0, 1.0),
+                bits: corrupt.bits.max(1),
+                mode: match &corrupt.mode {
+                    CorruptMode::Payload => qos::CorruptMode::Payload,
+                    CorruptMode::Invalid => qos::CorruptMode::Invalid,
+                    CorruptMode::Header(ranges) => qos::CorruptMode::Header(
+                        ranges.iter()
+                            .map(|range| (range.offset, range.length))
+                            .collect()
+                    )
+                },
+                seed: app_seed(seed, &name)
+            });
+            format!("{}.output", name)
+        },
+        None => input
+    };
+
     let loss = format!("loss_{}", label);
-    let rate_to_loss = format!("{}.output -> {}.input", rate, loss);
+    let input_to_loss = format!("{} -> {}.input", input, loss);
     let latency = format!("latency_{}", label);
     let loss_to_latency = format!("{}.output -> {}.input", loss, latency);
     let jitter = format!("jitter_{}", label);
     let latency_to_jitter = format!("{}.output -> {}.input", latency, jitter);
     let jitter_to_output = format!("{}.output -> {}", jitter, output);
 
-
-    config::link(config, &input_to_rate);
-    config::app(config, &rate, &qos::RateLimiter {
-        rate: qos.rate
-    });
-    config::link(config, &rate_to_loss);
+    config::link(config, &input_to_loss);
     config::app(config, &loss, &qos::Loss {
-        ratio: qos.loss.clamp(0.0, 1.0)
+        model: loss_model(qos),
+        seed: app_seed(seed, &loss),
+        schedule: schedule(qos, |keyframe| keyframe.loss)
     });
     config::link(config, &loss_to_latency);
+    // Serial links delay packets by `latency' themselves
+    // (see configure_qos_apps())
     config::app(config, &latency, &qos::Latency {
-        ms: qos.latency,
-        capacity: delay_queue_capacity
+        ms: if qos.serial { 0 } else { qos.latency },
+        capacity: delay_queue_capacity(),
+        schedule: schedule(qos, |keyframe| keyframe.latency.map(|ms| ms as f64))
     });
     config::link(config, &latency_to_jitter);
     config::app(config, &jitter, &qos::Jitter {
         ms: qos.jitter,
+        mean: qos.jitter_mean,
+        distribution: match qos.jitter_distribution {
+            Distribution::Uniform => qos::Distribution::Uniform,
+            Distribution::Normal => qos::Distribution::Normal,
+            Distribution::Pareto => qos::Distribution::Pareto,
+            Distribution::ParetoNormal => qos::Distribution::ParetoNormal,
+            Distribution::Table | Distribution::Histogram =>
+                qos.jitter_table_data.clone()
+                    .expect("Distribution table not loaded")
+        },
+        correlation: qos.jitter_correlation.clamp(0.0, 1.0),
         strength: qos.jitter_strength.clamp(0.0, 1.0),
         reorder: qos.reorder_packets,
-        capacity: delay_queue_capacity
+        capacity: delay_queue_capacity(),
+        seed: app_seed(seed, &jitter),
+        schedule: schedule(qos, |keyframe| keyframe.jitter.map(|ms| ms as f64))
     });
     config::link(config, &jitter_to_output);
 }
 
+// Derive a distinct seed for each stochastic app from the spec’s seed
+// (FNV-1a hash of the app name mixed into the seed)
+fn app_seed(seed: Option<u64>, name: &str) -> Option<u64> {
+    seed.map(|seed| {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in name.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        seed ^ hash
+    })
+}
+
+// Schedule of the QoS parameter selected by `value' (None if no keyframe
+// sets it)
+fn schedule(qos: &QoS, value: fn(&Keyframe) -> Option<f64>)
+            -> Option<qos::Schedule>
+{
+    let spec = qos.schedule.as_ref()?;
+    let mut keyframes: Vec<(u64, f64)> = spec.keyframes.iter()
+        .filter_map(|keyframe| value(keyframe).map(|v| (keyframe.time, v)))
+        .collect();
+    if keyframes.is_empty() { return None }
+    keyframes.sort_by_key(|&(time, _)| time);
+    Some(qos::Schedule {
+        keyframes,
+        interpolation: match spec.interpolation {
+            Interpolation::Step => qos::Interpolation::Step,
+            Interpolation::Linear => qos::Interpolation::Linear
+        },
+        period: match spec.repeat {
+            true => Some(spec.period.unwrap_or_else(|| {
+                spec.keyframes.iter().map(|keyframe| keyframe.time)
+                    .max().unwrap_or(0)
+            })),
+            false => None
+        }
+    })
+}
+
+// Queues that must be bounded default to ~1000 full-size packets
+fn default_queue_size() -> qos::QueueSize {
+    qos::QueueSize::Bytes(1_514_000)
+}
+
+// Capacity of queues used to delay packets
+// Hardcoded to a value we’re likely not to exceed, i.e:
+//  100,000 is good for delaying ~100K packets per second for 1 second
+//  (or ~1 Mpps for 100ms, etc.)
+// If this value is too small we’ll start dropping packets that would
+// overflow the queues, so re-evaluate once we have a good idea of our peak
+// pps, and pick a value that can generously handle that (like 3x or
+// something) for a feel-good margin and reasonable memory use.
+fn delay_queue_capacity() -> usize {
+    100_000
+}
+
+fn queue_size(queue: &QueueSize) -> qos::QueueSize {
+    match *queue {
+        QueueSize::Bytes(bytes) => qos::QueueSize::Bytes(bytes),
+        QueueSize::Ms(ms) => qos::QueueSize::Millis(ms)
+    }
+}
+
+fn framing(framing: &Framing) -> packet::Framing {
+    match *framing {
+        Framing::Ethernet => packet::ETHERNET,
+        Framing::Pppoe => packet::PPPOE,
+        Framing::Adsl => packet::ADSL,
+        Framing::Wifi => packet::WIFI,
+        Framing::Cellular => packet::CELLULAR,
+        Framing::Raw =>
+            packet::Framing { overhead: 0, min_length: 0, atm: false },
+        Framing::Custom { overhead, min_length, atm } =>
+            packet::Framing { overhead, min_length, atm }
+    }
+}
+
+// Flows are specified for ingress (i.e., src is the remote end), and
+// mirrored for egress. The legacy fields ip, port_min, and port_max stand in
+// for src and src_ports, and the legacy port range only applies to TCP/UDP.
+// A filter expression narrows down the flow, and can compile into multiple
+// flows (see filter::compile).
+fn split_flows
+    (synthetic_flow: &SyntheticFlow, dir: flow::Dir) -> Vec<flow::Flow>
+{
+    let flow = split_flow(synthetic_flow);
+    let flows = match &synthetic_flow.filter {
+        Some(filter) => filter::compile(filter, &flow).unwrap(),
+        None => vec![flow]
+    };
+    match dir {
+        flow::Dir::Src => flows,
+        flow::Dir::Dst => flows.iter().map(flow::Flow::mirror).collect()
+    }
+}
+
+fn split_flow(synthetic_flow: &SyntheticFlow) -> flow::Flow {
+    let default = Flow::default(); // only a filter is given
+    let spec = synthetic_flow.flow.as_ref().unwrap_or(&default);
+    let src = match (&spec.ip, &spec.src) {
+        (Some(ip), _) => address(ip).unwrap(),
+        (None, Some(src)) => prefix(src).unwrap(),
+        (None, None) => flow::Prefix::Any
+    };
+    let dst = match &spec.dst {
+        Some(dst) => prefix(dst).unwrap(),
+        None => flow::Prefix::Any
+    };
+    let src_ports = match &spec.src_ports {
+        Some(ports) => flow::port_range(ports).unwrap(),
+        None => match spec.protocol {
+            ipv4::PROTOCOL_TCP | ipv4::PROTOCOL_UDP =>
+                spec.port_min.unwrap_or(0)..=spec.port_max.unwrap_or(u16::MAX),
+            _ => 0..=u16::MAX
+        }
+    };
+    let dst_ports = match &spec.dst_ports {
+        Some(ports) => flow::port_range(ports).unwrap(),
+        None => 0..=u16::MAX
+    };
+    flow::Flow {
+        label: synthetic_flow.label.to_string(),
+        src,
+        dst,
+        protocol: spec.protocol,
+        src_ports,
+        dst_ports,
+        dscp: spec.dscp,
+        ecn: spec.ecn.map(|ecn| match ecn {
+            Ecn::NotEct => ipv4::ECN_NOT_ECT,
+            Ecn::Ect1 => ipv4::ECN_ECT1,
+            Ecn::Ect0 => ipv4::ECN_ECT0,
+            Ecn::Ce => ipv4::ECN_CE
+        }),
+        icmp_type: spec.icmp_type,
+        icmp_code: spec.icmp_code,
+        tcp_flags: spec.tcp_flags.as_deref().map(|flags| {
+            tcp_flags(flags).unwrap()
+        }),
+        except: Vec::new()
+    }
+}
+
+fn address(address: &Address) -> Option<flow::Prefix> {
+    match address {
+        Address::Number(0) => Some(flow::Prefix::Any),
+        Address::Number(ip) => Some(flow::Prefix::V4(*ip, 32)),
+        Address::String(string) => prefix(string)
+    }
+}
+
+// Parse an address or CIDR prefix (see flow::prefix). Unspecified addresses
+// without prefix length (0.0.0.0, ::) match any address of either family.
+fn prefix(string: &str) -> Option<flow::Prefix> {
+    match IpAddr::from_str(string) {
+        Ok(ip) if ip.is_unspecified() => Some(flow::Prefix::Any),
+        _ => flow::prefix(string)
+    }
+}
+
+// Parse TCP flags that must be set (or clear, if prefixed with “!”) into a
+// mask and value, e.g. "syn,!ack" -> (SYN|ACK, SYN)
+fn tcp_flags(string: &str) -> Option<(u8, u8)> {
+    let (mut mask, mut value) = (0, 0);
+    for flag in string.split(',') {
+        let (set, flag) = match flag.trim().strip_prefix('!') {
+            Some(flag) => (false, flag),
+            None => (true, flag.trim())
+        };
+        let flag = match flag.to_lowercase().as_str() {
+            "fin" => tcp::FLAG_FIN,
+            "syn" => tcp::FLAG_SYN,
+            "rst" => tcp::FLAG_RST,
+            "psh" => tcp::FLAG_PSH,
+            "ack" => tcp::FLAG_ACK,
+            "urg" => tcp::FLAG_URG,
+            "ece" => tcp::FLAG_ECE,
+            "cwr" => tcp::FLAG_CWR,
+            _ => return None
+        };
+        mask |= flag;
+        if set { value |= flag }
+    }
+    Some((mask, value))
+}
+
+fn loss_model(qos: &QoS) -> qos::LossModel {
+    let ratio = |x: f64| x.clamp(0.0, 1.0);
+    match qos.loss_model {
+        None => qos::LossModel::Uniform(ratio(qos.loss)),
+        Some(LossModel::GilbertElliott { p, r, loss_good, loss_bad }) =>
+            qos::LossModel::GilbertElliott {
+                p: ratio(p), r: ratio(r),
+                loss_good: ratio(loss_good), loss_bad: ratio(loss_bad)
+            },
+        Some(LossModel::FourState { p13, p31, p32, p23, p14 }) =>
+            qos::LossModel::FourState {
+                p13: ratio(p13), p31: ratio(p31), p32: ratio(p32),
+                p23: ratio(p23), p14: ratio(p14)
+            }
+    }
+}
+
 
 // This is our QoS spec / configuration format
 
 #[derive(Serialize,Deserialize)]
 struct SyntheticNetwork {
     default_link: SyntheticLink,
-    flows: Vec<SyntheticFlow>
+    flows: Vec<SyntheticFlow>,
+    // Seed for stochastic apps (makes runs reproducible)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    // Flows share the capacity of `default_link' (see qos::Htb), which
+    // replaces aqm, scheduler, and trace (these are rejected)
+    #[serde(default)]
+    hierarchical: bool
 }
 #[derive(Serialize,Deserialize)]
 struct SyntheticLink {
     ingress: QoS,
     egress: QoS
 }
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,Default)]
 struct QoS {
     rate: u64,
     loss: f64,
     latency: u64,
     jitter: u64,
     jitter_strength: f64,
-    reorder_packets: bool
+    reorder_packets: bool,
+    // Optional bursty loss model (overrides `loss')
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    loss_model: Option<LossModel>,
+    // Jitter distribution (see qos::Jitter), for non-uniform distributions
+    // `jitter' is the standard deviation
+    #[serde(default)]
+    jitter_distribution: Distribution,
+    #[serde(default)]
+    jitter_mean: u64,
+    #[serde(default)]
+    jitter_correlation: f64,
+    // Path to distribution table (for "table" and "histogram" distributions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jitter_table: Option<String>,
+    #[serde(skip)]
+    jitter_table_data: Option<qos::Distribution>,
+    // Queue packets exceeding `rate' (shaping) instead of dropping them
+    // (policing), e.g. {"bytes": 150000} or {"ms": 100}
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueSize>,
+    // Active queue management (implies queueing, see `queue')
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aqm: Option<Aqm>,
+    // For hierarchical networks: maximum rate including capacity borrowed
+    // from `default_link' (defaults to its rate), and borrowing priority
+    // (lower values take precedence)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ceil: Option<u64>,
+    #[serde(default)]
+    priority: u8,
+    // Classes of DSCP-keyed scheduler (replaces the rate limiter, see
+    // qos::Scheduler)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scheduler: Vec<SchedulerClass>,
+    // Time-varying rate, loss, latency, and jitter (see qos::Schedule)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schedule: Option<Schedule>,
+    // Path to Mahimahi packet-delivery trace (replaces `rate', see
+    // qos::Trace), e.g. an uplink trace for egress and a downlink trace for
+    // ingress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<String>,
+    #[serde(skip)]
+    trace_data: Option<Vec<u64>>,
+    // Connectivity outages (see qos::Outage)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outage: Option<Outage>,
+    // Packet reordering like netem (see qos::Reorder)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reorder: Option<Reorder>,
+    // Packet duplication like netem (see qos::Duplicate)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duplicate: Option<Duplicate>,
+    // Packet corruption (see qos::Corrupt)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    corrupt: Option<Corrupt>,
+    // Transmit packets one after another at `rate', and delay them by
+    // `latency' once transmitted (replaces the rate limiter, see
+    // qos::SerialLink)
+    #[serde(default)]
+    serial: bool,
+    // Link-layer framing of packets, accounted for by the rate limiter (and
+    // its replacements), the policer, and the flow profiler (see
+    // packet::Framing)
+    #[serde(default)]
+    framing: Framing,
+    // Two rate three color policer in front of the rate limiter (see
+    // qos::Policer)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    policer: Option<Policer>,
+    // Switch to different QoS once a quota is exceeded (see qos::Quota)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota: Option<Quota>
+}
+#[derive(Serialize,Deserialize)]
+struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<u64>, // ms
+    // Reset quota periodically
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reset: Option<u64>, // ms
+    // QoS once quota is exceeded
+    exceeded: Box<QoS>
+}
+#[derive(Serialize,Deserialize)]
+struct Policer {
+    cir: u64, // bps
+    cbs: u64, // bytes
+    pir: u64, // bps
+    pbs: u64, // bytes
+    // Remark yellow packets with DSCP (instead of dropping them)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    yellow_dscp: Option<u8>
+}
+#[derive(Serialize,Deserialize)]
+struct Reorder {
+    probability: f64,
+    #[serde(default)]
+    correlation: f64,
+    #[serde(default)]
+    gap: u32,
+    #[serde(default = "reorder_distance")]
+    distance: usize,
+    #[serde(default = "reorder_timeout")]
+    timeout: u64 // ms
+}
+fn reorder_distance() -> usize { 1 }
+fn reorder_timeout() -> u64 { 100 }
+#[derive(Serialize,Deserialize)]
+struct Duplicate {
+    probability: f64,
+    #[serde(default)]
+    correlation: f64,
+    #[serde(default)]
+    delay: u64 // ms
+}
+#[derive(Serialize,Deserialize)]
+struct Corrupt {
+    probability: f64,
+    #[serde(default)]
+    correlation: f64,
+    #[serde(default = "corrupt_bits")]
+    bits: u32,
+    // "payload", "invalid", or {"header": [{"offset": ..., "length": ...}]}
+    #[serde(default)]
+    mode: CorruptMode
+}
+fn corrupt_bits() -> u32 { 1 }
+#[derive(Serialize,Deserialize,Default)]
+#[serde(rename_all = "lowercase")]
+enum CorruptMode { #[default] Payload, Invalid, Header(Vec<ByteRange>) }
+#[derive(Serialize,Deserialize)]
+struct ByteRange { offset: usize, length: usize }
+#[derive(Serialize,Deserialize)]
+struct Outage {
+    #[serde(flatten)]
+    pattern: OutagePattern,
+    // Hold packets during outages (up to `queue') instead of dropping them
+    #[serde(default)]
+    hold: bool
+}
+#[derive(Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OutagePattern {
+    Once { start: u64, duration: u64 },
+    Periodic {
+        #[serde(default)]
+        start: u64,
+        period: u64,
+        duration: u64
+    },
+    Random { mean_up: f64, mean_down: f64 }
+}
+#[derive(Serialize,Deserialize)]
+struct Schedule {
+    keyframes: Vec<Keyframe>,
+    #[serde(default)]
+    interpolation: Interpolation,
+    // Repeat schedule every `period' ms (defaults to time of last keyframe)
+    #[serde(default, rename = "loop")]
+    repeat: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    period: Option<u64>
+}
+#[derive(Serialize,Deserialize)]
+struct Keyframe {
+    time: u64, // ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    loss: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jitter: Option<u64>
+}
+#[derive(Serialize,Deserialize,Default)]
+#[serde(rename_all = "lowercase")]
+enum Interpolation { #[default] Step, Linear }
+#[derive(Serialize,Deserialize)]
+struct SchedulerClass {
+    label: String,
+    #[serde(default)]
+    dscp: Vec<u8>,
+    #[serde(default)]
+    priority: u8,
+    #[serde(default = "scheduler_weight")]
+    weight: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueSize>
+}
+fn scheduler_weight() -> u32 { 1 }
+#[derive(Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QueueSize { Bytes(usize), Ms(u64) }
+#[derive(Serialize,Deserialize,Default)]
+#[serde(rename_all = "snake_case")]
+enum Framing {
+    #[default]
+    Ethernet,
+    Pppoe,
+    Adsl,
+    Wifi,
+    Cellular,
+    Raw,
+    Custom {
+        overhead: i64,
+        #[serde(default)]
+        min_length: u64,
+        #[serde(default)]
+        atm: bool
+    }
+}
+#[derive(Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LossModel {
+    GilbertElliott {
+        p: f64,
+        r: f64,
+        #[serde(default)]
+        loss_good: f64,
+        #[serde(default = "one")]
+        loss_bad: f64
+    },
+    FourState {
+        p13: f64,
+        p31: f64,
+        #[serde(default)]
+        p32: f64,
+        #[serde(default = "one")]
+        p23: f64,
+        #[serde(default)]
+        p14: f64
+    }
+}
+fn one() -> f64 { 1.0 }
+#[derive(Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Aqm {
+    Codel {
+        #[serde(default = "codel_target")]
+        target: f64, // ms
+        #[serde(default = "codel_interval")]
+        interval: f64, // ms
+        #[serde(default)]
+        ecn: bool
+    },
+    FqCodel {
+        #[serde(default = "codel_target")]
+        target: f64, // ms
+        #[serde(default = "codel_interval")]
+        interval: f64, // ms
+        #[serde(default = "fq_codel_flows")]
+        flows: usize,
+        #[serde(default = "fq_codel_quantum")]
+        quantum: usize, // bytes
+        #[serde(default)]
+        ecn: bool
+    },
+    // RED thresholds of average queue size, e.g. {"ms": 5}
+    Red {
+        min: QueueSize,
+        max: QueueSize,
+        #[serde(default = "red_max_p")]
+        max_p: f64,
+        #[serde(default = "red_weight")]
+        weight: f64,
+        #[serde(default)]
+        ecn: bool
+    }
+}
+fn codel_target() -> f64 { 5.0 }
+fn codel_interval() -> f64 { 100.0 }
+fn fq_codel_flows() -> usize { 1024 }
+fn fq_codel_quantum() -> usize { 1514 }
+fn red_max_p() -> f64 { 0.1 }
+fn red_weight() -> f64 { 0.002 }
+#[derive(Serialize,Deserialize,Default)]
+#[serde(rename_all = "lowercase")]
+enum Distribution {
+    #[default] Uniform, Normal, Pareto, ParetoNormal,
+    Table, // netem .dist file, see qos::read_netem_table
+    Histogram // CSV file, see qos::read_histogram
 }
 #[derive(Serialize,Deserialize)]
 struct SyntheticFlow {
     label: String,
-    flow: Flow,
+    // At least one of flow and filter (both select all packets if empty)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flow: Option<Flow>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<String>, // pcap-filter expression, see filter::compile
     link: SyntheticLink
 }
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,Default)]
 struct Flow {
-    ip: u32,
+    // Flows are specified for ingress, see split_flow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    src: Option<String>, // address or prefix (e.g., "10.1.0.0/16")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dst: Option<String>,
+    #[serde(default)]
     protocol: u8,
-    port_min: u16,
-    port_max: u16
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    src_ports: Option<String>, // port range (e.g., "40000-50000")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dst_ports: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dscp: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ecn: Option<Ecn>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icmp_type: Option<u8>, // ICMP or ICMPv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icmp_code: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp_flags: Option<String>, // e.g. "syn,!ack" (SYN set, ACK clear)
+    // Legacy fields: src address (or number), and src port range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port_min: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port_max: Option<u16>
+}
+#[derive(Serialize,Deserialize,Clone,Copy)]
+#[serde(rename_all = "snake_case")]
+enum Ecn { NotEct, Ect1, Ect0, Ce }
+#[derive(Serialize,Deserialize)]
+#[serde(untagged)]
+enum Address {
+    Number(u32), // IPv4 address in network byte order
+    String(String) // IPv4 or IPv6 address, e.g. "10.0.0.1" or "2001:db8::1"
 }
 
 
@@ -369,8 +1268,12 @@ struct Flow {
 
 fn read_qos_spec(path: &str) -> Result<SyntheticNetwork, Box<dyn Error>> {
     let file = File::open(path)?;
-    let spec = serde_json::from_reader(BufReader::new(file))?;
+    let mut spec = serde_json::from_reader(BufReader::new(file))?;
     sanitize_labels(&spec)?;
+    validate_flows(&spec)?;
+    validate_links(&spec)?;
+    load_distribution_tables(&mut spec)?;
+    load_traces(&mut spec)?;
     Ok(spec)
 }
 
@@ -406,6 +1309,313 @@ fn sanitize_labels(spec: &SyntheticNetwork) -> Result<(), Box<io::Error>> {
     }
     Ok(())
 }
+fn validate_flows(spec: &SyntheticNetwork) -> Result<(), Box<io::Error>> {
+    let invalid = |message: &str| Err(Box::new(
+        io::Error::new(io::ErrorKind::InvalidData, message)));
+    for synthetic_flow in &spec.flows {
+        let default = Flow::default();
+        let flow = match (&synthetic_flow.flow, &synthetic_flow.filter) {
+            (None, None) => return invalid("Flow requires flow or filter."),
+            (flow, _) => flow.as_ref().unwrap_or(&default)
+        };
+        if flow.ip.is_some() && flow.src.is_some() {
+            return invalid("Flow ip and src are mutually exclusive.")
+        }
+        if (flow.port_min.is_some() || flow.port_max.is_some())
+            && flow.src_ports.is_some()
+        {
+            return invalid("Flow port_min/port_max and src_ports are \
+                            mutually exclusive.")
+        }
+        if flow.ip.as_ref().is_some_and(|ip| address(ip).is_none()) {
+            return invalid("Invalid flow address or prefix.")
+        }
+        for string in [&flow.src, &flow.dst].iter().copied().flatten() {
+            if prefix(string).is_none() {
+                return invalid("Invalid flow address or prefix.")
+            }
+        }
+        let ports = [&flow.src_ports, &flow.dst_ports];
+        for string in ports.iter().copied().flatten() {
+            if flow::port_range(string).is_none() {
+                return invalid("Invalid flow port range.")
+            }
+        }
+        if flow.dscp.is_some_and(|dscp| dscp >= 64) {
+            return invalid("DSCP must be below 64.")
+        }
+        let icmp = [0, ipv4::PROTOCOL_ICMP, ipv6::PROTOCOL_ICMPV6];
+        if (flow.icmp_type.is_some() || flow.icmp_code.is_some())
+            && !icmp.contains(&flow.protocol)
+        {
+            return invalid("Flow icmp_type and icmp_code require protocol \
+                            ICMP (1) or ICMPv6 (58).")
+        }
+        if let Some(flags) = &flow.tcp_flags {
+            if tcp_flags(flags).is_none() {
+                return invalid("Invalid flow tcp_flags.")
+            }
+            if ![0, ipv4::PROTOCOL_TCP].contains(&flow.protocol) {
+                return invalid("Flow tcp_flags require protocol TCP (6).")
+            }
+        }
+        // Filter narrows down the flow (see split_flows), and must leave
+        // some of it
+        if let Some(filter) = &synthetic_flow.filter {
+            match filter::compile(filter, &split_flow(synthetic_flow)) {
+                Err(error) =>
+                    return invalid(&format!("Invalid flow filter: {}.", error)),
+                Ok(flows) if flows.is_empty() =>
+                    return invalid("Flow filter excludes all packets of flow."),
+                Ok(_) => ()
+            }
+        }
+    }
+    Ok(())
+}
+// Validate the QoS of the default link and of each flow’s link (including the
+// QoS of exceeded quotas) with each of the per-feature checks below
+fn validate_links(spec: &SyntheticNetwork) -> Result<(), Box<io::Error>> {
+    let checks = [check_hierarchy, check_loss_model, check_scheduler,
+                  check_schedule, check_trace, check_outage,
+                  check_serial_link, check_framing, check_policer,
+                  check_quota];
+    let mut links = vec![(&spec.default_link, false)];
+    for synthetic_flow in &spec.flows {
+        links.push((&synthetic_flow.link, true));
+    }
+    for (link, flow) in links {
+        let context = QoSContext { hierarchical: spec.hierarchical, flow };
+        for qos in link_qos(link) {
+            for check in checks {
+                check(qos, &context).map_err(|message| Box::new(
+                    io::Error::new(io::ErrorKind::InvalidData, message)))?;
+            }
+        }
+    }
+    Ok(())
+}
+struct QoSContext {
+    hierarchical: bool, // QoS is part of a hierarchical network
+    flow: bool // QoS of a flow (i.e., a HTB class in hierarchical networks)
+}
+type Check = Result<(), &'static str>;
+// HTB classes (see configure_htb) take the place of the rate limiter and its
+// replacements
+fn check_hierarchy(qos: &QoS, context: &QoSContext) -> Check {
+    if context.hierarchical
+        && (qos.aqm.is_some() || !qos.scheduler.is_empty()
+            || qos.trace.is_some())
+    {
+        return Err("Aqm, scheduler, and trace are not supported in \
+                    hierarchical networks.")
+    }
+    Ok(())
+}
+// Transition probabilities out of a state (see qos::loss_step) must not add
+// up to more than one
+fn check_loss_model(qos: &QoS, _: &QoSContext) -> Check {
+    match qos.loss_model {
+        Some(LossModel::FourState { p13, p31, p32, p14, .. })
+            if p13 + p14 > 1.0 || p31 + p32 > 1.0 =>
+            Err("Loss model p13 + p14 and p31 + p32 must not exceed 1."),
+        _ => Ok(())
+    }
+}
+fn check_scheduler(qos: &QoS, _: &QoSContext) -> Check {
+    if !qos.scheduler.is_empty() && qos.aqm.is_some() {
+        return Err("Scheduler and aqm are mutually exclusive.")
+    }
+    let mut labels = HashSet::new();
+    for class in &qos.scheduler {
+        if !LABEL_SYNTAX.is_match(&class.label) {
+            return Err("Invalid characters in scheduler class label.")
+        }
+        if !labels.insert(&class.label) {
+            return Err("Duplicate scheduler class labels.")
+        }
+        if class.dscp.iter().any(|&dscp| dscp >= 64) {
+            return Err("DSCP must be below 64.")
+        }
+    }
+    Ok(())
+}
+fn check_schedule(qos: &QoS, context: &QoSContext) -> Check {
+    let schedule = match &qos.schedule {
+        Some(schedule) => schedule,
+        None => return Ok(())
+    };
+    if schedule.keyframes.is_empty() {
+        return Err("Schedule requires keyframes.")
+    }
+    if schedule.repeat && schedule.period.or_else(|| {
+        schedule.keyframes.iter().map(|keyframe| keyframe.time).max()
+    }) == Some(0) {
+        return Err("Looping schedule requires a non-zero period.")
+    }
+    let sets = |value: fn(&Keyframe) -> bool|
+        schedule.keyframes.iter().any(value);
+    if qos.loss_model.is_some() && sets(|k| k.loss.is_some()) {
+        return Err("Loss schedule and loss_model are exclusive.")
+    }
+    if context.hierarchical && context.flow && sets(|k| k.rate.is_some()) {
+        return Err("Flow rates of hierarchical networks can not be \
+                    scheduled.")
+    }
+    Ok(())
+}
+fn check_trace(qos: &QoS, _: &QoSContext) -> Check {
+    if qos.trace.is_none() { return Ok(()) }
+    if qos.aqm.is_some() || !qos.scheduler.is_empty() {
+        return Err("Trace excludes aqm and scheduler.")
+    }
+    if qos.schedule.as_ref().is_some_and(|schedule| {
+        schedule.keyframes.iter().any(|k| k.rate.is_some())
+    }) {
+        return Err("Trace excludes rate schedule.")
+    }
+    Ok(())
+}
+fn check_outage(qos: &QoS, _: &QoSContext) -> Check {
+    match qos.outage.as_ref().map(|outage| &outage.pattern) {
+        Some(OutagePattern::Periodic { period, duration, .. })
+            if duration > period || *period == 0 =>
+            Err("Outage duration must not exceed period."),
+        Some(OutagePattern::Random { mean_up, mean_down })
+            if *mean_up <= 0.0 || *mean_down <= 0.0 =>
+            Err("Outage mean times must be positive."),
+        _ => Ok(())
+    }
+}
+fn check_serial_link(qos: &QoS, context: &QoSContext) -> Check {
+    if !qos.serial { return Ok(()) }
+    if context.hierarchical {
+        return Err("Serial links are not supported in hierarchical \
+                    networks.")
+    }
+    if qos.rate == 0 {
+        return Err("Serial link requires a non-zero rate.")
+    }
+    if qos.aqm.is_some() || !qos.scheduler.is_empty() || qos.trace.is_some() {
+        return Err("Serial link excludes aqm, scheduler, and trace.")
+    }
+    if qos.schedule.as_ref().is_some_and(|schedule| {
+        schedule.keyframes.iter()
+            .any(|k| k.rate.is_some() || k.latency.is_some())
+    }) {
+        return Err("Serial link excludes rate and latency schedules.")
+    }
+    Ok(())
+}
+// HTB classes share the framing of the parent link (see configure_htb)
+fn check_framing(qos: &QoS, context: &QoSContext) -> Check {
+    if let Framing::Ethernet = qos.framing { return Ok(()) }
+    if context.hierarchical && context.flow {
+        return Err("Flow framing of hierarchical networks can not be set.")
+    }
+    Ok(())
+}
+fn check_policer(qos: &QoS, context: &QoSContext) -> Check {
+    let policer = match &qos.policer {
+        Some(policer) => policer,
+        None => return Ok(())
+    };
+    if context.hierarchical {
+        return Err("Policers are not supported in hierarchical networks.")
+    }
+    if policer.pir < policer.cir {
+        return Err("Policer pir must not be below cir.")
+    }
+    if policer.yellow_dscp.is_some_and(|dscp| dscp >= 64) {
+        return Err("DSCP must be below 64.")
+    }
+    Ok(())
+}
+fn check_quota(qos: &QoS, context: &QoSContext) -> Check {
+    let quota = match &qos.quota {
+        Some(quota) => quota,
+        None => return Ok(())
+    };
+    if context.hierarchical {
+        return Err("Quotas are not supported in hierarchical networks.")
+    }
+    if quota.bytes.is_none() && quota.time.is_none() {
+        return Err("Quota requires bytes or time.")
+    }
+    if quota.reset == Some(0) {
+        return Err("Quota reset must be positive.")
+    }
+    if quota.exceeded.quota.is_some() {
+        return Err("Quotas can not be nested.")
+    }
+    Ok(())
+}
+fn load_distribution_tables(spec: &mut SyntheticNetwork)
+                            -> Result<(), Box<dyn Error>>
+{
+    for_each_qos(spec, &mut |qos| {
+        let read_table = match qos.jitter_distribution {
+            Distribution::Table => qos::read_netem_table,
+            Distribution::Histogram => qos::read_histogram,
+            _ => return Ok(())
+        };
+        qos.jitter_table_data = match &qos.jitter_table {
+            Some(path) => Some(read_table(path)?),
+            None => return Err(Box::new(
+                io::Error::new(io::ErrorKind::InvalidData,
+                               "Distribution requires jitter_table.")))
+        };
+        Ok(())
+    })
+}
+fn load_traces(spec: &mut SyntheticNetwork) -> Result<(), Box<dyn Error>> {
+    for_each_qos(spec, &mut |qos| {
+        let path = match &qos.trace {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        qos.trace_data = Some(qos::read_mahimahi_trace(path)?);
+        Ok(())
+    })
+}
+
+// QoS of link (in both directions, and once quotas are exceeded)
+fn link_qos(link: &SyntheticLink) -> Vec<&QoS> {
+    let mut all = Vec::new();
+    for qos in [&link.ingress, &link.egress] {
+        all.push(qos);
+        if let Some(quota) = &qos.quota {
+            all.push(&*quota.exceeded);
+        }
+    }
+    all
+}
+
+// Apply f to each QoS of spec (see link_qos())
+fn for_each_qos<F>(spec: &mut SyntheticNetwork, f: &mut F)
+                   -> Result<(), Box<dyn Error>>
+    where F: FnMut(&mut QoS) -> Result<(), Box<dyn Error>>
+{
+    fn visit<F>(qos: &mut QoS, f: &mut F) -> Result<(), Box<dyn Error>>
+        where F: FnMut(&mut QoS) -> Result<(), Box<dyn Error>>
+    {
+        f(qos)?;
+        match &mut qos.quota {
+            Some(quota) => visit(&mut quota.exceeded, f),
+            None => Ok(())
+        }
+    }
+    let mut links = vec![&mut spec.default_link];
+    for synthetic_flow in spec.flows.iter_mut() {
+        links.push(&mut synthetic_flow.link);
+    }
+    for link in links {
+        visit(&mut link.ingress, f)?;
+        visit(&mut link.egress, f)?;
+    }
+    Ok(())
+}
+
 static LABEL_SYNTAX: Lazy<Regex> = Lazy::new
     (|| Regex::new(r"^[\w_]+$").unwrap());
 
@@ -423,4 +1633,106 @@ fn signal_received(signal: i32) -> Box<dyn Fn() -> bool> {
     // receive `signal'
     Box::new(move || flag.swap(false, Ordering::Relaxed))
 }
-    
\ No newline at end of file
+    
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // Read QoS spec from JSON (via a temporary file)
+    fn read(json: &str) -> Result<SyntheticNetwork, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "synthetic_network_{}_{}.json",
+            process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, json).unwrap();
+        let spec = read_qos_spec(path).map_err(|error| error.to_string());
+        let _ = std::fs::remove_file(path);
+        spec
+    }
+
+    // JSON of a link at 10 Mbps, with additional fields for ingress QoS
+    fn link(ingress: &str) -> String {
+        let qos = |fields: &str| format!(
+            r#"{{"rate": 10000000, "loss": 0, "latency": 0, "jitter": 0,
+                 "jitter_strength": 0, "reorder_packets": false{}}}"#,
+            fields);
+        format!(r#"{{"ingress": {}, "egress": {}}}"#, qos(ingress), qos(""))
+    }
+
+    // JSON of a network with a single flow (additional fields for the flow,
+    // and for the ingress QoS of the default link and of the flow)
+    fn network(default: &str, flow: &str, qos: &str, hierarchical: bool)
+               -> String {
+        format!(r#"{{"default_link": {}, "hierarchical": {},
+                     "flows": [{{"label": "a", {} "link": {}}}]}}"#,
+                link(default), hierarchical, flow, link(qos))
+    }
+
+    #[test]
+    fn validate_flows() {
+        let validate = |flow: &str| read(&network("", flow, "", false));
+        assert!(validate(r#""filter": "udp","#).is_ok());
+        assert!(validate(r#""flow": {"protocol": 17}, "filter": "port 53","#)
+                .is_ok());
+        for (flow, error) in [
+            ("", "Flow requires flow or filter."),
+            (r#""flow": {"protocol": 6}, "filter": "udp","#,
+             "Flow filter excludes all packets of flow."),
+            (r#""flow": {"dst": "10.0.0.0/8"}, "filter": "dst net fc00::/7","#,
+             "Flow filter excludes all packets of flow.")
+        ] {
+            assert!(validate(flow).err().as_deref() == Some(error));
+        }
+    }
+
+    #[test]
+    fn validate_links() {
+        let flow = r#""flow": {"ip": 0, "protocol": 17, "port_min": 0,
+                               "port_max": 65535},"#;
+        assert!(read(&network("", flow, "", false)).is_ok());
+        assert!(read(&network("", flow, "", true)).is_ok());
+        for (default, qos, hierarchical, error) in [
+            ("", r#", "loss_model": {"four_state": {"p13": 0.5, "p31": 0.5,
+                                                   "p32": 0.6}}"#,
+             false, "Loss model p13 + p14 and p31 + p32 must not exceed 1."),
+            (r#", "aqm": {"codel": {}}, "scheduler": [{"label": "x"}]"#, "",
+             false, "Scheduler and aqm are mutually exclusive."),
+            ("", r#", "scheduler": [{"label": "x"}, {"label": "x"}]"#,
+             false, "Duplicate scheduler class labels."),
+            ("", r#", "schedule": {"keyframes": []}"#,
+             false, "Schedule requires keyframes."),
+            ("", r#", "schedule": {"keyframes": [{"time": 0, "rate": 1}]}"#,
+             true, "Flow rates of hierarchical networks can not be \
+                    scheduled."),
+            ("", r#", "trace": "x", "aqm": {"codel": {}}"#,
+             false, "Trace excludes aqm and scheduler."),
+            (r#", "outage": {"periodic": {"period": 1, "duration": 2}}"#, "",
+             false, "Outage duration must not exceed period."),
+            ("", r#", "serial": true, "aqm": {"codel": {}}"#,
+             false, "Serial link excludes aqm, scheduler, and trace."),
+            ("", r#", "framing": "raw""#,
+             true, "Flow framing of hierarchical networks can not be set."),
+            (r#", "aqm": {"fq_codel": {}}"#, "",
+             true, "Aqm, scheduler, and trace are not supported in \
+                    hierarchical networks."),
+            ("", r#", "scheduler": [{"label": "x"}]"#,
+             true, "Aqm, scheduler, and trace are not supported in \
+                    hierarchical networks."),
+            ("", r#", "policer": {"cir": 2, "cbs": 1, "pir": 1, "pbs": 1}"#,
+             false, "Policer pir must not be below cir."),
+            ("", r#", "policer": {"cir": 1, "cbs": 1, "pir": 1, "pbs": 1}"#,
+             true, "Policers are not supported in hierarchical networks."),
+            (r#", "quota": {"exceeded": {"rate": 1, "loss": 0,
+                 "latency": 0, "jitter": 0, "jitter_strength": 0,
+                 "reorder_packets": false}}"#,
+             "", false, "Quota requires bytes or time.")
+        ] {
+            let result = read(&network(default, flow, qos, hierarchical)).err();
+            assert!(result.as_deref() == Some(error), "{:?}", result);
+        }
+    }
+
+}
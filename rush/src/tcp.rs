@@ -13,9 +13,13 @@ use std::cmp;
 //   Header<TCP>.set_src_port(u16) - set source port
 //   Header<TCP>.dst_port() -> u16 - get destination port
 //   Header<TCP>.set_dst_port(u16) - set destination port
+//   Header<TCP>.flags() -> u8 - get flags (see FLAG_*, NS is not included)
+//   Header<TCP>.set_flags(u8) - set flags (see FLAG_*)
 //   Header<TCP>.checksum() -> u16 - get TCP checksum
 //   Header<TCP>.set_checksum(u16) - set TCP checksum
 //   Header<TCP>.checksum_compute(&[u8],u16,u16) - compute and set TCP checksum
+//   FLAG_FIN, FLAG_SYN, FLAG_RST, FLAG_PSH, FLAG_ACK, FLAG_URG, FLAG_ECE,
+//   FLAG_CWR - const u8 TCP flags
 
 
 #[repr(C, packed)]
@@ -71,6 +75,16 @@ impl header::Header<TCP> {
         cmp::max(5, self.data_offset() as usize) * 4
     }
 
+    pub fn flags(&self) -> u8 {
+        (lib::ntohs(self.header_ref().off_flags) & 0xff) as u8
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        let h = self.header_mut();
+        h.off_flags &= lib::htons(0xff00);
+        h.off_flags |= lib::htons(flags as u16);
+    }
+
     pub fn checksum(&self) -> u16 {
         self.header_ref().checksum
     }
@@ -92,6 +106,15 @@ impl header::Header<TCP> {
 
 }
 
+pub const FLAG_FIN: u8 = 0x01;
+pub const FLAG_SYN: u8 = 0x02;
+pub const FLAG_RST: u8 = 0x04;
+pub const FLAG_PSH: u8 = 0x08;
+pub const FLAG_ACK: u8 = 0x10;
+pub const FLAG_URG: u8 = 0x20;
+pub const FLAG_ECE: u8 = 0x40;
+pub const FLAG_CWR: u8 = 0x80;
+
 #[cfg(test)]
 mod selftest {
     use super::*;
@@ -130,6 +153,11 @@ mod selftest {
         assert!(tcp.seq() == 3889911740);
         tcp.set_seq(42);
         assert!(tcp.seq() == 42);
+
+        assert!(tcp.flags() == FLAG_ACK);
+        tcp.set_flags(FLAG_SYN | FLAG_ACK);
+        assert!(tcp.flags() == FLAG_SYN | FLAG_ACK);
+        assert!(tcp.data_offset() == 0);
     }
 
 }